@@ -0,0 +1,325 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose, Engine};
+use openssl::{
+    hash::MessageDigest,
+    pkey::PKey,
+    rsa::Padding,
+    sha::sha256,
+    sign::{RsaPssSaltlen, Signer},
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+const PRIVATE_KEY_PATH: &str = "./resources/test/test_private_key.pem";
+const MANIFEST_PATH: &str = "logical_blocks/update_manifest.xml";
+/// Logical blocks start on a multiple of this within shared devices, like erase blocks would.
+const DEVICE_ALIGNMENT: usize = 4096;
+
+/// Sizes and compression of the logical blocks of a synthetic archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveShape {
+    pub name: String,
+    pub logical_block_sizes: Vec<usize>,
+    pub compression: CompressionMethod,
+}
+
+impl ArchiveShape {
+    pub fn many_small_blocks(compression: CompressionMethod) -> ArchiveShape {
+        ArchiveShape::new("many_small_blocks", vec![16 * 1024; 64], compression)
+    }
+
+    pub fn one_huge_block(compression: CompressionMethod) -> ArchiveShape {
+        ArchiveShape::new("one_huge_block", vec![16 * 1024 * 1024], compression)
+    }
+
+    fn new(
+        name: &str,
+        logical_block_sizes: Vec<usize>,
+        compression: CompressionMethod,
+    ) -> ArchiveShape {
+        let compression_name = match compression {
+            CompressionMethod::Stored => "stored",
+            _ => "deflated",
+        };
+
+        ArchiveShape {
+            name: format!("{name}_{compression_name}"),
+            logical_block_sizes,
+            compression,
+        }
+    }
+
+    /// Tells whether reading the archive involves decompressing it.
+    pub fn is_compressed(&self) -> bool {
+        self.compression != CompressionMethod::Stored
+    }
+
+    pub fn get_total_size(&self) -> u64 {
+        self.logical_block_sizes.iter().sum::<usize>() as u64
+    }
+}
+
+/// Where the logical blocks of a synthetic update get installed.
+#[derive(Debug, Clone, Copy)]
+pub enum DestinationKind {
+    /// Every logical block of a bank in one device, copied with the default chunking.
+    SharedDevice,
+    /// Every logical block of a bank in one device, copied in large aligned chunks like a block
+    /// device would like.
+    AlignedSharedDevice,
+    /// Every logical block in its own file, like a partition per logical block.
+    DevicePerLogicalBlock,
+}
+
+impl DestinationKind {
+    pub const ALL: [DestinationKind; 3] = [
+        DestinationKind::SharedDevice,
+        DestinationKind::AlignedSharedDevice,
+        DestinationKind::DevicePerLogicalBlock,
+    ];
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DestinationKind::SharedDevice => "shared_device",
+            DestinationKind::AlignedSharedDevice => "aligned_shared_device",
+            DestinationKind::DevicePerLogicalBlock => "device_per_logical_block",
+        }
+    }
+}
+
+/// Signed archive and memory mapping generated for an [`ArchiveShape`], along with the
+/// directory holding the destinations of the mapping.
+pub struct SyntheticUpdate {
+    archive_path: String,
+    mapping_path: String,
+    destination_dir: PathBuf,
+    /// Size of each destination file, updates never create them.
+    destination_sizes: BTreeMap<PathBuf, u64>,
+}
+
+impl SyntheticUpdate {
+    /// Writes the archive, its mapping and the destination directory in `work_dir`.
+    pub fn generate(
+        work_dir: &Path,
+        shape: &ArchiveShape,
+        destination_kind: DestinationKind,
+    ) -> SyntheticUpdate {
+        let update_dir = work_dir.join(format!("{}_{}", shape.name, destination_kind.get_name()));
+        let destination_dir = update_dir.join("destinations");
+        fs::create_dir_all(&destination_dir).unwrap();
+
+        let logical_blocks: Vec<_> = shape
+            .logical_block_sizes
+            .iter()
+            .enumerate()
+            .map(|(index, size)| SyntheticLogicalBlock::generate(index, *size))
+            .collect();
+
+        let archive_path = update_dir.join("update.zip");
+        write_archive(&archive_path, &logical_blocks, shape.compression);
+
+        let mapping_path = update_dir.join("lb_cfg.json");
+        let destination_sizes = write_mapping(
+            &mapping_path,
+            &logical_blocks,
+            &destination_dir,
+            destination_kind,
+        );
+
+        let synthetic_update = SyntheticUpdate {
+            archive_path: archive_path.to_str().unwrap().to_string(),
+            mapping_path: mapping_path.to_str().unwrap().to_string(),
+            destination_dir,
+            destination_sizes,
+        };
+        synthetic_update.create_destinations();
+        synthetic_update
+    }
+
+    pub fn get_archive_path(&self) -> &str {
+        &self.archive_path
+    }
+
+    pub fn get_mapping_path(&self) -> &str {
+        &self.mapping_path
+    }
+
+    /// Replaces the destinations with zeroed ones, so that every logical block gets written
+    /// again.
+    pub fn clear_destinations(&self) {
+        fs::remove_dir_all(&self.destination_dir).unwrap();
+        fs::create_dir(&self.destination_dir).unwrap();
+        self.create_destinations();
+    }
+
+    fn create_destinations(&self) {
+        for (path, size) in &self.destination_sizes {
+            File::create(path).unwrap().set_len(*size).unwrap();
+        }
+    }
+}
+
+struct SyntheticLogicalBlock {
+    id: String,
+    short_name: String,
+    content: Vec<u8>,
+}
+
+impl SyntheticLogicalBlock {
+    fn generate(index: usize, size: usize) -> SyntheticLogicalBlock {
+        SyntheticLogicalBlock {
+            id: format!("{index:04X}"),
+            short_name: format!("synthetic_{index:04X}"),
+            content: generate_content(index as u64, size),
+        }
+    }
+
+    fn get_path_in_archive(&self) -> String {
+        format!("logical_blocks/{}.bin", self.id)
+    }
+}
+
+/// Generates pseudo-random bytes drawn from 64 values, so that deflate has work to do without
+/// the content compressing to almost nothing.
+fn generate_content(seed: u64, size: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 58) as u8
+        })
+        .collect()
+}
+
+fn write_archive(
+    archive_path: &Path,
+    logical_blocks: &[SyntheticLogicalBlock],
+    compression: CompressionMethod,
+) {
+    let mut index = String::from(
+        "<file_list xmlns=\"file_list\">\n    <file short_name=\"update_manifest\">\n        <path>logical_blocks/update_manifest.xml</path>\n    </file>\n",
+    );
+    let mut manifest = String::from("<logical_blocks xmlns=\"logical_blocks\">\n");
+
+    for logical_block in logical_blocks {
+        index.push_str(&format!(
+            "    <file short_name=\"{}\">\n        <path>{}</path>\n    </file>\n",
+            logical_block.short_name,
+            logical_block.get_path_in_archive()
+        ));
+        manifest.push_str(&format!(
+            "    <logical_block>\n        <id>{}</id>\n        <short_name>{}</short_name>\n        <signature>{}</signature>\n        <digest>{}</digest>\n    </logical_block>\n",
+            logical_block.id,
+            logical_block.short_name,
+            sign(&logical_block.content),
+            to_hex(&sha256(&logical_block.content)),
+        ));
+    }
+    index.push_str("</file_list>\n");
+    manifest.push_str("</logical_blocks>\n");
+
+    let mut archive = ZipWriter::new(File::create(archive_path).unwrap());
+    let options = FileOptions::default().compression_method(compression);
+
+    archive.add_directory("logical_blocks/", options).unwrap();
+    archive.start_file("index.xml", options).unwrap();
+    archive.write_all(index.as_bytes()).unwrap();
+    archive.start_file(MANIFEST_PATH, options).unwrap();
+    archive.write_all(manifest.as_bytes()).unwrap();
+
+    for logical_block in logical_blocks {
+        archive
+            .start_file(logical_block.get_path_in_archive(), options)
+            .unwrap();
+        archive.write_all(&logical_block.content).unwrap();
+    }
+    archive.finish().unwrap();
+}
+
+/// Writes the mapping of the logical blocks and returns the size of each destination file.
+fn write_mapping(
+    mapping_path: &Path,
+    logical_blocks: &[SyntheticLogicalBlock],
+    destination_dir: &Path,
+    destination_kind: DestinationKind,
+) -> BTreeMap<PathBuf, u64> {
+    let mut device_offset = 0;
+    let mut destination_sizes = BTreeMap::new();
+
+    let mapped_logical_blocks: Vec<_> = logical_blocks
+        .iter()
+        .map(|logical_block| {
+            let size = logical_block.content.len();
+            let mut get_destination = |bank: &str| {
+                let (path, offset) = match destination_kind {
+                    DestinationKind::DevicePerLogicalBlock => (
+                        destination_dir.join(format!("{}_{bank}", logical_block.id)),
+                        0,
+                    ),
+                    _ => (
+                        destination_dir.join(format!("device_{bank}")),
+                        device_offset,
+                    ),
+                };
+                let destination_size = destination_sizes.entry(path.clone()).or_insert(0);
+                *destination_size = (*destination_size).max((offset + size) as u64);
+
+                let mut destination = serde_json::json!({
+                    "path": path.to_str().unwrap(),
+                    "offset": offset,
+                    "size": size,
+                });
+                if let DestinationKind::AlignedSharedDevice = destination_kind {
+                    destination["buffer_size"] = (128 * 1024).into();
+                    destination["alignment"] = DEVICE_ALIGNMENT.into();
+                }
+                destination
+            };
+
+            let mapped_logical_block = serde_json::json!({
+                "name": logical_block.short_name,
+                "id": logical_block.id,
+                "destination": {
+                    "bank_a": get_destination("a"),
+                    "bank_b": get_destination("b"),
+                },
+            });
+            device_offset += size.div_ceil(DEVICE_ALIGNMENT) * DEVICE_ALIGNMENT;
+            mapped_logical_block
+        })
+        .collect();
+
+    fs::write(
+        mapping_path,
+        serde_json::json!({ "logical_blocks": mapped_logical_blocks }).to_string(),
+    )
+    .unwrap();
+
+    destination_sizes
+}
+
+fn sign(content: &[u8]) -> String {
+    let private_key = PKey::private_key_from_pem(&fs::read(PRIVATE_KEY_PATH).unwrap()).unwrap();
+
+    let mut signer = Signer::new(MessageDigest::sha256(), &private_key).unwrap();
+    signer.set_rsa_padding(Padding::PKCS1_PSS).unwrap();
+    signer
+        .set_rsa_pss_saltlen(RsaPssSaltlen::custom(0))
+        .unwrap();
+    signer.set_rsa_mgf1_md(MessageDigest::sha256()).unwrap();
+    signer.update(content).unwrap();
+
+    general_purpose::STANDARD.encode(signer.sign_to_vec().unwrap())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
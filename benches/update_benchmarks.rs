@@ -1,45 +1,234 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use std::{path::Path, time::Duration};
 
-use update_logic_clean_code::{async_update, multi_threaded_update, sequencial_update};
+use criterion::{
+    criterion_group, criterion_main, measurement::WallTime, BatchSize, BenchmarkGroup, Criterion,
+    SamplingMode, Throughput,
+};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use zip::CompressionMethod;
 
-fn async_update_benchmark(c: &mut Criterion) {
-    c.bench_function("async_update", |b| {
-        b.iter(|| {
-            async_update(
-                "./resources/test/test_lb_cfg.json",
-                "./resources/test/update_folder.zip",
-            )
-        })
-    });
+use update_logic_clean_code::{
+    multi_threaded_update_with_control, sequencial_update_with_control, update_with_control,
+    PhaseDurations, UpdateControl, UpdateError, UpdateReport,
+    DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS,
+};
+
+mod synthetic_archive;
+
+use synthetic_archive::{ArchiveShape, DestinationKind, SyntheticUpdate};
+
+/// Destinations and archives live in tmpfs when available, so that results don't depend on the
+/// disk the benchmarks happen to run on.
+const TMPFS_PATH: &str = "/dev/shm";
+
+#[derive(Debug, Clone, Copy)]
+enum Strategy {
+    Sequential,
+    MultiThreaded,
+    Async,
 }
 
-fn multi_threaded_update_benchmark(c: &mut Criterion) {
-    c.bench_function("multi_threaded_update", |b| {
-        b.iter(|| {
-            multi_threaded_update(
-                "./resources/test/test_lb_cfg.json",
-                "./resources/test/update_folder.zip",
-            )
-        })
-    });
+impl Strategy {
+    const ALL: [Strategy; 3] = [
+        Strategy::Sequential,
+        Strategy::MultiThreaded,
+        Strategy::Async,
+    ];
+
+    fn get_name(&self) -> &'static str {
+        match self {
+            Strategy::Sequential => "sequential",
+            Strategy::MultiThreaded => "multi_threaded",
+            Strategy::Async => "async",
+        }
+    }
+
+    fn update(
+        &self,
+        runtime: &Runtime,
+        synthetic_update: &SyntheticUpdate,
+        update_control: &UpdateControl,
+    ) -> Result<UpdateReport, UpdateError> {
+        let mapping_path = synthetic_update.get_mapping_path();
+        let archive_path = synthetic_update.get_archive_path();
+
+        match self {
+            Strategy::Sequential => {
+                sequencial_update_with_control(mapping_path, archive_path, update_control)
+            }
+            Strategy::MultiThreaded => {
+                multi_threaded_update_with_control(mapping_path, archive_path, update_control)
+            }
+            Strategy::Async => runtime.block_on(update_with_control(
+                mapping_path,
+                archive_path,
+                DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS,
+                update_control,
+            )),
+        }
+    }
 }
 
-fn sequencial_update_benchmark(c: &mut Criterion) {
-    c.bench_function("sequential_update", |b| {
-        b.iter(|| {
-            sequencial_update(
-                "./resources/test/test_lb_cfg.json",
-                "./resources/test/update_folder.zip",
-            )
-            .unwrap();
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Decompression,
+    Write,
+    Verify,
+}
+
+impl Phase {
+    fn get_name(&self) -> &'static str {
+        match self {
+            Phase::Decompression => "decompression",
+            Phase::Write => "write",
+            Phase::Verify => "verify",
+        }
+    }
+
+    fn get_duration(&self, phase_durations: PhaseDurations) -> Duration {
+        match self {
+            Phase::Decompression => phase_durations.decompression,
+            Phase::Write => phase_durations.write,
+            Phase::Verify => phase_durations.verify,
+        }
+    }
+}
+
+fn get_archive_shapes() -> Vec<ArchiveShape> {
+    [CompressionMethod::Stored, CompressionMethod::Deflated]
+        .into_iter()
+        .flat_map(|compression| {
+            [
+                ArchiveShape::many_small_blocks(compression),
+                ArchiveShape::one_huge_block(compression),
+            ]
         })
-    });
+        .collect()
+}
+
+fn create_work_dir() -> TempDir {
+    match Path::new(TMPFS_PATH).is_dir() {
+        true => tempfile::tempdir_in(TMPFS_PATH).unwrap_or_else(|_| tempfile::tempdir().unwrap()),
+        false => tempfile::tempdir().unwrap(),
+    }
+}
+
+fn configure_group(group: &mut BenchmarkGroup<'_, WallTime>) {
+    group
+        .sample_size(10)
+        .sampling_mode(SamplingMode::Flat)
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(3));
+}
+
+/// Opening the archive and parsing its index, manifest and the memory mapping, measured with
+/// updates cancelled before their first logical block.
+fn index_parsing_benchmark(c: &mut Criterion) {
+    let work_dir = create_work_dir();
+    let runtime = Runtime::new().unwrap();
+    let update_control = UpdateControl::new();
+    update_control.cancel();
+
+    for shape in get_archive_shapes() {
+        let synthetic_update =
+            SyntheticUpdate::generate(work_dir.path(), &shape, DestinationKind::SharedDevice);
+
+        let mut group = c.benchmark_group(format!("index_parsing/{}", shape.name));
+        configure_group(&mut group);
+
+        for strategy in Strategy::ALL {
+            group.bench_function(strategy.get_name(), |b| {
+                b.iter(|| {
+                    strategy
+                        .update(&runtime, &synthetic_update, &update_control)
+                        .unwrap_err()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+/// Time spent in each phase of the logical blocks, as reported by updates to fresh destinations.
+fn logical_block_phase_benchmark(c: &mut Criterion) {
+    let work_dir = create_work_dir();
+    let runtime = Runtime::new().unwrap();
+
+    for shape in get_archive_shapes() {
+        let synthetic_update =
+            SyntheticUpdate::generate(work_dir.path(), &shape, DestinationKind::SharedDevice);
+
+        for phase in [Phase::Decompression, Phase::Write, Phase::Verify] {
+            // Nothing gets decompressed from stored archives
+            if let (Phase::Decompression, false) = (phase, shape.is_compressed()) {
+                continue;
+            }
+
+            let mut group = c.benchmark_group(format!("{}/{}", phase.get_name(), shape.name));
+            configure_group(&mut group);
+            group.throughput(Throughput::Bytes(shape.get_total_size()));
+
+            for strategy in Strategy::ALL {
+                group.bench_function(strategy.get_name(), |b| {
+                    b.iter_custom(|iterations| {
+                        (0..iterations)
+                            .map(|_| {
+                                synthetic_update.clear_destinations();
+                                let update_report = strategy
+                                    .update(&runtime, &synthetic_update, &UpdateControl::new())
+                                    .unwrap();
+                                phase.get_duration(update_report.get_phase_durations())
+                            })
+                            .sum()
+                    })
+                });
+            }
+            group.finish();
+        }
+    }
+}
+
+/// Whole updates to fresh destinations of every kind.
+fn full_update_benchmark(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    for shape in get_archive_shapes() {
+        let work_dir = create_work_dir();
+
+        let mut group = c.benchmark_group(format!("full_update/{}", shape.name));
+        configure_group(&mut group);
+        group.throughput(Throughput::Bytes(shape.get_total_size()));
+
+        for destination_kind in DestinationKind::ALL {
+            let synthetic_update =
+                SyntheticUpdate::generate(work_dir.path(), &shape, destination_kind);
+
+            for strategy in Strategy::ALL {
+                group.bench_function(
+                    format!("{}/{}", strategy.get_name(), destination_kind.get_name()),
+                    |b| {
+                        b.iter_batched(
+                            || synthetic_update.clear_destinations(),
+                            |_| {
+                                strategy
+                                    .update(&runtime, &synthetic_update, &UpdateControl::new())
+                                    .unwrap()
+                            },
+                            BatchSize::PerIteration,
+                        )
+                    },
+                );
+            }
+        }
+        group.finish();
+    }
 }
 
 criterion_group!(
     benches,
-    async_update_benchmark,
-    multi_threaded_update_benchmark,
-    sequencial_update_benchmark
+    index_parsing_benchmark,
+    logical_block_phase_benchmark,
+    full_update_benchmark
 );
 criterion_main!(benches);
@@ -1,9 +1,21 @@
-use std::{fmt, io::Read};
+use std::{
+    fmt,
+    io::Read,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use memmap2::Mmap;
+use piz::{
+    read::{as_tree, FileTree},
+    ZipArchive,
+};
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc::{self, Receiver},
+    task::{self, JoinHandle},
 };
+use tracing::Span;
 
 use base64::{engine::general_purpose, Engine};
 use openssl::{
@@ -14,126 +26,388 @@ use openssl::{
 };
 
 use crate::{
-    async_update::memory::LogicalBlockDestination,
-    reporting::{LogicalBlockError, UpdateError},
+    async_update::memory::read_at,
+    chunked_io::{read_chunk_within, DestinationFile},
+    memory_mapping::LogicalBlockDestination,
+    reporting::{DocumentError, LogicalBlockError, UpdateError, UpdatePhase, UpdateProgress},
+    update_control::UpdateControl,
 };
 
-pub struct LogicalBlock<'a> {
+const CHUNK_CHANNEL_CAPACITY: usize = 16;
+
+pub struct LogicalBlock {
     pub id: String,
     pub name: String,
     pub signature: String,
-    pub source: LogicalBlockSource<'a>,
+    pub digest: Option<String>,
+    /// Logical blocks are installed stage after stage, see [`crate::manifest`].
+    pub install_stage: usize,
+    pub source: LogicalBlockSource,
     pub destination: LogicalBlockDestination,
+    /// Number of chunk writes retried so far.
+    pub retries: u32,
 }
 
-pub struct LogicalBlockSource<'a> {
-    pub file: Box<dyn Read + Send + 'a>,
+pub struct LogicalBlockSource {
+    pub archive_bytes: Arc<Mmap>,
+    pub path_in_archive: String,
+    /// Uncompressed size of the content, as recorded in the archive.
+    pub size: usize,
+    /// Range of the content in the mapped archive, when it is stored uncompressed.
+    pub stored_range: Option<Range<usize>>,
 }
 
-impl<'a> LogicalBlock<'a> {
-    pub async fn write(&mut self) -> Result<(), UpdateError> {
-        let mut read_buffer = [0; 4096];
-        let mut total_copied_bytes = 0;
+impl LogicalBlockSource {
+    /// Decompresses the logical block on the blocking thread pool and streams its content
+    /// back in chunks sized for `destination`, so that decompression never blocks the runtime
+    /// worker threads. The decompression task returns the time it spent reading the archive.
+    fn read_chunks(
+        &self,
+        destination: &LogicalBlockDestination,
+    ) -> (Receiver<std::io::Result<Vec<u8>>>, JoinHandle<Duration>) {
+        let (sender, receiver) = mpsc::channel(CHUNK_CHANNEL_CAPACITY);
+
+        let destination = destination.clone();
+        let archive_bytes = self.archive_bytes.clone();
+        let path_in_archive = self.path_in_archive.clone();
+
+        let decompression = task::spawn_blocking(move || {
+            let mut decompression_duration = Duration::ZERO;
+
+            let read_start = Instant::now();
+            let mut reader = match Self::open_reader(&archive_bytes, &path_in_archive) {
+                Ok(reader) => reader,
+                Err(error) => {
+                    let _ = sender.blocking_send(Err(error));
+                    return decompression_duration;
+                }
+            };
+            decompression_duration += read_start.elapsed();
+
+            let mut read_bytes = 0;
+
+            loop {
+                let mut chunk = vec![0; destination.get_chunk_size(read_bytes)];
+                let remaining_bytes = destination.get_size() - read_bytes;
+
+                let read_start = Instant::now();
+                let read_result = read_chunk_within(&mut reader, &mut chunk, remaining_bytes);
+                decompression_duration += read_start.elapsed();
+
+                let chunk = match read_result {
+                    Ok(0) => return decompression_duration,
+                    Ok(n) => {
+                        read_bytes += n;
+                        chunk.truncate(n);
+                        Ok(chunk)
+                    }
+                    Err(error) => Err(error),
+                };
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .open(self.destination.get_path())
+                let is_error = chunk.is_err();
+                if sender.blocking_send(chunk).is_err() || is_error {
+                    return decompression_duration;
+                }
+            }
+        });
+
+        (receiver, decompression)
+    }
+
+    fn open_reader<'a>(
+        archive_bytes: &'a Mmap,
+        path_in_archive: &str,
+    ) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+        let archive = ZipArchive::new(archive_bytes).map_err(std::io::Error::other)?;
+        let tree = as_tree(archive.entries()).map_err(std::io::Error::other)?;
+        let metadata = tree
+            .lookup(path_in_archive)
+            .map_err(std::io::Error::other)?;
+
+        archive.read(metadata).map_err(std::io::Error::other)
+    }
+}
+
+impl LogicalBlock {
+    /// Tells whether the destination already holds the content described by the manifest digest.
+    pub async fn is_up_to_date(&self) -> bool {
+        self.destination
+            .holds_content_with_digest_async(self.digest.as_deref())
             .await
-            .unwrap();
-        file.seek(std::io::SeekFrom::Start(self.destination.get_offset()))
+    }
+
+    /// Writes the logical block to its destination and returns the time spent decompressing it.
+    pub async fn write(&mut self, update_control: &UpdateControl) -> Result<Duration, UpdateError> {
+        if let Some(stored_range) = self.source.stored_range.clone() {
+            let stored_size = stored_range.len();
+            self.retries += self
+                .write_stored_content(stored_range, update_control)
+                .await?;
+            Span::current().record("bytes", stored_size);
+            return Ok(Duration::ZERO);
+        }
+
+        let expected_size = self.destination.get_size();
+
+        if self.source.size != expected_size {
+            return Err(UpdateError::LogicalBlockWrite(LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Write,
+                format!("Number of bytes to write ({}) doesn't match the expected logical block size ({expected_size})", self.source.size),
+            )));
+        }
+
+        let mut total_copied_bytes = 0;
+
+        // Read back when retried chunks are verified
+        let file = self
+            .destination
+            .create_file_async()
             .await
-            .unwrap();
+            .map_err(|error| {
+                UpdateError::LogicalBlockWrite(
+                    LogicalBlockError::new(
+                        self.id.clone(),
+                        UpdatePhase::Write,
+                        "Unable to open destination".to_string(),
+                    )
+                    .caused_by(&error),
+                )
+            })?;
 
-        loop {
-            let copied_bytes_count = self.copy_chunk(&mut read_buffer, &mut file).await?;
-            if copied_bytes_count == 0 {
-                break;
-            } else {
-                total_copied_bytes += copied_bytes_count;
+        let (mut chunks, decompression) = self.source.read_chunks(&self.destination);
+
+        while let Some(chunk) = chunks.recv().await {
+            let chunk = self.check_chunk_from_logical_block(chunk)?;
+            let copied_bytes_count = self
+                .write_chunk_in_file(chunk, &file, total_copied_bytes, update_control)
+                .await?;
+            total_copied_bytes += copied_bytes_count;
+            update_control
+                .get_io_throttle()
+                .throttle_async(copied_bytes_count)
+                .await;
+            if !update_control.proceed_async().await {
+                return Err(self.cancelled(UpdatePhase::Write, total_copied_bytes));
             }
         }
 
-        let expected_size = self.destination.get_size();
+        Span::current().record("bytes", total_copied_bytes);
 
         match total_copied_bytes == expected_size {
-            true => Ok(()),
-            false => Err(UpdateError::LogicalBlockWrite(LogicalBlockError {
-                logical_block_id: self.id.clone(),
-                description: format!("Number of bytes written ({total_copied_bytes}) doesn't match the expected logical block size ({expected_size})"),
-            })),
+            true => decompression
+                .await
+                .map_err(std::io::Error::other)
+                .map_err(|error| {
+                    UpdateError::LogicalBlockRead(
+                        LogicalBlockError::new(
+                            self.id.clone(),
+                            UpdatePhase::Write,
+                            "Unable to decompress logical block from source".to_string(),
+                        )
+                        .caused_by(&error),
+                    )
+                }),
+            false => Err(UpdateError::LogicalBlockWrite(LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Write,
+                format!("Number of bytes written ({total_copied_bytes}) doesn't match the expected logical block size ({expected_size})"),
+            ))),
         }
     }
 
-    async fn copy_chunk(
-        &mut self,
-        chunk_buffer: &mut [u8],
-        file: &mut File,
-    ) -> Result<usize, UpdateError> {
-        let read_bytes = self.read_chunk_from_logical_block(chunk_buffer)?;
-
-        let written_bytes = self
-            .write_chunk_in_file(&mut chunk_buffer[..read_bytes], file)
-            .await?;
+    /// Writes the content straight from the mapped archive with one positional write per chunk,
+    /// on the blocking thread pool. Returns the number of chunk writes retried.
+    async fn write_stored_content(
+        &self,
+        stored_range: Range<usize>,
+        update_control: &UpdateControl,
+    ) -> Result<u32, UpdateError> {
+        let expected_size = self.destination.get_size();
 
-        match written_bytes == read_bytes {
-            true => Ok(written_bytes),
-            false => Err(UpdateError::LogicalBlockWrite(LogicalBlockError {
-                logical_block_id: self.id.clone(),
-                description: format!("Chunk copy error: number of bytes read ({read_bytes}) doesn't match the number of bytes written ({written_bytes})"),
-            })),
+        if stored_range.len() != expected_size {
+            return Err(UpdateError::LogicalBlockWrite(LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Write,
+                format!("Number of bytes to write ({}) doesn't match the expected logical block size ({expected_size})", stored_range.len()),
+            )));
         }
+
+        let id = self.id.clone();
+        let archive_bytes = self.source.archive_bytes.clone();
+        let path_in_archive = self.source.path_in_archive.clone();
+        let destination = self.destination.clone();
+        let update_control = update_control.clone();
+
+        task::spawn_blocking(move || {
+            let content = archive_bytes.get(stored_range).ok_or_else(|| {
+                UpdateError::InvalidArchive(DocumentError::new(
+                    path_in_archive,
+                    UpdatePhase::Write,
+                    "Stored file extends past the end of the archive",
+                ))
+            })?;
+            let file = destination.create_file().map_err(|error| {
+                UpdateError::LogicalBlockWrite(
+                    LogicalBlockError::new(
+                        id.clone(),
+                        UpdatePhase::Write,
+                        "Unable to open destination".to_string(),
+                    )
+                    .caused_by(&error),
+                )
+            })?;
+            let retry_policy = destination.get_retry_policy();
+
+            let _niceness = update_control.get_io_throttle().apply_niceness();
+
+            let mut written_bytes = 0;
+            let mut retries = 0;
+            while written_bytes < content.len() {
+                let chunk_size = destination
+                    .get_chunk_size(written_bytes)
+                    .min(content.len() - written_bytes);
+                let chunk_offset = destination.get_offset() + written_bytes as u64;
+                let chunk = &content[written_bytes..written_bytes + chunk_size];
+
+                retries += retry_policy
+                    .write_chunk(
+                        &file,
+                        chunk_offset,
+                        chunk_size,
+                        || file.write_all_at(chunk, chunk_offset),
+                        |expected| {
+                            expected.copy_from_slice(chunk);
+                            Ok(())
+                        },
+                    )
+                    .map_err(|error| {
+                        UpdateError::LogicalBlockWrite(
+                            LogicalBlockError::new(
+                                id.clone(),
+                                UpdatePhase::Write,
+                                "Unable to write chunk to destination".to_string(),
+                            )
+                            .at_offset(chunk_offset)
+                            .caused_by(&error),
+                        )
+                    })?;
+                written_bytes += chunk_size;
+
+                update_control.get_io_throttle().throttle(chunk_size);
+                if !update_control.proceed() {
+                    return Err(UpdateError::Cancelled(
+                        UpdateProgress::new(UpdatePhase::Write).in_logical_block(id),
+                    )
+                    .at_offset(destination.get_offset() + written_bytes as u64));
+                }
+            }
+            Ok(retries)
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .map_err(|error| {
+            UpdateError::LogicalBlockWrite(
+                LogicalBlockError::new(
+                    self.id.clone(),
+                    UpdatePhase::Write,
+                    "Unable to write stored content to destination".to_string(),
+                )
+                .caused_by(&error),
+            )
+        })?
     }
 
-    fn read_chunk_from_logical_block(
-        &mut self,
-        chunk_buffer: &mut [u8],
-    ) -> Result<usize, UpdateError> {
-        match self.source.file.read(chunk_buffer) {
-            Ok(n) => Ok(n),
-            Err(_) => Err(UpdateError::LogicalBlockRead(LogicalBlockError {
-                logical_block_id: self.id.clone(),
-                description: "Unable to read chunk from source".to_string(),
-            })),
+    fn check_chunk_from_logical_block(
+        &self,
+        chunk: std::io::Result<Vec<u8>>,
+    ) -> Result<Vec<u8>, UpdateError> {
+        match chunk {
+            Ok(chunk) => Ok(chunk),
+            Err(error) => Err(UpdateError::LogicalBlockRead(
+                LogicalBlockError::new(
+                    self.id.clone(),
+                    UpdatePhase::Write,
+                    "Unable to read chunk from source".to_string(),
+                )
+                .caused_by(&error),
+            )),
         }
     }
 
+    /// Writes the chunk `copied_bytes` into the destination region, retrying as the destination
+    /// allows.
     async fn write_chunk_in_file(
         &mut self,
-        chunk_buffer: &mut [u8],
-        file: &mut File,
+        chunk: Vec<u8>,
+        file: &Arc<DestinationFile>,
+        copied_bytes: usize,
+        update_control: &UpdateControl,
     ) -> Result<usize, UpdateError> {
-        match file.write(chunk_buffer).await {
-            Ok(n) => Ok(n),
-            Err(_) => Err(UpdateError::LogicalBlockWrite(LogicalBlockError {
-                logical_block_id: self.id.clone(),
-                description: "Unable to write chunk to destination".to_string(),
-            })),
+        let offset = self.destination.get_offset() + copied_bytes as u64;
+        let chunk_size = chunk.len();
+
+        match self
+            .destination
+            .get_retry_policy()
+            .write_chunk_async(file, offset, chunk, update_control.get_io_throttle())
+            .await
+        {
+            Ok(retries) => {
+                self.retries += retries;
+                Ok(chunk_size)
+            }
+            Err(error) => Err(UpdateError::LogicalBlockWrite(
+                LogicalBlockError::new(
+                    self.id.clone(),
+                    UpdatePhase::Write,
+                    "Unable to write chunk to destination".to_string(),
+                )
+                .caused_by(&error),
+            )),
         }
     }
 
-    pub(crate) async fn verify(&self) -> Result<(), UpdateError> {
+    pub(crate) async fn verify(&self, update_control: &UpdateControl) -> Result<(), UpdateError> {
         let public_key = self.get_public_key()?;
         let mut verifier = self.get_verifier(&public_key)?;
 
-        self.update_verifier_with_logical_block_content(&mut verifier)
+        self.update_verifier_with_logical_block_content(&mut verifier, update_control)
             .await?;
 
-        let decoded_signature = general_purpose::STANDARD.decode(&self.signature).unwrap();
+        let decoded_signature =
+            general_purpose::STANDARD
+                .decode(&self.signature)
+                .map_err(|error| {
+                    UpdateError::VerificationError(
+                        LogicalBlockError::new(
+                            self.id.clone(),
+                            UpdatePhase::Verify,
+                            "Unable to decode signature".to_string(),
+                        )
+                        .caused_by(&error),
+                    )
+                })?;
 
         match verifier.verify(&decoded_signature) {
             Ok(true) => Ok(()),
-            Ok(false) => Err(UpdateError::VerificationError(LogicalBlockError {
-                logical_block_id: self.id.clone(),
-                description: format!(
+            Ok(false) => Err(UpdateError::VerificationError(LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Verify,
+                format!(
                     "Verification failed: logical block {} doesn't match its signature ({})",
                     self.id.clone(),
                     self.signature.clone()
                 ),
-            })),
-            Err(_) => Err(UpdateError::VerificationError(LogicalBlockError {
-                logical_block_id: self.id.clone(),
-                description: "Unable to verify signature".to_string(),
-            })),
+            ))),
+            Err(error) => Err(UpdateError::VerificationError(
+                LogicalBlockError::new(
+                    self.id.clone(),
+                    UpdatePhase::Verify,
+                    "Unable to verify signature".to_string(),
+                )
+                .caused_by(&error),
+            )),
         }
     }
 
@@ -146,7 +420,10 @@ impl<'a> LogicalBlock<'a> {
         Ok(PKey::public_key_from_pem(&public_key).unwrap())
     }
 
-    fn get_verifier(&'a self, public_key: &'a PKey<Public>) -> Result<Verifier<'a>, UpdateError> {
+    fn get_verifier<'a>(
+        &'a self,
+        public_key: &'a PKey<Public>,
+    ) -> Result<Verifier<'a>, UpdateError> {
         let mut verifier = Verifier::new(MessageDigest::sha256(), public_key).unwrap();
 
         verifier.set_rsa_padding(Padding::PKCS1_PSS).unwrap();
@@ -161,14 +438,21 @@ impl<'a> LogicalBlock<'a> {
     async fn update_verifier_with_logical_block_content(
         &self,
         verifier: &mut Verifier<'_>,
+        update_control: &UpdateControl,
     ) -> Result<(), UpdateError> {
-        let mut file = File::open(self.destination.get_path()).await.unwrap();
-        file.seek(std::io::SeekFrom::Start(self.destination.get_offset()))
-            .await
-            .unwrap();
+        let file = self.destination.open_file_async().await.map_err(|error| {
+            UpdateError::LogicalBlockRead(
+                LogicalBlockError::new(
+                    self.id.clone(),
+                    UpdatePhase::Verify,
+                    "Unable to open destination".to_string(),
+                )
+                .at_offset(self.destination.get_offset())
+                .caused_by(&error),
+            )
+        })?;
 
-        const CHUNK_SIZE: usize = 4096;
-        let mut read_buffer = [0; CHUNK_SIZE];
+        let chunk_size = self.destination.get_buffer_size();
 
         let total_bytes_to_read = self.destination.get_size();
         let mut total_bytes_read = 0;
@@ -179,30 +463,45 @@ impl<'a> LogicalBlock<'a> {
             if remaining_bytes == 0 {
                 return Ok(());
             }
+            if !update_control.proceed_async().await {
+                return Err(self.cancelled(UpdatePhase::Verify, total_bytes_read));
+            }
 
-            let bytes_to_read = if remaining_bytes >= CHUNK_SIZE {
-                CHUNK_SIZE
+            let bytes_to_read = if remaining_bytes >= chunk_size {
+                chunk_size
             } else {
                 remaining_bytes
             };
 
-            match file.read_exact(&mut read_buffer[..bytes_to_read]).await {
-                Ok(_) => {
-                    verifier.update(&read_buffer[..bytes_to_read]).unwrap();
+            let offset = self.destination.get_offset() + total_bytes_read as u64;
+            match read_at(&file, offset, bytes_to_read).await {
+                Ok(chunk) => {
+                    verifier.update(&chunk).unwrap();
                     total_bytes_read += bytes_to_read;
                 }
-                Err(_) => {
-                    return Err(UpdateError::LogicalBlockRead(LogicalBlockError {
-                        logical_block_id: self.id.clone(),
-                        description: "Unable to read back chunk from destination".to_string(),
-                    }))
+                Err(error) => {
+                    return Err(UpdateError::LogicalBlockRead(
+                        LogicalBlockError::new(
+                            self.id.clone(),
+                            UpdatePhase::Verify,
+                            "Unable to read back chunk from destination".to_string(),
+                        )
+                        .at_offset(self.destination.get_offset() + total_bytes_read as u64)
+                        .caused_by(&error),
+                    ))
                 }
             }
         }
     }
+
+    /// Reports a cancellation after `processed_bytes` of the logical block were handled.
+    fn cancelled(&self, phase: UpdatePhase, processed_bytes: usize) -> UpdateError {
+        UpdateError::Cancelled(UpdateProgress::new(phase).in_logical_block(self.id.clone()))
+            .at_offset(self.destination.get_offset() + processed_bytes as u64)
+    }
 }
 
-impl<'a> fmt::Display for LogicalBlock<'a> {
+impl fmt::Display for LogicalBlock {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
@@ -1,110 +1,155 @@
-use serde::Deserialize;
-use std::{collections::HashMap, fs::File};
+use openssl::sha::Sha256;
+use std::{io, sync::Arc};
 
-use crate::reporting::UpdateError;
+use crate::{
+    chunked_io::DestinationFile,
+    digest::{sha256_hex_of_destination_region, to_hex},
+    memory_mapping::{LogicalBlockClone, LogicalBlockDestination, MemoryMapping},
+    reporting::UpdateError,
+};
 
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct LogicalBlockCfg {
-    pub logical_blocks: Vec<LogicalBlock>,
-}
+impl LogicalBlockDestination {
+    /// Same as [`LogicalBlockDestination::open_file`], on the blocking thread pool.
+    pub(crate) async fn open_file_async(&self) -> io::Result<Arc<DestinationFile>> {
+        let destination = self.clone();
 
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct LogicalBlock {
-    pub name: String,
-    pub id: String,
-    pub destination: Banks,
-}
-impl LogicalBlock {
-    fn get_location_from_bank(
-        &self,
-        targeted_bank: &str,
-    ) -> Result<LogicalBlockDestination, UpdateError> {
-        match targeted_bank {
-            "bank_a" => Ok(self.destination.bank_a.clone()),
-            "bank_b" => Ok(self.destination.bank_b.clone()),
-            other => panic!("{} is not a supported bank", other),
-        }
+        on_blocking_pool(move || destination.open_file().map(Arc::new)).await
     }
-}
 
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct Banks {
-    pub bank_a: LogicalBlockDestination,
-    pub bank_b: LogicalBlockDestination,
-}
+    /// Same as [`LogicalBlockDestination::create_file`], on the blocking thread pool.
+    pub(crate) async fn create_file_async(&self) -> io::Result<Arc<DestinationFile>> {
+        let destination = self.clone();
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+        on_blocking_pool(move || destination.create_file().map(Arc::new)).await
+    }
 
-pub struct LogicalBlockDestination {
-    path: String,
-    offset: u64,
-    size: usize,
-}
+    /// Same as [`LogicalBlockDestination::erase`], on the blocking thread pool.
+    pub async fn erase_async(&self) -> io::Result<()> {
+        let destination = self.clone();
 
-impl LogicalBlockDestination {
-    pub fn get_path(&self) -> &str {
-        self.path.as_ref()
+        on_blocking_pool(move || destination.erase()).await
     }
 
-    pub fn get_offset(&self) -> u64 {
-        self.offset
+    /// Same as [`LogicalBlockDestination::get_content_digest`], on the blocking thread pool.
+    pub async fn get_content_digest_async(&self) -> Option<String> {
+        let file = self.open_file_async().await.ok()?;
+        let (offset, size, chunk_size) =
+            (self.get_offset(), self.get_size(), self.get_buffer_size());
+
+        on_blocking_pool(move || sha256_hex_of_destination_region(&file, offset, size, chunk_size))
+            .await
+            .ok()
     }
 
-    pub fn get_size(&self) -> usize {
-        self.size
+    pub async fn holds_content_with_digest_async(&self, digest: Option<&str>) -> bool {
+        match digest {
+            Some(digest) => self.get_content_digest_async().await.as_deref() == Some(digest),
+            None => false,
+        }
     }
 }
 
-pub struct MemoryMapping {
-    logical_blocks: HashMap<String, LogicalBlockDestination>,
+/// Runs the blocking `operation` on the blocking thread pool, as tokio does for its own files, so
+/// that reading and writing destinations never blocks the runtime worker threads.
+pub(crate) async fn on_blocking_pool<T: Send + 'static>(
+    operation: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(operation)
+        .await
+        .map_err(io::Error::other)?
 }
 
-impl MemoryMapping {
-    pub fn from(mapping_path: &str) -> Result<MemoryMapping, UpdateError> {
-        let mapping_file = File::open(mapping_path).unwrap();
-        let lb_cfg: LogicalBlockCfg = serde_json::from_reader(mapping_file).unwrap();
-
-        let targeted_bank = Self::get_target_bank()?;
-        let target_bank_mapping =
-            lb_cfg
-                .logical_blocks
-                .iter()
-                .fold(HashMap::new(), |mut map, lb| {
-                    let location = lb.get_location_from_bank(&targeted_bank).unwrap();
-
-                    map.insert(lb.id.clone(), location);
-                    map
-                });
-
-        // TODO: Return an error if there is overlapping sections in the mapping
-
-        Ok(MemoryMapping {
-            logical_blocks: target_bank_mapping,
-        })
-    }
+/// Reads `size` bytes at `offset` of `file` on the blocking thread pool.
+pub(crate) async fn read_at(
+    file: &Arc<DestinationFile>,
+    offset: u64,
+    size: usize,
+) -> io::Result<Vec<u8>> {
+    let file = file.clone();
+
+    on_blocking_pool(move || {
+        let mut read_buffer = vec![0; size];
+        file.read_exact_at(&mut read_buffer, offset)?;
+        Ok(read_buffer)
+    })
+    .await
+}
 
-    pub fn get_logical_block_destination(
-        &self,
-        logical_block_id: &str,
-    ) -> Result<&LogicalBlockDestination, UpdateError> {
-        Ok(self.logical_blocks.get(logical_block_id).unwrap())
-    }
+/// Writes `data` at `offset` of `file` on the blocking thread pool.
+pub(crate) async fn write_all_at(
+    file: &Arc<DestinationFile>,
+    data: Vec<u8>,
+    offset: u64,
+) -> io::Result<()> {
+    let file = file.clone();
 
-    fn get_target_bank() -> Result<String, UpdateError> {
-        Ok("bank_a".to_string())
-    }
+    on_blocking_pool(move || file.write_all_at(&data, offset)).await
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl LogicalBlockClone {
+    /// Same as [`LogicalBlockClone::copy`], each chunk being read and written on the blocking
+    /// thread pool.
+    pub async fn copy_async(&self) -> Result<String, UpdateError> {
+        self.check_size()?;
+
+        let source_file = self
+            .source
+            .open_file_async()
+            .await
+            .map_err(|error| self.read_error(error))?;
+        let destination_file = self
+            .destination
+            .create_file_async()
+            .await
+            .map_err(|error| self.write_error(error))?;
+
+        let mut hasher = Sha256::new();
+        let mut remaining_bytes = self.source.get_size();
+
+        while remaining_bytes > 0 {
+            let copied_bytes = self.source.get_size() - remaining_bytes;
+            let bytes_to_copy = remaining_bytes.min(self.destination.get_chunk_size(copied_bytes));
+
+            let chunk = read_at(
+                &source_file,
+                self.source.get_offset() + copied_bytes as u64,
+                bytes_to_copy,
+            )
+            .await
+            .map_err(|error| self.read_error(error))?;
+            hasher.update(&chunk);
+
+            write_all_at(
+                &destination_file,
+                chunk,
+                self.destination.get_offset() + copied_bytes as u64,
+            )
+            .await
+            .map_err(|error| self.write_error(error))?;
+
+            remaining_bytes -= bytes_to_copy;
+        }
 
-    #[test]
-    fn real_mapping_test() {
-        let mapping = MemoryMapping::from("./resources/test/test_lb_cfg.json").unwrap();
+        self.check_copy(
+            to_hex(&hasher.finish()),
+            self.destination.get_content_digest_async().await,
+        )
+    }
+}
 
-        for (id, location) in mapping.logical_blocks {
-            println!("id: {}, location: {:#?}", id, location);
+impl MemoryMapping {
+    /// Same as [`MemoryMapping::check_recovery`], reading the recovery image on the blocking
+    /// thread pool.
+    pub async fn check_recovery_async(&self) -> Result<(), UpdateError> {
+        if let Some(recovery) = self.get_recovery_image() {
+            if !recovery
+                .location
+                .holds_content_with_digest_async(Some(&recovery.digest))
+                .await
+            {
+                return Err(MemoryMapping::recovery_check_error());
+            }
         }
+        Ok(())
     }
 }
@@ -1,200 +1,590 @@
-use std::fs::File;
+use std::{
+    collections::HashMap, error::Error, fs::File, io::Cursor, ops::Range, sync::Arc, time::Instant,
+};
 
 use memmap2::Mmap;
 use piz::{
     read::{as_tree, FileTree},
-    ZipArchive,
+    CompressionMethod, ZipArchive,
+};
+use tokio::{
+    sync::Semaphore,
+    task::{self, JoinSet},
+};
+use tracing::{debug, field, info_span, Instrument};
+
+use crate::{
+    manifest::{ArchiveIndex, UpdateManifest, INDEX_PATH},
+    memory_mapping::MemoryMapping,
+    reporting::{
+        invalid_archive, DocumentError, LogicalBlockError, LogicalBlockReport, LogicalBlockStatus,
+        PhaseDurations, UpdateError, UpdatePhase, UpdateProgress, UpdateReport,
+    },
+    retry::LogicalBlockInstall,
+    update_control::UpdateControl,
 };
-use tokio::runtime::Runtime;
-
-use crate::{async_update::memory::MemoryMapping, reporting::UpdateError};
 
 use super::logical_blocks::{LogicalBlock, LogicalBlockSource};
 
-const MANIFEST_XML_NAMESPACE: &str = "logical_blocks";
-
 pub struct SoftwareArchive {
-    archive_bytes: Mmap,
+    archive_bytes: Arc<Mmap>,
 }
 
 impl SoftwareArchive {
     pub fn from(archive_path: &str) -> Result<SoftwareArchive, UpdateError> {
         let archive_bytes = Self::read_archive(archive_path)?;
-        Ok(SoftwareArchive { archive_bytes })
+        Ok(SoftwareArchive {
+            archive_bytes: Arc::new(archive_bytes),
+        })
     }
 
     fn read_archive(archive_path: &str) -> Result<Mmap, UpdateError> {
-        let zip_file = File::open(archive_path).unwrap();
-        Ok(unsafe { Mmap::map(&zip_file).unwrap() })
+        let _archive_open = info_span!("archive_open", path = archive_path).entered();
+
+        let zip_file = File::open(archive_path).map_err(|error| {
+            invalid_archive(
+                archive_path,
+                UpdatePhase::Index,
+                "Unable to open archive",
+                &error,
+            )
+        })?;
+        unsafe { Mmap::map(&zip_file) }.map_err(|error| {
+            invalid_archive(
+                archive_path,
+                UpdatePhase::Index,
+                "Unable to map archive",
+                &error,
+            )
+        })
     }
 
-    pub fn extract_logical_blocks(&self, memory_mapping: MemoryMapping) -> Result<(), UpdateError> {
-        let archive = self.get_archive()?;
-
-        let logical_blocks = self.get_logical_blocks(&archive, memory_mapping)?;
-
-        self.write_logical_blocks(logical_blocks)
+    pub async fn extract_logical_blocks(
+        &self,
+        memory_mapping: MemoryMapping,
+        max_concurrent_logical_blocks: usize,
+        update_control: &UpdateControl,
+        update_report: &mut UpdateReport,
+    ) -> Result<(), UpdateError> {
+        let index_parsing_start = Instant::now();
+        let logical_blocks = {
+            let archive = self.get_archive()?;
+            self.get_logical_blocks(&archive, &memory_mapping)?
+        };
+        update_report.index_parsing_duration = index_parsing_start.elapsed();
+        // Logical block tasks record when they start overwriting the targeted slot
+        let memory_mapping = Arc::new(memory_mapping);
+
+        memory_mapping.stage_update()?;
+
+        let concurrency_limit = Arc::new(Semaphore::new(max_concurrent_logical_blocks.max(1)));
+
+        self.write_logical_blocks(
+            logical_blocks,
+            &memory_mapping,
+            &concurrency_limit,
+            update_control,
+            update_report,
+        )
+        .await
+        .map_err(|error| memory_mapping.require_recovery_on_failure(error))?;
+
+        self.clone_logical_blocks_missing_from_update(
+            &memory_mapping,
+            update_report,
+            &concurrency_limit,
+            update_control,
+        )
+        .await?;
+
+        memory_mapping.commit_update()
     }
 
     fn get_archive(&self) -> Result<ZipArchive<'_>, UpdateError> {
-        Ok(ZipArchive::new(&self.archive_bytes).unwrap())
+        ZipArchive::new(&self.archive_bytes).map_err(|error| {
+            invalid_archive(
+                "archive",
+                UpdatePhase::Index,
+                "Unable to read archive",
+                &error,
+            )
+        })
     }
 
-    fn get_logical_blocks<'a>(
-        &'a self,
-        archive: &'a ZipArchive<'_>,
-        memory_mapping: MemoryMapping,
+    fn get_logical_blocks(
+        &self,
+        archive: &ZipArchive<'_>,
+        memory_mapping: &MemoryMapping,
     ) -> Result<Vec<LogicalBlock>, UpdateError> {
         let index = self.read_archive_index(archive)?;
-        let manifest = self.create_update_manifest(archive, index)?;
+        let manifest = self.read_update_manifest(archive, &index)?;
 
         self.get_logical_blocks_from_manifest_and_memory_map(manifest, memory_mapping, archive)
     }
 
-    fn read_archive_index(
-        &self,
-        archive: &ZipArchive<'_>,
-    ) -> Result<minidom::Element, UpdateError> {
-        let index = self.read_file_content(archive, "index.xml").unwrap();
-        Ok(index.parse().unwrap())
+    fn read_archive_index(&self, archive: &ZipArchive<'_>) -> Result<ArchiveIndex, UpdateError> {
+        let _index_parse = info_span!("index_parse", path = INDEX_PATH).entered();
+
+        let index = self.read_file_content(archive, INDEX_PATH, UpdatePhase::Index)?;
+        ArchiveIndex::parse(&index)
     }
 
     fn read_file_content(
         &self,
         archive: &ZipArchive<'_>,
         path_in_archive: &str,
-    ) -> Result<String, UpdateError> {
-        let tree = as_tree(archive.entries()).unwrap();
-        let metadata = tree.lookup(path_in_archive).unwrap();
-
-        let mut reader = archive.read(metadata).unwrap();
-
-        let mut file_content = String::new();
-
-        reader.read_to_string(&mut file_content).unwrap();
+        phase: UpdatePhase,
+    ) -> Result<Vec<u8>, UpdateError> {
+        let read_error = |error: &(dyn Error + 'static)| {
+            invalid_archive(
+                path_in_archive,
+                phase,
+                "Unable to read file from archive",
+                error,
+            )
+        };
+
+        let tree = as_tree(archive.entries()).map_err(|error| read_error(&error))?;
+        let metadata = tree
+            .lookup(path_in_archive)
+            .map_err(|error| read_error(&error))?;
+
+        let mut reader = archive.read(metadata).map_err(|error| read_error(&error))?;
+
+        let mut file_content = Vec::new();
+
+        reader
+            .read_to_end(&mut file_content)
+            .map_err(|error| read_error(&error))?;
 
         Ok(file_content)
     }
 
-    fn create_update_manifest(
+    fn read_update_manifest(
         &self,
         archive: &ZipArchive<'_>,
-        index: minidom::Element,
-    ) -> Result<minidom::Element, UpdateError> {
-        let manifest_path = self.get_manifest_path(&index)?;
-        let manifest = self.read_file_content(archive, &manifest_path)?;
-
-        let manifest = manifest.parse().unwrap();
-
-        self.get_manifest_with_logical_block_paths(manifest, index)
-    }
-
-    fn get_manifest_path(&self, index: &minidom::Element) -> Result<String, UpdateError> {
-        let manifest_info = index
-            .children()
-            .find(|elem| elem.attr("short_name") == Some("update_manifest"))
-            .unwrap();
+        index: &ArchiveIndex,
+    ) -> Result<UpdateManifest, UpdateError> {
+        let manifest_path = index.get_manifest_path()?;
+        let _manifest_parse = info_span!("manifest_parse", path = manifest_path).entered();
 
-        let manifest_path = manifest_info.get_child("path", "file_list").unwrap().text();
+        let manifest = self.read_file_content(archive, manifest_path, UpdatePhase::Manifest)?;
 
-        Ok(manifest_path)
+        UpdateManifest::parse(manifest_path, &manifest, index)
     }
 
-    fn get_manifest_with_logical_block_paths(
+    fn get_logical_blocks_from_manifest_and_memory_map(
         &self,
-        mut manifest: minidom::Element,
-        index: minidom::Element,
-    ) -> Result<minidom::Element, UpdateError> {
-        for elem in manifest.children_mut() {
-            let name = elem
-                .get_child("short_name", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let path_in_archive = index
-                .children()
-                .find(|elem| elem.attr("short_name") == Some(&name))
-                .unwrap()
-                .get_child("path", "file_list")
-                .unwrap()
-                .text();
-
-            let path_node =
-                elem.append_child(minidom::Element::bare("path", MANIFEST_XML_NAMESPACE));
-            path_node.append_node(minidom::Node::Text(path_in_archive))
-        }
-
-        Ok(manifest)
-    }
-
-    fn get_logical_blocks_from_manifest_and_memory_map<'a>(
-        &'a self,
-        manifest: minidom::Element,
-        memory_mapping: MemoryMapping,
-        archive: &'a ZipArchive<'_>,
-    ) -> Result<Vec<LogicalBlock<'a>>, UpdateError> {
+        manifest: UpdateManifest,
+        memory_mapping: &MemoryMapping,
+        archive: &ZipArchive<'_>,
+    ) -> Result<Vec<LogicalBlock>, UpdateError> {
         let mut logical_blocks = Vec::new();
 
-        let tree = as_tree(archive.entries()).unwrap();
-
-        for elem in manifest.children() {
-            let id = elem.get_child("id", MANIFEST_XML_NAMESPACE).unwrap().text();
-
-            let name = elem
-                .get_child("short_name", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let signature = elem
-                .get_child("signature", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let path_in_archive = elem
-                .get_child("path", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let metadata = tree.lookup(path_in_archive).unwrap();
-            let logical_block_reader = archive.read(metadata).unwrap();
+        let tree = as_tree(archive.entries()).map_err(|error| {
+            invalid_archive(
+                "archive",
+                UpdatePhase::Manifest,
+                "Unable to list archive entries",
+                &error,
+            )
+        })?;
+        // Parsed once, to look up where the content of stored logical blocks starts
+        let mut stored_entries = zip::ZipArchive::new(Cursor::new(&self.archive_bytes[..]))
+            .map_err(|error| {
+                invalid_archive(
+                    "archive",
+                    UpdatePhase::Manifest,
+                    "Unable to list archive entries",
+                    &error,
+                )
+            })?;
+
+        for logical_block in manifest.logical_blocks {
+            let path_in_archive = logical_block.path_in_archive;
+
+            // The content is only decompressed when written, make sure it exists beforehand
+            let metadata = tree.lookup(&path_in_archive).map_err(|error| {
+                invalid_archive(
+                    &path_in_archive,
+                    UpdatePhase::Manifest,
+                    "Logical block listed in the manifest is missing from the archive",
+                    &error,
+                )
+            })?;
+
+            let stored_range = match metadata.compression_method {
+                CompressionMethod::None => {
+                    Some(self.get_stored_range(&mut stored_entries, &path_in_archive)?)
+                }
+                _ => None,
+            };
 
             let logical_block_source = LogicalBlockSource {
-                file: logical_block_reader,
+                archive_bytes: self.archive_bytes.clone(),
+                path_in_archive,
+                size: metadata.size,
+                stored_range,
             };
 
             let logical_block_destination = memory_mapping
-                .get_logical_block_destination(&id)
-                .unwrap()
+                .get_logical_block_destination(&logical_block.id)?
                 .clone();
 
             logical_blocks.push(LogicalBlock {
-                id,
-                name,
-                signature,
+                id: logical_block.id,
+                name: logical_block.short_name,
+                signature: logical_block.signature,
+                digest: logical_block.digest,
+                install_stage: logical_block.install_stage,
                 source: logical_block_source,
                 destination: logical_block_destination,
+                retries: 0,
             })
         }
         Ok(logical_blocks)
     }
 
-    fn write_logical_blocks(
+    /// Returns where the bytes of an uncompressed archive entry are in the mapped archive.
+    fn get_stored_range(
         &self,
-        logical_blocks: Vec<LogicalBlock<'_>>,
+        stored_entries: &mut zip::ZipArchive<Cursor<&[u8]>>,
+        path_in_archive: &str,
+    ) -> Result<Range<usize>, UpdateError> {
+        let read_error = |error: &(dyn Error + 'static)| {
+            invalid_archive(
+                path_in_archive,
+                UpdatePhase::Manifest,
+                "Unable to locate stored file in archive",
+                error,
+            )
+        };
+
+        let file = stored_entries
+            .by_name(path_in_archive)
+            .map_err(|error| read_error(&error))?;
+
+        let data_start = file.data_start() as usize;
+        let stored_range = data_start..data_start + file.size() as usize;
+        match stored_range.end <= self.archive_bytes.len() {
+            true => Ok(stored_range),
+            false => Err(UpdateError::InvalidArchive(DocumentError::new(
+                path_in_archive,
+                UpdatePhase::Manifest,
+                "Stored file extends past the end of the archive",
+            ))),
+        }
+    }
+
+    async fn write_logical_blocks(
+        &self,
+        logical_blocks: Vec<LogicalBlock>,
+        memory_mapping: &Arc<MemoryMapping>,
+        concurrency_limit: &Arc<Semaphore>,
+        update_control: &UpdateControl,
+        update_report: &mut UpdateReport,
     ) -> Result<(), UpdateError> {
-        let rt = Runtime::new().unwrap();
+        let mut logical_blocks = logical_blocks.into_iter().peekable();
+
+        // Stages are installed one after the other, only the logical blocks of a stage are
+        // written concurrently
+        while let Some(install_stage) = logical_blocks
+            .peek()
+            .map(|logical_block| logical_block.install_stage)
+        {
+            let mut tasks = JoinSet::new();
+            let mut task_logical_block_ids = HashMap::new();
+
+            let stage_logical_blocks = std::iter::from_fn(|| {
+                logical_blocks.next_if(|logical_block| logical_block.install_stage == install_stage)
+            });
+            for (position, mut logical_block) in stage_logical_blocks.enumerate() {
+                let permit = concurrency_limit.clone().acquire_owned().await.unwrap();
+                let memory_mapping = memory_mapping.clone();
+                let update_control = update_control.clone();
+                let logical_block_span =
+                    info_span!("logical_block", logical_block_id = %logical_block.id);
+                let logical_block_id = logical_block.id.clone();
+
+                let task = tasks.spawn(
+                    async move {
+                        let _permit = permit;
+
+                        if !update_control.proceed_async().await {
+                            return Err(UpdateError::Cancelled(UpdateProgress::new(
+                                UpdatePhase::Write,
+                            )));
+                        }
+
+                        memory_mapping.check_recovery_async().await?;
+
+                        let mut phase_durations = PhaseDurations::default();
+
+                        let verify_start = Instant::now();
+                        let is_up_to_date = logical_block.is_up_to_date().await;
+                        phase_durations.verify += verify_start.elapsed();
+
+                        let logical_block_install = LogicalBlockInstall {
+                            logical_block_id: logical_block.id.clone(),
+                            digest: logical_block.digest.clone(),
+                            signature: logical_block.signature.clone(),
+                            destination: logical_block.destination.clone(),
+                            memory_mapping: &memory_mapping,
+                        };
+                        let mut attempt = 1;
+                        let status = loop {
+                            // Whatever the destination held before, it gets written again once
+                            // retried
+                            match install_logical_block(
+                                &mut logical_block,
+                                is_up_to_date && attempt == 1,
+                                &mut phase_durations,
+                                &memory_mapping,
+                                &update_control,
+                            )
+                            .await
+                            {
+                                Ok(status) => break status,
+                                Err(error) => {
+                                    logical_block_install.retry_async(attempt, error).await?
+                                }
+                            }
+                            attempt += 1;
+                        };
+                        let logical_block_report = logical_block_install.into_report(
+                            status,
+                            logical_block.retries + attempt - 1,
+                            phase_durations,
+                        );
+
+                        Ok((position, logical_block_report))
+                    }
+                    .instrument(logical_block_span),
+                );
+                task_logical_block_ids.insert(task.id(), logical_block_id);
+            }
+
+            Self::join_logical_block_tasks(tasks, task_logical_block_ids, update_report).await?;
+        }
 
-        rt.block_on(self.async_write_logical_blocks(logical_blocks))
+        Ok(())
     }
 
-    async fn async_write_logical_blocks(
+    async fn clone_logical_blocks_missing_from_update(
         &self,
-        mut logical_blocks: Vec<LogicalBlock<'_>>,
+        memory_mapping: &MemoryMapping,
+        update_report: &mut UpdateReport,
+        concurrency_limit: &Arc<Semaphore>,
+        update_control: &UpdateControl,
     ) -> Result<(), UpdateError> {
-        for logical_block in logical_blocks.iter_mut() {
-            logical_block.write().await?;
-            logical_block.verify().await?;
+        let updated_logical_block_ids = update_report.get_logical_block_ids();
+
+        let mut tasks = JoinSet::new();
+        let mut task_logical_block_ids = HashMap::new();
+
+        for (position, logical_block_clone) in memory_mapping
+            .get_logical_block_clones(&updated_logical_block_ids)?
+            .into_iter()
+            .enumerate()
+        {
+            let permit = concurrency_limit.clone().acquire_owned().await.unwrap();
+            let update_control = update_control.clone();
+            let clone_span = info_span!(
+                "clone",
+                logical_block_id = %logical_block_clone.id,
+                offset = logical_block_clone.destination.get_offset(),
+                size = logical_block_clone.destination.get_size(),
+            );
+
+            let logical_block_id = logical_block_clone.id.clone();
+
+            let task = tasks.spawn(
+                async move {
+                    let _permit = permit;
+
+                    if !update_control.proceed_async().await {
+                        return Err(UpdateError::Cancelled(UpdateProgress::new(
+                            UpdatePhase::Write,
+                        )));
+                    }
+
+                    let write_start = Instant::now();
+                    let digest = logical_block_clone.copy_async().await?;
+
+                    Ok((
+                        position,
+                        LogicalBlockReport {
+                            logical_block_id: logical_block_clone.id,
+                            status: LogicalBlockStatus::Cloned,
+                            digest: Some(digest),
+                            signature: None,
+                            destination_path: logical_block_clone
+                                .destination
+                                .get_path()
+                                .to_string(),
+                            written_bytes: logical_block_clone.destination.get_size() as u64,
+                            retries: 0,
+                            phase_durations: PhaseDurations {
+                                write: write_start.elapsed(),
+                                ..PhaseDurations::default()
+                            },
+                        },
+                    ))
+                }
+                .instrument(clone_span),
+            );
+            task_logical_block_ids.insert(task.id(), logical_block_id);
         }
-        Ok(())
+
+        Self::join_logical_block_tasks(tasks, task_logical_block_ids, update_report).await
+    }
+
+    /// Waits for every logical block task and adds their reports to `update_report` in the
+    /// order the tasks were spawned. On the first error, the tasks still running are aborted,
+    /// those completed before they stop are still added. A task that panicked fails the logical
+    /// block given for it in `task_logical_block_ids`.
+    async fn join_logical_block_tasks(
+        mut tasks: JoinSet<Result<(usize, LogicalBlockReport), UpdateError>>,
+        task_logical_block_ids: HashMap<task::Id, String>,
+        update_report: &mut UpdateReport,
+    ) -> Result<(), UpdateError> {
+        let mut logical_block_reports = Vec::new();
+        let mut failure = None;
+
+        while let Some(result) = tasks.join_next().await {
+            let result = match result {
+                Ok(result) => result,
+                // Aborted after the failure, already recorded
+                Err(error) if error.is_cancelled() => continue,
+                Err(error) => Err(UpdateError::LogicalBlockWrite(
+                    LogicalBlockError::new(
+                        task_logical_block_ids[&error.id()].clone(),
+                        UpdatePhase::Write,
+                        "Logical block task failed",
+                    )
+                    .caused_by(&error),
+                )),
+            };
+            match result {
+                Ok(logical_block_report) => logical_block_reports.push(logical_block_report),
+                Err(error) => {
+                    if failure.is_none() {
+                        tasks.abort_all();
+                        failure = Some(error);
+                    }
+                }
+            }
+        }
+
+        logical_block_reports.sort_by_key(|(position, _)| *position);
+        update_report.logical_blocks.extend(
+            logical_block_reports
+                .into_iter()
+                .map(|(_, logical_block_report)| logical_block_report),
+        );
+
+        match failure {
+            Some(error) => Err(error.after_completing(update_report.get_logical_block_ids())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes the logical block unless it is up to date, then verifies it against its signature.
+async fn install_logical_block(
+    logical_block: &mut LogicalBlock,
+    is_up_to_date: bool,
+    phase_durations: &mut PhaseDurations,
+    memory_mapping: &MemoryMapping,
+    update_control: &UpdateControl,
+) -> Result<LogicalBlockStatus, UpdateError> {
+    let offset = logical_block.destination.get_offset();
+    let size = logical_block.destination.get_size();
+
+    let status = if is_up_to_date {
+        debug!("logical block already up to date");
+        LogicalBlockStatus::Skipped
+    } else {
+        memory_mapping.record_slot_write();
+        let write_start = Instant::now();
+        let decompression_duration = logical_block
+            .write(update_control)
+            .instrument(info_span!("write", offset, size, bytes = field::Empty))
+            .await?;
+        phase_durations.decompression += decompression_duration;
+        phase_durations.write += write_start.elapsed().saturating_sub(decompression_duration);
+        LogicalBlockStatus::Written
+    };
+
+    let verify_start = Instant::now();
+    logical_block
+        .verify(update_control)
+        .instrument(info_span!("verify", offset, size))
+        .await?;
+    phase_durations.verify += verify_start.elapsed();
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn join_panicked_logical_block_task_test() {
+        let mut tasks = JoinSet::new();
+        let task = tasks.spawn(async { panic!("logical block task panicked") });
+
+        let error = SoftwareArchive::join_logical_block_tasks(
+            tasks,
+            HashMap::from([(task.id(), "FD01".to_string())]),
+            &mut UpdateReport::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.code(), 301);
+        assert_eq!(error.get_logical_block_id(), Some("FD01"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn join_logical_block_tasks_after_failure_test() {
+        let mut tasks = JoinSet::new();
+        let failing_task = tasks.spawn(async {
+            Err(UpdateError::LogicalBlockWrite(LogicalBlockError::new(
+                "FD01",
+                UpdatePhase::Write,
+                "Injected write failure",
+            )))
+        });
+        tasks.spawn(async {
+            Ok((
+                1,
+                LogicalBlockReport {
+                    logical_block_id: "FD02".to_string(),
+                    status: LogicalBlockStatus::Written,
+                    digest: None,
+                    signature: None,
+                    destination_path: "mtd_a".to_string(),
+                    written_bytes: 0,
+                    retries: 0,
+                    phase_durations: PhaseDurations::default(),
+                },
+            ))
+        });
+        tasks.spawn(std::future::pending());
+        let mut update_report = UpdateReport::default();
+
+        // The pending task is aborted, the one completed after the failure is still reported
+        let error = SoftwareArchive::join_logical_block_tasks(
+            tasks,
+            HashMap::from([(failing_task.id(), "FD01".to_string())]),
+            &mut update_report,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.get_logical_block_id(), Some("FD01"));
+        assert_eq!(update_report.get_logical_block_ids(), vec!["FD02"]);
     }
 }
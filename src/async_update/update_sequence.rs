@@ -1,29 +1,242 @@
-use crate::{async_update::memory::MemoryMapping, reporting::UpdateError};
+use std::time::Instant;
+
+use tracing::{info_span, Instrument};
+
+use crate::{
+    memory_mapping::MemoryMapping,
+    reporting::{trace_update_result, DocumentError, UpdateError, UpdatePhase, UpdateReport},
+    update_control::UpdateControl,
+};
 
 use super::software_archive::SoftwareArchive;
+use tokio::runtime::Runtime;
 
-pub fn async_update(
+pub const DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS: usize = 4;
+
+/// Updates from within the caller's runtime, processing up to `max_concurrent_logical_blocks`
+/// logical blocks at once.
+pub async fn update(
     memory_mapping_path: &str,
     software_archive_path: &str,
+    max_concurrent_logical_blocks: usize,
+) -> Result<UpdateReport, UpdateError> {
+    update_with_control(
+        memory_mapping_path,
+        software_archive_path,
+        max_concurrent_logical_blocks,
+        &UpdateControl::new(),
+    )
+    .await
+}
+
+/// Same as [`update`], letting `update_control` cancel, pause, throttle or hook the writes of the
+/// update while it runs, and record its outcome.
+pub async fn update_with_control(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    max_concurrent_logical_blocks: usize,
+    update_control: &UpdateControl,
+) -> Result<UpdateReport, UpdateError> {
+    let update_start = Instant::now();
+
+    let update_span = info_span!(
+        "update",
+        strategy = "async",
+        archive = software_archive_path,
+        mapping = memory_mapping_path
+    );
+    let mut update_report = UpdateReport::default();
+    let update_result = async {
+        let update_result = install_update(
+            memory_mapping_path,
+            software_archive_path,
+            max_concurrent_logical_blocks,
+            update_control,
+            &mut update_report,
+        )
+        .await;
+        trace_update_result(&update_result, &update_report);
+        update_result
+    }
+    .instrument(update_span)
+    .await;
+
+    let record_result = update_control.record_update(
+        software_archive_path,
+        &update_report,
+        update_result.as_ref().err(),
+        update_start.elapsed(),
+    );
+    update_result.and(record_result).map(|()| update_report)
+}
+
+async fn install_update(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    max_concurrent_logical_blocks: usize,
+    update_control: &UpdateControl,
+    update_report: &mut UpdateReport,
 ) -> Result<(), UpdateError> {
-    let memory_mapping = MemoryMapping::from(memory_mapping_path)?;
+    let memory_mapping =
+        MemoryMapping::from(memory_mapping_path)?.with_write_hook(update_control.get_write_hook());
+    update_report.targeted_bank = Some(memory_mapping.get_targeted_bank().to_string());
 
     let software_archive = SoftwareArchive::from(software_archive_path)?;
 
-    software_archive.extract_logical_blocks(memory_mapping)
+    software_archive
+        .extract_logical_blocks(
+            memory_mapping,
+            max_concurrent_logical_blocks,
+            update_control,
+            update_report,
+        )
+        .await
+}
+
+/// Blocking entry point for callers that don't run a tokio runtime themselves.
+pub fn async_update(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+) -> Result<UpdateReport, UpdateError> {
+    let rt = Runtime::new().map_err(|error| {
+        UpdateError::Runtime(
+            DocumentError::new(
+                software_archive_path,
+                UpdatePhase::Index,
+                "Unable to start the runtime running the update",
+            )
+            .caused_by(&error),
+        )
+    })?;
+
+    rt.block_on(update(
+        memory_mapping_path,
+        software_archive_path,
+        DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        audit_log::{AuditLog, AuditOutcome},
+        reporting::{LogicalBlockStatus, UpdatePhase, UpdateProgress},
+        test_utils::{
+            assert_written_in_stages, fail_failing_writes, ArchiveBuilder, MappingBuilder,
+            WriteRecorder,
+        },
+    };
 
     #[test]
     fn async_update_test() {
-        let result = async_update(
-            "./resources/test/test_lb_cfg.json",
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        let result = async_update(&mapping_path, "./resources/test/update_folder.zip");
+
+        assert_eq!(result.unwrap().logical_blocks.len(), 9)
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn async_update_installs_stages_in_order_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let archive_path = ArchiveBuilder::new(destination_dir.path())
+            .depends_on("FD03", &["FD01"])
+            .depends_on("FD07", &["FD01"])
+            .phase(1, &["FD02", "FD05", "FD08"])
+            .build();
+        let write_recorder = WriteRecorder::new(&mapping_path);
+
+        // Up to 4 logical blocks of a stage are written at once, the next stage waits for all of them
+        update_with_control(
+            &mapping_path,
+            &archive_path,
+            DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS,
+            &UpdateControl::new().with_write_hook(write_recorder.clone()),
+        )
+        .await
+        .unwrap();
+
+        assert_written_in_stages(
+            &write_recorder.get_writes(),
+            &[
+                &["FD01", "FD04", "FD06", "FD09"],
+                &["FD03", "FD07"],
+                &["FD02", "FD05", "FD08"],
+            ],
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn update_with_audit_log_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .retry(serde_json::json!({ "max_attempts": 1 }))
+            .failing(&["FD09"])
+            .build();
+        let audit_log = AuditLog::from(destination_dir.path().join("audit.log").to_str().unwrap());
+        let update_control = UpdateControl::new()
+            .with_audit_log(audit_log.clone())
+            .with_write_hook(fail_failing_writes);
+
+        update_with_control(
+            &mapping_path,
             "./resources/test/update_folder.zip",
+            1,
+            &update_control,
+        )
+        .await
+        .unwrap_err();
+
+        let entries = audit_log.read_entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(matches!(
+            entries[0].record.outcome,
+            AuditOutcome::Failure {
+                error_code: 301,
+                ..
+            }
+        ));
+        assert_eq!(entries[0].record.logical_blocks.len(), 8);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn update_within_caller_runtime_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        let update_report = update(&mapping_path, "./resources/test/update_folder.zip", 2)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            update_report.get_logical_blocks_with_status(LogicalBlockStatus::Written),
+            vec!["FD01", "FD02", "FD03", "FD04", "FD05", "FD06", "FD07", "FD08", "FD09"]
         );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn cancelled_update_reports_progress_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let update_control = UpdateControl::new();
+        update_control.cancel();
 
-        assert_eq!(result, Ok(()))
+        let error = update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            2,
+            &update_control,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.code(), 800);
+        assert_eq!(
+            error,
+            UpdateError::Cancelled(UpdateProgress::new(UpdatePhase::Write))
+        );
     }
 }
@@ -0,0 +1,345 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose, Engine};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    digest::{sha256_hex_of_file_region, to_hex},
+    reporting::{DocumentError, LogicalBlockStatus, UpdateError, UpdatePhase, UpdateReport},
+};
+
+/// Hash the first entry of a log is chained to.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Append-only log of the updates applied on the device, stored as one JSON entry per line.
+///
+/// Each entry holds the hash of the previous one, so that altering, removing or reordering
+/// entries is detected by [`AuditLog::verify`]. Removing the last entries leaves a valid chain,
+/// only [`AuditLog::verify_head`] detects it, given the hash of the last entry appended, kept
+/// outside the log.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AuditLogEntry {
+    pub sequence: u64,
+    pub previous_hash: String,
+    #[serde(flatten)]
+    pub record: AuditRecord,
+    pub hash: String,
+}
+
+impl AuditLogEntry {
+    fn compute_hash(&self) -> String {
+        let content =
+            serde_json::to_string(&(self.sequence, &self.previous_hash, &self.record)).unwrap();
+        to_hex(&sha256(content.as_bytes()))
+    }
+}
+
+/// What an audit log entry records about an update attempt.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// `None` when the archive couldn't be read.
+    pub archive_digest: Option<String>,
+    /// `None` when the update failed before loading its memory mapping.
+    pub targeted_bank: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Logical blocks installed or cloned, before the failure if the update failed.
+    pub logical_blocks: Vec<AuditedLogicalBlock>,
+}
+
+impl AuditRecord {
+    /// Records an update that installed the logical blocks of `update_report`, then failed with
+    /// `update_error` if any.
+    pub fn from_update(
+        archive_digest: Option<String>,
+        update_report: &UpdateReport,
+        update_error: Option<&UpdateError>,
+    ) -> AuditRecord {
+        let outcome = match update_error {
+            None => AuditOutcome::Success,
+            Some(error) => AuditOutcome::Failure {
+                error_code: error.code(),
+                description: error.to_string(),
+            },
+        };
+
+        AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            archive_digest,
+            targeted_bank: update_report.targeted_bank.clone(),
+            outcome,
+            logical_blocks: update_report
+                .logical_blocks
+                .iter()
+                .map(|logical_block| AuditedLogicalBlock {
+                    id: logical_block.logical_block_id.clone(),
+                    digest: logical_block.digest.clone(),
+                    signature_fingerprint: logical_block
+                        .signature
+                        .as_deref()
+                        .map(get_signature_fingerprint),
+                    status: logical_block.status,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure {
+        error_code: u16,
+        description: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AuditedLogicalBlock {
+    pub id: String,
+    pub digest: Option<String>,
+    /// SHA-256 of the decoded signature, `None` for logical blocks cloned from the active bank.
+    pub signature_fingerprint: Option<String>,
+    pub status: LogicalBlockStatus,
+}
+
+impl AuditLog {
+    pub fn from(path: &str) -> AuditLog {
+        AuditLog {
+            path: path.to_string(),
+        }
+    }
+
+    /// Chains `record` to the last entry of the log and appends it, syncing the log to disk.
+    pub fn append(&self, record: AuditRecord) -> Result<AuditLogEntry, UpdateError> {
+        let entries = self.read_entries()?;
+
+        let mut entry = AuditLogEntry {
+            sequence: entries.len() as u64,
+            previous_hash: entries
+                .last()
+                .map_or(GENESIS_HASH.to_string(), |entry| entry.hash.clone()),
+            record,
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+
+        let write_error =
+            |error: &(dyn Error + 'static)| self.error("Unable to append entry", error);
+
+        let mut file = File::options()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .map_err(|error| write_error(&error))?;
+        let line = serde_json::to_string(&entry).map_err(|error| write_error(&error))?;
+        writeln!(file, "{line}").map_err(|error| write_error(&error))?;
+        file.sync_all().map_err(|error| write_error(&error))?;
+
+        Ok(entry)
+    }
+
+    /// Reads every entry of the log, without checking the hash chain. A missing log is empty.
+    pub fn read_entries(&self) -> Result<Vec<AuditLogEntry>, UpdateError> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(self.error("Unable to open log", &error)),
+        };
+
+        BufReader::new(file)
+            .lines()
+            .enumerate()
+            .map(|(line_index, line)| {
+                let line = line.map_err(|error| self.error("Unable to read log", &error))?;
+                serde_json::from_str(&line).map_err(|error| {
+                    self.error(&format!("Unable to parse entry {line_index}"), &error)
+                })
+            })
+            .collect()
+    }
+
+    /// Checks the hash chain of the log and returns its number of entries.
+    pub fn verify(&self) -> Result<usize, UpdateError> {
+        self.read_verified_entries().map(|entries| entries.len())
+    }
+
+    /// Same as [`AuditLog::verify`], also checking that the log ends with the entry hashed to
+    /// `head_hash`, as returned by the last [`AuditLog::append`].
+    pub fn verify_head(&self, head_hash: &str) -> Result<usize, UpdateError> {
+        let entries = self.read_verified_entries()?;
+
+        let last_hash = entries.last().map_or(GENESIS_HASH, |entry| &entry.hash);
+        if last_hash != head_hash {
+            return Err(UpdateError::AuditLog(DocumentError::new(
+                &self.path,
+                UpdatePhase::Audit,
+                format!(
+                    "Log ends with entry {last_hash} instead of {head_hash}, the log has been \
+                     truncated or tampered with"
+                ),
+            )));
+        }
+
+        Ok(entries.len())
+    }
+
+    fn read_verified_entries(&self) -> Result<Vec<AuditLogEntry>, UpdateError> {
+        let entries = self.read_entries()?;
+        let mut previous_hash = GENESIS_HASH;
+
+        for (sequence, entry) in entries.iter().enumerate() {
+            if entry.sequence != sequence as u64 || entry.previous_hash != previous_hash {
+                return Err(self.tampering_error(sequence, "doesn't follow the previous entry"));
+            }
+            if entry.hash != entry.compute_hash() {
+                return Err(self.tampering_error(sequence, "doesn't match its hash"));
+            }
+            previous_hash = &entry.hash;
+        }
+
+        Ok(entries)
+    }
+
+    fn error(&self, description: &str, error: &(dyn Error + 'static)) -> UpdateError {
+        UpdateError::AuditLog(
+            DocumentError::new(&self.path, UpdatePhase::Audit, description).caused_by(error),
+        )
+    }
+
+    fn tampering_error(&self, sequence: usize, description: &str) -> UpdateError {
+        UpdateError::AuditLog(DocumentError::new(
+            &self.path,
+            UpdatePhase::Audit,
+            format!("Entry {sequence} {description}, the log has been tampered with"),
+        ))
+    }
+}
+
+/// Computes the SHA-256 digest of a whole software archive, as recorded in the audit log.
+pub(crate) fn get_archive_digest(software_archive_path: &str) -> Result<String, UpdateError> {
+    let digest_error = |error: &(dyn Error + 'static)| {
+        UpdateError::InvalidArchive(
+            DocumentError::new(
+                software_archive_path,
+                UpdatePhase::Audit,
+                "Unable to compute archive digest",
+            )
+            .caused_by(error),
+        )
+    };
+
+    let archive_size = std::fs::metadata(software_archive_path)
+        .map_err(|error| digest_error(&error))?
+        .len();
+
+    sha256_hex_of_file_region(software_archive_path, 0, archive_size as usize)
+        .map_err(|error| digest_error(&error))
+}
+
+fn get_signature_fingerprint(signature: &str) -> String {
+    match general_purpose::STANDARD.decode(signature) {
+        Ok(decoded_signature) => to_hex(&sha256(&decoded_signature)),
+        Err(_) => to_hex(&sha256(signature.as_bytes())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::reporting::{LogicalBlockReport, PhaseDurations};
+
+    fn successful_record() -> AuditRecord {
+        let update_report = UpdateReport {
+            logical_blocks: vec![LogicalBlockReport {
+                logical_block_id: "FD01".to_string(),
+                status: LogicalBlockStatus::Written,
+                digest: Some("ab".repeat(32)),
+                signature: Some("c2lnbmF0dXJl".to_string()),
+                destination_path: "./mtd_a".to_string(),
+                written_bytes: 130757,
+                retries: 0,
+                phase_durations: PhaseDurations::default(),
+            }],
+            targeted_bank: Some("bank_a".to_string()),
+            index_parsing_duration: Duration::ZERO,
+        };
+
+        AuditRecord::from_update(Some("cd".repeat(32)), &update_report, None)
+    }
+
+    #[test]
+    fn append_and_verify_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = AuditLog::from(dir.path().join("audit.log").to_str().unwrap());
+
+        assert_eq!(audit_log.verify().unwrap(), 0);
+
+        let first_entry = audit_log.append(successful_record()).unwrap();
+        let second_entry = audit_log.append(successful_record()).unwrap();
+
+        assert_eq!(first_entry.previous_hash, GENESIS_HASH);
+        assert_eq!(second_entry.previous_hash, first_entry.hash);
+        assert_eq!(audit_log.verify().unwrap(), 2);
+        assert_eq!(
+            audit_log.read_entries().unwrap()[0].record.logical_blocks[0].signature_fingerprint,
+            Some(to_hex(&sha256(b"signature")))
+        );
+    }
+
+    #[test]
+    fn verify_detects_tampering_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let audit_log = AuditLog::from(path.to_str().unwrap());
+
+        audit_log.append(successful_record()).unwrap();
+        audit_log.append(successful_record()).unwrap();
+
+        let log_content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, log_content.replacen("bank_a", "bank_b", 1)).unwrap();
+
+        let error = audit_log.verify().unwrap_err();
+        assert_eq!(error.code(), 500);
+        assert!(error.to_string().contains("Entry 0"));
+    }
+
+    #[test]
+    fn verify_head_detects_truncation_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let audit_log = AuditLog::from(path.to_str().unwrap());
+
+        audit_log.append(successful_record()).unwrap();
+        let head_hash = audit_log.append(successful_record()).unwrap().hash;
+        assert_eq!(audit_log.verify_head(&head_hash).unwrap(), 2);
+
+        let log_content = std::fs::read_to_string(&path).unwrap();
+        let first_line_end = log_content.find('\n').unwrap() + 1;
+        std::fs::write(&path, &log_content[..first_line_end]).unwrap();
+
+        // The remaining chain is still valid
+        assert_eq!(audit_log.verify().unwrap(), 1);
+        let error = audit_log.verify_head(&head_hash).unwrap_err();
+        assert_eq!(error.code(), 500);
+        assert!(error.to_string().contains("truncated"));
+    }
+}
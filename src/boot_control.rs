@@ -0,0 +1,404 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::reporting::{DocumentError, UpdateError, UpdatePhase};
+
+/// Boots of an unconfirmed bank after which the device falls back to the previous bank.
+pub const DEFAULT_MAX_BOOT_ATTEMPTS: u32 = 3;
+
+/// Size of each copy of the record in the state file.
+const COPY_SIZE: usize = 1024;
+/// Each copy starts with the CRC-32 of the rest of the copy, then the length of the record.
+const COPY_HEADER_SIZE: usize = 8;
+
+/// Record telling the bootloader which bank to boot, and which banks hold a complete software.
+///
+/// An update first marks the bank it installs into as invalid, then switches boot to it only
+/// once every logical block is written and verified. The new software must then confirm it
+/// booted with [`BootControl::mark_boot_successful`], or the device falls back to the previous
+/// bank after too many boot attempts.
+///
+/// The state file holds two CRC-protected copies of the record. Each change overwrites the
+/// oldest copy, so that a change interrupted mid-write leaves the previous record readable.
+#[derive(Debug)]
+pub struct BootControl {
+    path: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct BootControlRecord {
+    /// Incremented on each change of the record.
+    pub generation: u64,
+    /// Bank the device boots, `None` until an update is committed.
+    pub active_slot: Option<String>,
+    /// Bank booted before the last update, which the device falls back to on rollback.
+    pub previous_slot: Option<String>,
+    /// Whether the software of the active bank confirmed it booted.
+    pub boot_confirmed: bool,
+    /// Boots of the active bank since it was committed, while unconfirmed.
+    pub boot_attempts: u32,
+    pub slots: BTreeMap<String, SlotState>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotState {
+    /// Every logical block of the bank was written and verified.
+    Valid,
+    /// The bank is being, or failed being, updated and must not be booted.
+    Invalid,
+}
+
+impl BootControl {
+    pub fn from(path: &str) -> BootControl {
+        BootControl {
+            path: path.to_string(),
+        }
+    }
+
+    /// Reads the most recent valid copy of the record, a missing state file meaning no update
+    /// was committed yet.
+    pub fn read_record(&self) -> Result<BootControlRecord, UpdateError> {
+        let content = match std::fs::read(&self.path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(BootControlRecord::default())
+            }
+            Err(error) => return Err(self.error("Unable to read boot-control record", &error)),
+        };
+
+        content
+            .chunks(COPY_SIZE)
+            .filter_map(decode_copy)
+            .max_by_key(|record| record.generation)
+            .ok_or_else(|| {
+                UpdateError::BootControl(DocumentError::new(
+                    &self.path,
+                    UpdatePhase::Commit,
+                    "No valid copy of the boot-control record",
+                ))
+            })
+    }
+
+    /// Marks `slot` invalid before the update starts overwriting it.
+    pub fn begin_staging(&self, slot: &str) -> Result<BootControlRecord, UpdateError> {
+        let mut record = self.read_record()?;
+
+        if record.active_slot.as_deref() == Some(slot) {
+            return Err(self.refusal(format!(
+                "Refusing to stage an update in {slot}, the active bank"
+            )));
+        }
+        if let (Some(active_slot), false) = (&record.active_slot, record.boot_confirmed) {
+            return Err(self.refusal(format!(
+                "Refusing to stage an update before the boot of {active_slot} is confirmed"
+            )));
+        }
+
+        record.slots.insert(slot.to_string(), SlotState::Invalid);
+        self.write_record(record)
+    }
+
+    /// Marks `slot` invalid before the update overwrites it in place, `slot` being the only
+    /// bank of the single slot layout. There is no other bank to fall back to, a failed update
+    /// boots the recovery image instead, so the active bank is staged whether its boot was
+    /// confirmed or not.
+    pub fn begin_staging_in_place(&self, slot: &str) -> Result<BootControlRecord, UpdateError> {
+        let mut record = self.read_record()?;
+
+        record.slots.insert(slot.to_string(), SlotState::Invalid);
+        self.write_record(record)
+    }
+
+    /// Switches boot to `slot`, once all its logical blocks are verified. The boot then has to
+    /// be confirmed with [`BootControl::mark_boot_successful`].
+    pub fn commit(&self, slot: &str) -> Result<BootControlRecord, UpdateError> {
+        let mut record = self.read_record()?;
+
+        record.slots.insert(slot.to_string(), SlotState::Valid);
+        // A bank updated in place has nothing to roll back to
+        record.previous_slot = record
+            .active_slot
+            .replace(slot.to_string())
+            .filter(|previous_slot| previous_slot != slot);
+        record.boot_confirmed = false;
+        record.boot_attempts = 0;
+        self.write_record(record)
+    }
+
+    /// Counts a boot of the active bank and returns the bank to boot. Once the active bank
+    /// booted more than `max_boot_attempts` times without confirming it, rolls back.
+    pub fn record_boot_attempt(&self, max_boot_attempts: u32) -> Result<String, UpdateError> {
+        let mut record = self.read_record()?;
+
+        let active_slot = match (&record.active_slot, record.boot_confirmed) {
+            (Some(active_slot), true) => return Ok(active_slot.clone()),
+            (Some(active_slot), false) => active_slot.clone(),
+            (None, _) => return Err(self.refusal("No bank was committed".to_string())),
+        };
+
+        if record.boot_attempts >= max_boot_attempts {
+            return self.rollback_record(record);
+        }
+
+        record.boot_attempts += 1;
+        self.write_record(record)?;
+        Ok(active_slot)
+    }
+
+    /// Confirms the software of the active bank booted, which stops any automatic rollback.
+    pub fn mark_boot_successful(&self) -> Result<BootControlRecord, UpdateError> {
+        let mut record = self.read_record()?;
+
+        if record.active_slot.is_none() {
+            return Err(self.refusal("No bank was committed".to_string()));
+        }
+
+        record.boot_confirmed = true;
+        record.boot_attempts = 0;
+        self.write_record(record)
+    }
+
+    /// Boots of the active bank since it was committed, while unconfirmed.
+    pub fn get_boot_attempts(&self) -> Result<u32, UpdateError> {
+        Ok(self.read_record()?.boot_attempts)
+    }
+
+    /// Marks the active bank invalid and switches boot back to the previous bank, which is
+    /// returned.
+    pub fn rollback(&self) -> Result<String, UpdateError> {
+        self.rollback_record(self.read_record()?)
+    }
+
+    fn rollback_record(&self, mut record: BootControlRecord) -> Result<String, UpdateError> {
+        let previous_slot = match record.previous_slot.take() {
+            Some(previous_slot) => previous_slot,
+            None => return Err(self.refusal("No previous bank to roll back to".to_string())),
+        };
+
+        if let Some(active_slot) = record.active_slot.replace(previous_slot.clone()) {
+            record.slots.insert(active_slot, SlotState::Invalid);
+        }
+        record.boot_confirmed = true;
+        record.boot_attempts = 0;
+        self.write_record(record)?;
+
+        Ok(previous_slot)
+    }
+
+    fn write_record(
+        &self,
+        mut record: BootControlRecord,
+    ) -> Result<BootControlRecord, UpdateError> {
+        let write_error = |error: &(dyn Error + 'static)| {
+            self.error("Unable to write boot-control record", error)
+        };
+
+        record.generation += 1;
+        let content = serde_json::to_vec(&record).map_err(|error| write_error(&error))?;
+        if content.len() > COPY_SIZE - COPY_HEADER_SIZE {
+            return Err(self.refusal(format!(
+                "Boot-control record of {} bytes doesn't fit in {COPY_SIZE} bytes",
+                content.len()
+            )));
+        }
+
+        let mut copy = vec![0; COPY_SIZE];
+        copy[4..8].copy_from_slice(&(content.len() as u32).to_le_bytes());
+        copy[COPY_HEADER_SIZE..COPY_HEADER_SIZE + content.len()].copy_from_slice(&content);
+        let crc = crc32fast::hash(&copy[4..]);
+        copy[..4].copy_from_slice(&crc.to_le_bytes());
+
+        // Overwrite the oldest copy, the other one is the record being replaced
+        let offset = (record.generation % 2) * COPY_SIZE as u64;
+
+        let mut file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .map_err(|error| write_error(&error))?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|error| write_error(&error))?;
+        file.write_all(&copy).map_err(|error| write_error(&error))?;
+        file.sync_all().map_err(|error| write_error(&error))?;
+
+        Ok(record)
+    }
+
+    fn refusal(&self, description: String) -> UpdateError {
+        UpdateError::BootControl(DocumentError::new(
+            &self.path,
+            UpdatePhase::Commit,
+            description,
+        ))
+    }
+
+    fn error(&self, description: &str, error: &(dyn Error + 'static)) -> UpdateError {
+        UpdateError::BootControl(
+            DocumentError::new(&self.path, UpdatePhase::Commit, description).caused_by(error),
+        )
+    }
+}
+
+/// Returns the record stored in `copy`, unless the copy is incomplete or corrupted.
+fn decode_copy(copy: &[u8]) -> Option<BootControlRecord> {
+    if copy.len() != COPY_SIZE {
+        return None;
+    }
+
+    let crc = u32::from_le_bytes(copy[..4].try_into().unwrap());
+    if crc != crc32fast::hash(&copy[4..]) {
+        return None;
+    }
+
+    let length = u32::from_le_bytes(copy[4..8].try_into().unwrap()) as usize;
+    let content = copy.get(COPY_HEADER_SIZE..COPY_HEADER_SIZE + length)?;
+    serde_json::from_slice(content).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_boot_control_in(dir: &std::path::Path) -> BootControl {
+        BootControl::from(dir.join("boot_control").to_str().unwrap())
+    }
+
+    #[test]
+    fn stage_and_commit_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let boot_control = create_boot_control_in(dir.path());
+
+        assert_eq!(
+            boot_control.read_record().unwrap(),
+            BootControlRecord::default()
+        );
+
+        boot_control.commit("bank_a").unwrap();
+        let error = boot_control.begin_staging("bank_b").unwrap_err();
+        assert!(error.to_string().contains("boot of bank_a is confirmed"));
+
+        boot_control.mark_boot_successful().unwrap();
+        let staged_record = boot_control.begin_staging("bank_b").unwrap();
+        assert_eq!(staged_record.active_slot.as_deref(), Some("bank_a"));
+        assert_eq!(staged_record.slots["bank_b"], SlotState::Invalid);
+
+        let committed_record = boot_control.commit("bank_b").unwrap();
+        assert_eq!(committed_record.generation, 4);
+        assert_eq!(committed_record.active_slot.as_deref(), Some("bank_b"));
+        assert_eq!(committed_record.previous_slot.as_deref(), Some("bank_a"));
+        assert_eq!(committed_record.slots["bank_b"], SlotState::Valid);
+        assert_eq!(boot_control.read_record().unwrap(), committed_record);
+
+        let error = boot_control.begin_staging("bank_b").unwrap_err();
+        assert_eq!(error.code(), 700);
+        assert_eq!(error.phase(), UpdatePhase::Commit);
+    }
+
+    #[test]
+    fn stage_in_place_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let boot_control = create_boot_control_in(dir.path());
+
+        boot_control.commit("single").unwrap();
+        let staged_record = boot_control.begin_staging_in_place("single").unwrap();
+        assert_eq!(staged_record.active_slot.as_deref(), Some("single"));
+        assert_eq!(staged_record.slots["single"], SlotState::Invalid);
+
+        let committed_record = boot_control.commit("single").unwrap();
+        assert_eq!(committed_record.active_slot.as_deref(), Some("single"));
+        assert_eq!(committed_record.previous_slot, None);
+        assert_eq!(committed_record.slots["single"], SlotState::Valid);
+        assert!(boot_control.rollback().is_err());
+    }
+
+    #[test]
+    fn rollback_after_failed_boot_attempts_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let boot_control = create_boot_control_in(dir.path());
+
+        boot_control.commit("bank_a").unwrap();
+        boot_control.mark_boot_successful().unwrap();
+        boot_control.commit("bank_b").unwrap();
+
+        for _ in 0..DEFAULT_MAX_BOOT_ATTEMPTS {
+            assert_eq!(
+                boot_control
+                    .record_boot_attempt(DEFAULT_MAX_BOOT_ATTEMPTS)
+                    .unwrap(),
+                "bank_b"
+            );
+        }
+        assert_eq!(
+            boot_control.get_boot_attempts().unwrap(),
+            DEFAULT_MAX_BOOT_ATTEMPTS
+        );
+
+        assert_eq!(
+            boot_control
+                .record_boot_attempt(DEFAULT_MAX_BOOT_ATTEMPTS)
+                .unwrap(),
+            "bank_a"
+        );
+        let record = boot_control.read_record().unwrap();
+        assert_eq!(record.active_slot.as_deref(), Some("bank_a"));
+        assert_eq!(record.slots["bank_b"], SlotState::Invalid);
+        assert!(record.boot_confirmed);
+
+        assert_eq!(boot_control.rollback().unwrap_err().code(), 700);
+    }
+
+    #[test]
+    fn confirmed_boot_is_kept_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let boot_control = create_boot_control_in(dir.path());
+
+        boot_control.commit("bank_a").unwrap();
+        boot_control.mark_boot_successful().unwrap();
+        boot_control.commit("bank_b").unwrap();
+        boot_control.record_boot_attempt(1).unwrap();
+        boot_control.mark_boot_successful().unwrap();
+
+        assert_eq!(boot_control.record_boot_attempt(1).unwrap(), "bank_b");
+        assert_eq!(boot_control.record_boot_attempt(1).unwrap(), "bank_b");
+        assert_eq!(boot_control.get_boot_attempts().unwrap(), 0);
+
+        assert_eq!(boot_control.rollback().unwrap(), "bank_a");
+        assert_eq!(
+            boot_control.read_record().unwrap().slots["bank_b"],
+            SlotState::Invalid
+        );
+    }
+
+    #[test]
+    fn corrupted_copy_falls_back_to_previous_record_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let boot_control = create_boot_control_in(dir.path());
+
+        boot_control.commit("bank_a").unwrap();
+        let latest_record = boot_control.mark_boot_successful().unwrap();
+
+        // Corrupt the latest copy, as an interrupted write would
+        let path = dir.path().join("boot_control");
+        let mut content = std::fs::read(&path).unwrap();
+        let latest_copy = (latest_record.generation % 2) as usize * COPY_SIZE;
+        content[latest_copy + COPY_HEADER_SIZE] ^= 0xff;
+        std::fs::write(&path, &content).unwrap();
+
+        let record = boot_control.read_record().unwrap();
+        assert_eq!(record.generation, latest_record.generation - 1);
+        assert!(!record.boot_confirmed);
+
+        let other_copy = COPY_SIZE - latest_copy;
+        content[other_copy + COPY_HEADER_SIZE] ^= 0xff;
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(boot_control.read_record().unwrap_err().code(), 700);
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::{fs::FileExt, io::AsRawFd},
+    ptr,
+    sync::Arc,
+};
+
+use crate::nand::{NandDevice, NandLayout, ERASED_BYTE};
+
+/// Size of the chunks copied and verified when the destination doesn't set one.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+/// Checks the chunking settings of a destination and tells why they are invalid.
+pub(crate) fn check_chunking(buffer_size: usize, alignment: usize) -> Result<(), String> {
+    if buffer_size == 0 {
+        return Err("Buffer size must not be 0".to_string());
+    }
+    if !alignment.is_power_of_two() {
+        return Err(format!("Alignment ({alignment}) must be a power of two"));
+    }
+    if !buffer_size.is_multiple_of(alignment) {
+        return Err(format!(
+            "Buffer size ({buffer_size}) must be a multiple of the alignment ({alignment})"
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the size of the chunk to write at `offset` of a destination, so that the chunk
+/// after it starts on a multiple of `alignment`.
+pub(crate) fn get_chunk_size(offset: u64, buffer_size: usize, alignment: usize) -> usize {
+    buffer_size - (offset % alignment as u64) as usize
+}
+
+/// Returns how many bytes to write at `offset` of a destination before reaching a multiple of
+/// `alignment`.
+pub(crate) fn get_misaligned_size(offset: u64, alignment: usize) -> usize {
+    (alignment - (offset % alignment as u64) as usize) % alignment
+}
+
+/// Reads until `chunk_buffer` is full or `reader` is exhausted, so that chunks keep their
+/// alignment whatever the reader returns at once. Returns the number of bytes read.
+pub(crate) fn read_chunk(reader: &mut impl Read, chunk_buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read_bytes = 0;
+
+    while read_bytes < chunk_buffer.len() {
+        match reader.read(&mut chunk_buffer[read_bytes..]) {
+            Ok(0) => break,
+            Ok(n) => read_bytes += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read_bytes)
+}
+
+/// Reads the next chunk of a logical block that `remaining_bytes` of its destination are left
+/// for, like [`read_chunk`] but never past them: once they are read, fails unless `reader` is
+/// exhausted, so that an oversized logical block never overwrites the next region.
+pub(crate) fn read_chunk_within(
+    reader: &mut impl Read,
+    chunk_buffer: &mut [u8],
+    remaining_bytes: usize,
+) -> io::Result<usize> {
+    if remaining_bytes > 0 {
+        let chunk_size = chunk_buffer.len().min(remaining_bytes);
+        return read_chunk(reader, &mut chunk_buffer[..chunk_size]);
+    }
+
+    match read_chunk(reader, &mut [0; 1])? {
+        0 => Ok(0),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Logical block is larger than its destination",
+        )),
+    }
+}
+
+/// Called before every write to the destinations of an update with the path, offset and size of
+/// the write, which fails with the error it returns. Lets integrators keep an update away from
+/// regions they protect, or see how their devices cope with failing writes.
+pub trait WriteHook: Send + Sync {
+    fn before_write(&self, path: &str, offset: u64, size: usize) -> io::Result<()>;
+}
+
+impl<F: Fn(&str, u64, usize) -> io::Result<()> + Send + Sync> WriteHook for F {
+    fn before_write(&self, path: &str, offset: u64, size: usize) -> io::Result<()> {
+        self(path, offset, size)
+    }
+}
+
+impl fmt::Debug for dyn WriteHook {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("WriteHook")
+    }
+}
+
+/// Hooks are only equal to themselves.
+impl PartialEq for dyn WriteHook {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::addr_eq(self, other)
+    }
+}
+
+/// File a destination is read from and written to, through the bad-block mapping of the NAND
+/// device it is on, if any.
+#[derive(Debug)]
+pub(crate) enum DestinationFile {
+    Plain(File),
+    Nand(NandDevice),
+    /// Destination at the given path whose writes go through a hook first.
+    Hooked(Box<DestinationFile>, String, Arc<dyn WriteHook>),
+}
+
+impl DestinationFile {
+    /// Opens the destination stored at `path` for reading.
+    pub(crate) fn open(
+        path: &str,
+        nand_layout: Option<&NandLayout>,
+    ) -> io::Result<DestinationFile> {
+        Self::from(File::open(path)?, nand_layout)
+    }
+
+    /// Opens the destination stored at `path` for writing, and reading back what was written.
+    /// Destinations are devices or partitions that must exist, they are never created.
+    pub(crate) fn create(
+        path: &str,
+        nand_layout: Option<&NandLayout>,
+    ) -> io::Result<DestinationFile> {
+        Self::from(
+            File::options().read(true).write(true).open(path)?,
+            nand_layout,
+        )
+    }
+
+    fn from(file: File, nand_layout: Option<&NandLayout>) -> io::Result<DestinationFile> {
+        match nand_layout {
+            Some(nand_layout) => Ok(DestinationFile::Nand(NandDevice::from(file, nand_layout)?)),
+            None => Ok(DestinationFile::Plain(file)),
+        }
+    }
+
+    /// Makes the writes to the destination, stored at `path`, go through `write_hook` first.
+    pub(crate) fn hooked(self, path: &str, write_hook: Arc<dyn WriteHook>) -> DestinationFile {
+        DestinationFile::Hooked(Box::new(self), path.to_string(), write_hook)
+    }
+
+    pub(crate) fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            DestinationFile::Plain(file) => file.read_exact_at(buffer, offset),
+            DestinationFile::Nand(device) => device.read_exact_at(buffer, offset),
+            DestinationFile::Hooked(destination, _, _) => destination.read_exact_at(buffer, offset),
+        }
+    }
+
+    pub(crate) fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        match self {
+            DestinationFile::Plain(file) => file.write_all_at(data, offset),
+            DestinationFile::Nand(device) => device.write_all_at(data, offset),
+            DestinationFile::Hooked(destination, path, write_hook) => {
+                write_hook.before_write(path, offset, data.len())?;
+                destination.write_all_at(data, offset)
+            }
+        }
+    }
+
+    /// Fills `size` bytes at `offset` with [`ERASED_BYTE`], `buffer_size` bytes at a time. NAND
+    /// devices erase the whole erase blocks holding them instead.
+    pub(crate) fn erase(&self, offset: u64, size: usize, buffer_size: usize) -> io::Result<()> {
+        match self {
+            DestinationFile::Nand(device) => return device.erase(offset, size),
+            DestinationFile::Hooked(destination, path, write_hook) => {
+                write_hook.before_write(path, offset, size)?;
+                return destination.erase(offset, size, buffer_size);
+            }
+            DestinationFile::Plain(_) => {}
+        }
+
+        let erased_buffer = vec![ERASED_BYTE; buffer_size.min(size)];
+        let mut erased_bytes = 0;
+
+        while erased_bytes < size {
+            let chunk_size = erased_buffer.len().min(size - erased_bytes);
+            self.write_all_at(&erased_buffer[..chunk_size], offset + erased_bytes as u64)?;
+            erased_bytes += chunk_size;
+        }
+        Ok(())
+    }
+
+    /// Copies `size` bytes of `source` at `source_offset` to `offset`, within the kernel for plain
+    /// files, see [`copy_file_region`].
+    pub(crate) fn copy_from(
+        &self,
+        source: &File,
+        source_offset: u64,
+        offset: u64,
+        size: usize,
+    ) -> io::Result<()> {
+        match self {
+            DestinationFile::Plain(file) => {
+                copy_file_region(source, source_offset, file, offset, size)
+            }
+            DestinationFile::Nand(device) => {
+                let mut chunk_buffer = vec![0; size];
+                source.read_exact_at(&mut chunk_buffer, source_offset)?;
+                device.write_all_at(&chunk_buffer, offset)
+            }
+            DestinationFile::Hooked(destination, path, write_hook) => {
+                write_hook.before_write(path, offset, size)?;
+                destination.copy_from(source, source_offset, offset, size)
+            }
+        }
+    }
+}
+
+/// Copies `size` bytes of `source` at `source_offset` to `destination` at
+/// `destination_offset` within the kernel, with `copy_file_range`, or with `sendfile` when the
+/// kernel can't copy between these two files. Falls back to reads and writes otherwise.
+pub(crate) fn copy_file_region(
+    source: &File,
+    source_offset: u64,
+    destination: &File,
+    destination_offset: u64,
+    size: usize,
+) -> io::Result<()> {
+    match copy_with_copy_file_range(source, source_offset, destination, destination_offset, size) {
+        Err(error) if is_unsupported(&error) => {}
+        result => return result,
+    }
+    match copy_with_sendfile(source, source_offset, destination, destination_offset, size) {
+        Err(error) if is_unsupported(&error) => {}
+        result => return result,
+    }
+
+    let mut chunk_buffer = vec![0; size];
+    source.read_exact_at(&mut chunk_buffer, source_offset)?;
+    destination.write_all_at(&chunk_buffer, destination_offset)
+}
+
+fn copy_with_copy_file_range(
+    source: &File,
+    source_offset: u64,
+    destination: &File,
+    destination_offset: u64,
+    size: usize,
+) -> io::Result<()> {
+    let mut copied_bytes = 0;
+
+    while copied_bytes < size {
+        let mut source_position = (source_offset + copied_bytes as u64) as libc::loff_t;
+        let mut destination_position = (destination_offset + copied_bytes as u64) as libc::loff_t;
+
+        let result = unsafe {
+            libc::copy_file_range(
+                source.as_raw_fd(),
+                &mut source_position,
+                destination.as_raw_fd(),
+                &mut destination_position,
+                size - copied_bytes,
+                0,
+            )
+        };
+        copied_bytes += check_copied_bytes(result, copied_bytes)?;
+    }
+    Ok(())
+}
+
+fn copy_with_sendfile(
+    source: &File,
+    source_offset: u64,
+    mut destination: &File,
+    destination_offset: u64,
+    size: usize,
+) -> io::Result<()> {
+    // sendfile writes at the current position of the destination
+    destination.seek(SeekFrom::Start(destination_offset))?;
+    let mut copied_bytes = 0;
+
+    while copied_bytes < size {
+        let mut source_position = (source_offset + copied_bytes as u64) as libc::off_t;
+
+        let result = unsafe {
+            libc::sendfile(
+                destination.as_raw_fd(),
+                source.as_raw_fd(),
+                &mut source_position,
+                size - copied_bytes,
+            )
+        };
+        copied_bytes += check_copied_bytes(result, copied_bytes)?;
+    }
+    Ok(())
+}
+
+/// Turns the result of a copy system call into a number of bytes. Errors only count as
+/// unsupported when nothing was copied yet, so that the fallback restarts from scratch.
+fn check_copied_bytes(result: isize, copied_bytes: usize) -> io::Result<usize> {
+    match result {
+        -1 => {
+            let error = io::Error::last_os_error();
+            match copied_bytes > 0 && is_unsupported(&error) {
+                true => Err(io::Error::other(error)),
+                false => Err(error),
+            }
+        }
+        0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        copied => Ok(copied as usize),
+    }
+}
+
+fn is_unsupported(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Mutex};
+
+    use super::*;
+
+    #[test]
+    fn copy_file_region_test() {
+        let files_dir = tempfile::tempdir().unwrap();
+        let source_path = files_dir.path().join("source");
+        let destination_path = files_dir.path().join("destination");
+        let content: Vec<u8> = (0..100_000u32).map(|value| value as u8).collect();
+        fs::write(&source_path, &content).unwrap();
+        fs::write(&destination_path, vec![0xFF; 8]).unwrap();
+
+        let source = File::open(&source_path).unwrap();
+        let destination = File::options().write(true).open(&destination_path).unwrap();
+        copy_file_region(&source, 10, &destination, 4, 50_000).unwrap();
+
+        let copied = fs::read(&destination_path).unwrap();
+        assert_eq!(copied[..4], [0xFF; 4]);
+        assert_eq!(copied[4..], content[10..50_010]);
+    }
+
+    #[test]
+    fn hooked_destination_test() {
+        let files_dir = tempfile::tempdir().unwrap();
+        let destination_path = files_dir.path().join("destination");
+        let destination_path = destination_path.to_str().unwrap();
+        fs::write(destination_path, [0; 16]).unwrap();
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let write_hook = {
+            let writes = writes.clone();
+            move |path: &str, offset, size| {
+                writes
+                    .lock()
+                    .unwrap()
+                    .push((path.to_string(), offset, size));
+                match offset {
+                    0 => Err(io::Error::other("write failed")),
+                    _ => Ok(()),
+                }
+            }
+        };
+        let destination = DestinationFile::create(destination_path, None)
+            .unwrap()
+            .hooked(destination_path, Arc::new(write_hook));
+
+        let error = destination.write_all_at(&[0xA5; 4], 0).unwrap_err();
+        assert_eq!(error.to_string(), "write failed");
+        destination.write_all_at(&[0xA5; 4], 4).unwrap();
+        destination.erase(8, 8, 4).unwrap();
+
+        assert_eq!(
+            *writes.lock().unwrap(),
+            [
+                (destination_path.to_string(), 0, 4),
+                (destination_path.to_string(), 4, 4),
+                (destination_path.to_string(), 8, 8)
+            ]
+        );
+        let written = fs::read(destination_path).unwrap();
+        assert_eq!(written[..4], [0; 4]);
+        assert_eq!(written[4..8], [0xA5; 4]);
+        assert_eq!(written[8..], [ERASED_BYTE; 8]);
+    }
+
+    #[test]
+    fn chunking_test() {
+        assert_eq!(get_chunk_size(0, 131072, 4096), 131072);
+        assert_eq!(get_chunk_size(4100, 131072, 4096), 131068);
+        assert_eq!(get_misaligned_size(4100, 4096), 4092);
+        assert_eq!(get_misaligned_size(8192, 4096), 0);
+
+        assert!(check_chunking(131072, 4096).is_ok());
+        assert!(check_chunking(0, 1).is_err());
+        assert!(check_chunking(4096, 3).is_err());
+        assert!(check_chunking(6144, 4096).is_err());
+    }
+    #[test]
+    fn read_chunk_within_test() {
+        let mut chunk_buffer = [0; 8];
+
+        let mut reader: &[u8] = &[1; 12];
+        assert_eq!(
+            read_chunk_within(&mut reader, &mut chunk_buffer, 12).unwrap(),
+            8
+        );
+        assert_eq!(
+            read_chunk_within(&mut reader, &mut chunk_buffer, 4).unwrap(),
+            4
+        );
+        assert_eq!(
+            read_chunk_within(&mut reader, &mut chunk_buffer, 0).unwrap(),
+            0
+        );
+
+        let mut reader: &[u8] = &[1; 12];
+        assert_eq!(
+            read_chunk_within(&mut reader, &mut chunk_buffer, 6).unwrap(),
+            6
+        );
+        assert_eq!(
+            read_chunk_within(&mut reader, &mut chunk_buffer, 0)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
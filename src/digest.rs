@@ -0,0 +1,134 @@
+use std::{
+    fs::File,
+    io::{Read, Seek},
+};
+
+use openssl::sha::{sha256, Sha256};
+use rayon::prelude::*;
+
+use crate::chunked_io::DestinationFile;
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Computes the SHA-256 digest of `size` bytes stored at `offset` in `path`.
+///
+/// Fails if the file doesn't exist or is too short to contain the whole region.
+pub(crate) fn sha256_hex_of_file_region(
+    path: &str,
+    offset: u64,
+    size: usize,
+) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    file.seek(std::io::SeekFrom::Start(offset))?;
+
+    const CHUNK_SIZE: usize = 4096;
+    let mut read_buffer = [0; CHUNK_SIZE];
+
+    let mut hasher = Sha256::new();
+    let mut remaining_bytes = size;
+
+    while remaining_bytes > 0 {
+        let bytes_to_read = remaining_bytes.min(CHUNK_SIZE);
+        file.read_exact(&mut read_buffer[..bytes_to_read])?;
+        hasher.update(&read_buffer[..bytes_to_read]);
+        remaining_bytes -= bytes_to_read;
+    }
+
+    Ok(to_hex(&hasher.finish()))
+}
+
+/// Same as [`sha256_hex_of_file_region`] for a region of `destination`, read `chunk_size` bytes
+/// at a time.
+pub(crate) fn sha256_hex_of_destination_region(
+    destination: &DestinationFile,
+    offset: u64,
+    size: usize,
+    chunk_size: usize,
+) -> std::io::Result<String> {
+    let mut read_buffer = vec![0; chunk_size.min(size)];
+    let mut hasher = Sha256::new();
+    let mut read_bytes = 0;
+
+    while read_bytes < size {
+        let bytes_to_read = read_buffer.len().min(size - read_bytes);
+        destination.read_exact_at(
+            &mut read_buffer[..bytes_to_read],
+            offset + read_bytes as u64,
+        )?;
+        hasher.update(&read_buffer[..bytes_to_read]);
+        read_bytes += bytes_to_read;
+    }
+
+    Ok(to_hex(&hasher.finish()))
+}
+
+/// Computes a digest tree of `content`: the SHA-256 of the concatenated SHA-256 digests of its
+/// `leaf_size` long leaves. Leaves are hashed in parallel.
+pub(crate) fn sha256_tree_hex(content: &[u8], leaf_size: usize) -> String {
+    let leaf_digests: Vec<[u8; 32]> = content.par_chunks(leaf_size).map(sha256).collect();
+
+    to_hex(&sha256(&leaf_digests.concat()))
+}
+
+/// Same as [`sha256_tree_hex`] for `size` bytes stored at `offset` of `destination`, reading the
+/// leaves in parallel.
+pub(crate) fn sha256_tree_hex_of_destination_region(
+    destination: &DestinationFile,
+    offset: u64,
+    size: usize,
+    leaf_size: usize,
+) -> std::io::Result<String> {
+    let leaf_digests = (0..size.div_ceil(leaf_size))
+        .into_par_iter()
+        .map(|leaf_index| -> std::io::Result<[u8; 32]> {
+            let leaf_offset = leaf_index * leaf_size;
+            let mut leaf = vec![0; leaf_size.min(size - leaf_offset)];
+
+            destination.read_exact_at(&mut leaf, offset + leaf_offset as u64)?;
+            Ok(sha256(&leaf))
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    Ok(to_hex(&sha256(&leaf_digests.concat())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_hex_of_file_region_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("region");
+        std::fs::write(&path, b"xxabcxx").unwrap();
+
+        let digest = sha256_hex_of_file_region(path.to_str().unwrap(), 2, 3).unwrap();
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        assert!(sha256_hex_of_file_region(path.to_str().unwrap(), 2, 10).is_err());
+    }
+
+    #[test]
+    fn sha256_tree_hex_of_destination_region_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("region");
+        let content: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, [b"xx".as_slice(), &content].concat()).unwrap();
+
+        let destination = DestinationFile::open(path.to_str().unwrap(), None).unwrap();
+
+        let digest = sha256_tree_hex_of_destination_region(&destination, 2, 10_000, 4096);
+        assert_eq!(digest.unwrap(), sha256_tree_hex(&content, 4096));
+
+        assert_ne!(
+            sha256_tree_hex(&content, 4096),
+            sha256_tree_hex(&content, 1024)
+        );
+        assert!(sha256_tree_hex_of_destination_region(&destination, 2, 20_000, 4096).is_err());
+    }
+}
@@ -1,10 +1,65 @@
 mod async_update;
-pub use crate::async_update::update_sequence::async_update;
+pub use crate::async_update::update_sequence::{
+    async_update, update, update_with_control, DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS,
+};
 
 mod multi_threaded_update;
-pub use crate::multi_threaded_update::update_sequence::multi_threaded_update;
+pub use crate::multi_threaded_update::update_sequence::{
+    multi_threaded_update, multi_threaded_update_with_control,
+};
+
+mod audit_log;
+pub use crate::audit_log::{
+    AuditLog, AuditLogEntry, AuditOutcome, AuditRecord, AuditedLogicalBlock,
+};
+
+mod boot_control;
+pub use crate::boot_control::{
+    BootControl, BootControlRecord, SlotState, DEFAULT_MAX_BOOT_ATTEMPTS,
+};
+
+mod chunked_io;
+pub use crate::chunked_io::WriteHook;
+
+mod digest;
+
+mod manifest;
+
+mod mapping_config;
+
+mod memory_mapping;
+
+mod metrics;
+pub use crate::metrics::{MetricsEndpoint, MetricsFormat, UpdateMetrics};
+
+mod nand;
 
 mod reporting;
+pub use crate::reporting::{
+    DocumentError, ErrorCategory, ErrorCause, LogicalBlockError, LogicalBlockReport,
+    LogicalBlockStatus, PhaseDurations, UpdateError, UpdatePhase, UpdateProgress, UpdateReport,
+};
+
+mod retry;
 
 mod sequential_update;
-pub use crate::sequential_update::update_sequence::sequencial_update;
+pub use crate::sequential_update::bank_export::{export_bank, BankExport, ExportedLogicalBlock};
+pub use crate::sequential_update::installed_verification::{
+    verify_installed, InstalledLogicalBlock, InstalledLogicalBlockVerdict, InstalledVerification,
+};
+pub use crate::sequential_update::update_plan::{plan_update, UpdatePlan};
+pub use crate::sequential_update::update_sequence::{
+    sequencial_update, sequencial_update_with_control,
+};
+
+mod throttle;
+pub use crate::throttle::{IoBudget, IoThrottle};
+
+mod update_control;
+pub use crate::update_control::UpdateControl;
+
+#[cfg(test)]
+mod test_utils;
+
+#[cfg(test)]
+mod update_strategy_tests;
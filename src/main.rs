@@ -0,0 +1,152 @@
+use std::{env, process::ExitCode};
+
+use update_logic_clean_code::{sequencial_update, verify_installed, LogicalBlockStatus};
+
+const USAGE: &str = "Usage: update_logic_clean_code [--verify-installed <bank> [--json]] <memory mapping> <software archive>";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Installs the software archive in the bank targeted by the memory mapping.
+    Update {
+        memory_mapping_path: String,
+        software_archive_path: String,
+    },
+    /// Checks the logical blocks installed in `bank` against the software archive, without
+    /// writing to the device.
+    VerifyInstalled {
+        memory_mapping_path: String,
+        software_archive_path: String,
+        bank: String,
+        json: bool,
+    },
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut verified_bank = None;
+    let mut json = false;
+    let mut paths = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--verify-installed" => match args.next() {
+                Some(bank) => verified_bank = Some(bank),
+                None => return Err("--verify-installed needs the bank to verify".to_string()),
+            },
+            "--json" => json = true,
+            option if option.starts_with("--") => return Err(format!("Unknown option {option}")),
+            _ => paths.push(arg),
+        }
+    }
+
+    let [memory_mapping_path, software_archive_path] = <[String; 2]>::try_from(paths)
+        .map_err(|_| "Expected a memory mapping and a software archive".to_string())?;
+
+    match (verified_bank, json) {
+        (Some(bank), json) => Ok(Command::VerifyInstalled {
+            memory_mapping_path,
+            software_archive_path,
+            bank,
+            json,
+        }),
+        (None, true) => Err("--json only applies to --verify-installed".to_string()),
+        (None, false) => Ok(Command::Update {
+            memory_mapping_path,
+            software_archive_path,
+        }),
+    }
+}
+
+fn main() -> ExitCode {
+    let command = match parse_args(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(description) => {
+            eprintln!("{description}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match command {
+        Command::Update {
+            memory_mapping_path,
+            software_archive_path,
+        } => match sequencial_update(&memory_mapping_path, &software_archive_path) {
+            Ok(update_report) => {
+                for status in [
+                    LogicalBlockStatus::Written,
+                    LogicalBlockStatus::Skipped,
+                    LogicalBlockStatus::Cloned,
+                ] {
+                    let logical_block_ids = update_report.get_logical_blocks_with_status(status);
+                    println!("{status:?}: {}", logical_block_ids.join(", "));
+                }
+                ExitCode::SUCCESS
+            }
+            Err(error) => {
+                eprintln!("{}", error.to_json());
+                ExitCode::FAILURE
+            }
+        },
+        Command::VerifyInstalled {
+            memory_mapping_path,
+            software_archive_path,
+            bank,
+            json,
+        } => match verify_installed(&memory_mapping_path, &software_archive_path, &bank) {
+            Ok(verification) => {
+                match json {
+                    true => println!("{}", verification.to_json()),
+                    false => print!("{verification}"),
+                }
+                match verification.is_intact() {
+                    true => ExitCode::SUCCESS,
+                    false => ExitCode::FAILURE,
+                }
+            }
+            Err(error) => {
+                eprintln!("{}", error.to_json());
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_args_test() {
+        assert_eq!(
+            parse(&["lb_cfg.json", "update.zip"]),
+            Ok(Command::Update {
+                memory_mapping_path: "lb_cfg.json".to_string(),
+                software_archive_path: "update.zip".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(&[
+                "--verify-installed",
+                "bank_b",
+                "lb_cfg.json",
+                "update.zip",
+                "--json"
+            ]),
+            Ok(Command::VerifyInstalled {
+                memory_mapping_path: "lb_cfg.json".to_string(),
+                software_archive_path: "update.zip".to_string(),
+                bank: "bank_b".to_string(),
+                json: true,
+            })
+        );
+
+        assert!(parse(&["lb_cfg.json", "update.zip", "--verify-installed"]).is_err());
+        assert!(parse(&["--json", "lb_cfg.json", "update.zip"]).is_err());
+        assert!(parse(&["--force", "lb_cfg.json", "update.zip"]).is_err());
+        assert!(parse(&["lb_cfg.json"]).is_err());
+    }
+}
@@ -0,0 +1,857 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    path::Path,
+};
+
+use base64::{engine::general_purpose, Engine};
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+
+use crate::reporting::{DocumentError, TextPosition, UpdateError, UpdatePhase};
+
+pub(crate) const INDEX_PATH: &str = "index.xml";
+const INDEX_XML_NAMESPACE: &str = "file_list";
+const MANIFEST_XML_NAMESPACE: &str = "logical_blocks";
+const MANIFEST_SHORT_NAME: &str = "update_manifest";
+
+/// Files listed in the `index.xml` of a software archive.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ArchiveIndex {
+    files: Vec<IndexedFile>,
+}
+
+#[derive(Debug, PartialEq)]
+struct IndexedFile {
+    short_name: String,
+    path: String,
+}
+
+impl ArchiveIndex {
+    pub(crate) fn parse(content: &[u8]) -> Result<ArchiveIndex, UpdateError> {
+        let parser = XmlParser::new(INDEX_PATH, UpdatePhase::Index, INDEX_XML_NAMESPACE);
+        let document = parser.parse(content)?;
+        let root = parser.get_root(&document, "file_list")?;
+
+        let mut files = Vec::new();
+        let mut short_names = HashSet::new();
+
+        for file in parser.get_children(&document, root, "file")? {
+            let short_name = match file.attribute("short_name") {
+                Some(short_name) => short_name.to_string(),
+                None => {
+                    return Err(parser.error(&document, file, "File has no short_name attribute"))
+                }
+            };
+            if !short_names.insert(short_name.clone()) {
+                return Err(parser.error(
+                    &document,
+                    file,
+                    &format!("Duplicate file short name {short_name}"),
+                ));
+            }
+
+            let path = parser.get_required_text(&document, file, "path")?;
+            files.push(IndexedFile { short_name, path });
+        }
+
+        Ok(ArchiveIndex { files })
+    }
+
+    pub(crate) fn get_path(&self, short_name: &str) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|file| file.short_name == short_name)
+            .map(|file| file.path.as_ref())
+    }
+
+    pub(crate) fn get_manifest_path(&self) -> Result<&str, UpdateError> {
+        self.get_path(MANIFEST_SHORT_NAME).ok_or_else(|| {
+            UpdateError::InvalidArchive(DocumentError::new(
+                INDEX_PATH,
+                UpdatePhase::Index,
+                format!("Index doesn't list the {MANIFEST_SHORT_NAME}"),
+            ))
+        })
+    }
+}
+
+/// Logical blocks described by the update manifest, with their location in the archive.
+///
+/// Logical blocks are sorted in install order: by phase, then so that each one comes after the
+/// logical blocks it depends on.
+#[derive(Debug, PartialEq)]
+pub(crate) struct UpdateManifest {
+    pub logical_blocks: Vec<ManifestLogicalBlock>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct ManifestLogicalBlock {
+    pub id: String,
+    pub short_name: String,
+    pub signature: String,
+    /// SHA-256 of the logical block content, in lowercase hex.
+    pub digest: Option<String>,
+    pub path_in_archive: String,
+    /// Install phase, a phase only starts once every logical block of the previous ones is
+    /// written and verified.
+    pub phase: u32,
+    /// Ids of the logical blocks that must be installed before this one.
+    pub depends_on: Vec<String>,
+    /// Rank of the group of logical blocks this one is installed with. Logical blocks of the
+    /// same stage don't depend on each other and can be installed in parallel.
+    pub install_stage: usize,
+}
+
+/// Encoding of the update manifest, detected from the extension of its path in the archive.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum ManifestFormat {
+    Xml,
+    Json,
+    Cbor,
+}
+
+impl ManifestFormat {
+    pub(crate) fn from_path(manifest_path: &str) -> ManifestFormat {
+        match Path::new(manifest_path).extension().and_then(OsStr::to_str) {
+            Some("json") => ManifestFormat::Json,
+            Some("cbor") => ManifestFormat::Cbor,
+            _ => ManifestFormat::Xml,
+        }
+    }
+}
+
+/// Update manifest as encoded in JSON or CBOR.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ManifestDocument {
+    pub logical_blocks: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ManifestEntry {
+    pub id: String,
+    pub short_name: String,
+    pub signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "is_first_phase")]
+    pub phase: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+}
+
+fn is_first_phase(phase: &u32) -> bool {
+    *phase == 0
+}
+
+impl UpdateManifest {
+    /// Parses the manifest found at `manifest_path`, in the format given by its extension, and
+    /// resolves the location of its logical blocks from `index`.
+    pub(crate) fn parse(
+        manifest_path: &str,
+        content: &[u8],
+        index: &ArchiveIndex,
+    ) -> Result<UpdateManifest, UpdateError> {
+        let entries = match ManifestFormat::from_path(manifest_path) {
+            ManifestFormat::Xml => Self::parse_xml(manifest_path, content)?,
+            ManifestFormat::Json => {
+                let document: ManifestDocument =
+                    serde_json::from_slice(content).map_err(|error| {
+                        let position = TextPosition {
+                            line: error.line() as u32,
+                            column: error.column() as u32,
+                        };
+                        UpdateError::InvalidArchive(
+                            manifest_error(
+                                manifest_path,
+                                Some(position),
+                                "Malformed JSON document",
+                            )
+                            .caused_by(&error),
+                        )
+                    })?;
+                Self::without_positions(document)
+            }
+            ManifestFormat::Cbor => {
+                let document: ManifestDocument =
+                    ciborium::from_reader(content).map_err(|error| {
+                        UpdateError::InvalidArchive(
+                            manifest_error(manifest_path, None, "Malformed CBOR document")
+                                .caused_by(&error),
+                        )
+                    })?;
+                Self::without_positions(document)
+            }
+        };
+
+        Self::resolve(manifest_path, entries, index)
+    }
+
+    fn parse_xml(
+        manifest_path: &str,
+        content: &[u8],
+    ) -> Result<Vec<(ManifestEntry, Option<TextPosition>)>, UpdateError> {
+        let parser = XmlParser::new(manifest_path, UpdatePhase::Manifest, MANIFEST_XML_NAMESPACE);
+        let document = parser.parse(content)?;
+        let root = parser.get_root(&document, "logical_blocks")?;
+
+        parser
+            .get_children(&document, root, "logical_block")?
+            .into_iter()
+            .map(|logical_block| {
+                let entry = ManifestEntry {
+                    id: parser.get_required_text(&document, logical_block, "id")?,
+                    short_name: parser.get_required_text(&document, logical_block, "short_name")?,
+                    signature: parser.get_required_text(&document, logical_block, "signature")?,
+                    digest: parser.get_optional_text(&document, logical_block, "digest")?,
+                    phase: match parser.get_optional_text(&document, logical_block, "phase")? {
+                        Some(phase) => phase.parse().map_err(|_| {
+                            parser.error(&document, logical_block, "Phase must be a number")
+                        })?,
+                        None => 0,
+                    },
+                    depends_on: parser.get_texts(&document, logical_block, "depends_on")?,
+                };
+                Ok((entry, Some(get_position(&document, logical_block))))
+            })
+            .collect()
+    }
+
+    fn without_positions(document: ManifestDocument) -> Vec<(ManifestEntry, Option<TextPosition>)> {
+        document
+            .logical_blocks
+            .into_iter()
+            .map(|entry| (entry, None))
+            .collect()
+    }
+
+    /// Checks the manifest entries, whatever their encoding, and locates them in the archive.
+    fn resolve(
+        manifest_path: &str,
+        entries: Vec<(ManifestEntry, Option<TextPosition>)>,
+        index: &ArchiveIndex,
+    ) -> Result<UpdateManifest, UpdateError> {
+        let mut logical_blocks: Vec<ManifestLogicalBlock> = Vec::new();
+        let mut positions = Vec::new();
+
+        for (entry, position) in entries {
+            let error = |description: String| {
+                UpdateError::InvalidArchive(manifest_error(manifest_path, position, &description))
+            };
+            let ManifestEntry {
+                id,
+                short_name,
+                signature,
+                digest,
+                phase,
+                depends_on,
+            } = entry;
+
+            if id.is_empty() || short_name.is_empty() || signature.is_empty() {
+                return Err(error(format!(
+                    "Logical block {id} has an empty id, short name or signature"
+                )));
+            }
+            if general_purpose::STANDARD.decode(&signature).is_err() {
+                return Err(error(format!(
+                    "Signature of logical block {id} isn't valid base64"
+                )));
+            }
+            if logical_blocks.iter().any(|other| other.id == id) {
+                return Err(error(format!("Duplicate logical block id {id}")));
+            }
+            if logical_blocks
+                .iter()
+                .any(|other| other.short_name == short_name)
+            {
+                return Err(error(format!(
+                    "Duplicate logical block short name {short_name}"
+                )));
+            }
+            if let Some(digest) = &digest {
+                if !is_sha256_hex(digest) {
+                    return Err(error(format!(
+                        "Digest of logical block {id} isn't a SHA-256 hex digest"
+                    )));
+                }
+            }
+
+            let path_in_archive = match index.get_path(&short_name) {
+                Some(path) => path.to_string(),
+                None => {
+                    return Err(error(format!(
+                        "Logical block {short_name} isn't listed in the index"
+                    )))
+                }
+            };
+
+            logical_blocks.push(ManifestLogicalBlock {
+                id,
+                short_name,
+                signature,
+                digest,
+                path_in_archive,
+                phase,
+                depends_on,
+                install_stage: 0,
+            });
+            positions.push(position);
+        }
+
+        Self::sort_in_install_order(manifest_path, logical_blocks, &positions)
+    }
+
+    /// Sorts logical blocks by phase, then by dependency depth within a phase, keeping the
+    /// manifest order otherwise, and numbers the resulting install stages.
+    fn sort_in_install_order(
+        manifest_path: &str,
+        logical_blocks: Vec<ManifestLogicalBlock>,
+        positions: &[Option<TextPosition>],
+    ) -> Result<UpdateManifest, UpdateError> {
+        let indices: HashMap<&str, usize> = logical_blocks
+            .iter()
+            .enumerate()
+            .map(|(index, logical_block)| (logical_block.id.as_str(), index))
+            .collect();
+
+        let mut depths = vec![None; logical_blocks.len()];
+        for index in 0..logical_blocks.len() {
+            get_dependency_depth(index, &logical_blocks, &indices, &mut depths, &mut vec![])
+                .map_err(|(index, description)| {
+                    UpdateError::InvalidArchive(manifest_error(
+                        manifest_path,
+                        positions[index],
+                        &description,
+                    ))
+                })?;
+        }
+
+        let mut staged_logical_blocks: Vec<_> = depths
+            .into_iter()
+            .map(Option::unwrap)
+            .zip(logical_blocks)
+            .map(|(depth, logical_block)| ((logical_block.phase, depth), logical_block))
+            .collect();
+        staged_logical_blocks.sort_by_key(|(stage, _)| *stage);
+
+        let mut stages: Vec<_> = staged_logical_blocks
+            .iter()
+            .map(|(stage, _)| *stage)
+            .collect();
+        stages.dedup();
+
+        let logical_blocks = staged_logical_blocks
+            .into_iter()
+            .map(|(stage, logical_block)| ManifestLogicalBlock {
+                install_stage: stages.binary_search(&stage).unwrap(),
+                ..logical_block
+            })
+            .collect();
+
+        Ok(UpdateManifest { logical_blocks })
+    }
+}
+
+/// Returns how many logical blocks of the same phase the one at `index` transitively depends
+/// on in a row, or the index of the faulty logical block and why its dependencies are invalid.
+fn get_dependency_depth(
+    index: usize,
+    logical_blocks: &[ManifestLogicalBlock],
+    indices: &HashMap<&str, usize>,
+    depths: &mut Vec<Option<usize>>,
+    dependency_chain: &mut Vec<usize>,
+) -> Result<usize, (usize, String)> {
+    if let Some(depth) = depths[index] {
+        return Ok(depth);
+    }
+    let logical_block = &logical_blocks[index];
+    if let Some(cycle_start) = dependency_chain
+        .iter()
+        .position(|chained| *chained == index)
+    {
+        let description = match &dependency_chain[cycle_start..] {
+            [_] => format!("Logical block {} depends on itself", logical_block.id),
+            cycle => {
+                let cycle: Vec<_> = cycle
+                    .iter()
+                    .chain([&index])
+                    .map(|chained| logical_blocks[*chained].id.as_str())
+                    .collect();
+                format!(
+                    "Logical blocks form a dependency cycle: {}",
+                    cycle.join(" -> ")
+                )
+            }
+        };
+        return Err((index, description));
+    }
+
+    dependency_chain.push(index);
+    let mut depth = 0;
+    for dependency_id in &logical_block.depends_on {
+        let dependency_index = match indices.get(dependency_id.as_str()) {
+            Some(dependency_index) => *dependency_index,
+            None => {
+                return Err((
+                    index,
+                    format!(
+                        "Logical block {} depends on {dependency_id}, which isn't in the manifest",
+                        logical_block.id
+                    ),
+                ))
+            }
+        };
+        let dependency = &logical_blocks[dependency_index];
+        if dependency.phase > logical_block.phase {
+            return Err((
+                index,
+                format!(
+                    "Logical block {} depends on {dependency_id}, which is installed in a later phase",
+                    logical_block.id
+                ),
+            ));
+        }
+
+        let dependency_depth = get_dependency_depth(
+            dependency_index,
+            logical_blocks,
+            indices,
+            depths,
+            dependency_chain,
+        )?;
+        if dependency.phase == logical_block.phase {
+            depth = depth.max(dependency_depth + 1);
+        }
+    }
+    dependency_chain.pop();
+
+    depths[index] = Some(depth);
+    Ok(depth)
+}
+
+fn manifest_error(
+    manifest_path: &str,
+    position: Option<TextPosition>,
+    description: &str,
+) -> DocumentError {
+    DocumentError {
+        position,
+        ..DocumentError::new(manifest_path, UpdatePhase::Manifest, description)
+    }
+}
+
+fn get_position(document: &Document<'_>, node: Node<'_, '_>) -> TextPosition {
+    let position = document.text_pos_at(node.range().start);
+
+    TextPosition {
+        line: position.row,
+        column: position.col,
+    }
+}
+
+fn is_sha256_hex(digest: &str) -> bool {
+    digest.len() == 64
+        && digest
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Parses one XML document of the archive, reporting errors with their position in it.
+struct XmlParser<'a> {
+    path: &'a str,
+    phase: UpdatePhase,
+    namespace: &'static str,
+}
+
+impl<'a> XmlParser<'a> {
+    fn new(path: &'a str, phase: UpdatePhase, namespace: &'static str) -> XmlParser<'a> {
+        XmlParser {
+            path,
+            phase,
+            namespace,
+        }
+    }
+
+    fn parse<'input>(&self, content: &'input [u8]) -> Result<Document<'input>, UpdateError> {
+        let content = std::str::from_utf8(content).map_err(|error| {
+            UpdateError::InvalidArchive(
+                DocumentError::new(self.path, self.phase, "XML document isn't valid UTF-8")
+                    .caused_by(&error),
+            )
+        })?;
+
+        Document::parse(content).map_err(|error| {
+            let position = error.pos();
+            UpdateError::InvalidArchive(
+                DocumentError::new(self.path, self.phase, "Malformed XML document")
+                    .at_position(position.row, position.col)
+                    .caused_by(&error),
+            )
+        })
+    }
+
+    fn get_root<'d, 'input>(
+        &self,
+        document: &'d Document<'input>,
+        name: &str,
+    ) -> Result<Node<'d, 'input>, UpdateError> {
+        let root = document.root_element();
+
+        match self.is_element(root, name) {
+            true => Ok(root),
+            false => Err(self.error(
+                document,
+                root,
+                &format!(
+                    "Expected a {name} root element in the {} namespace",
+                    self.namespace
+                ),
+            )),
+        }
+    }
+
+    /// Returns the child elements of `parent`, which must all be `name` elements.
+    fn get_children<'d, 'input>(
+        &self,
+        document: &Document<'input>,
+        parent: Node<'d, 'input>,
+        name: &str,
+    ) -> Result<Vec<Node<'d, 'input>>, UpdateError> {
+        parent
+            .children()
+            .filter(|child| child.is_element())
+            .map(|child| match self.is_element(child, name) {
+                true => Ok(child),
+                false => Err(self.error(
+                    document,
+                    child,
+                    &format!(
+                        "Unexpected {} element, expected {name}",
+                        child.tag_name().name()
+                    ),
+                )),
+            })
+            .collect()
+    }
+
+    fn get_required_text(
+        &self,
+        document: &Document<'_>,
+        parent: Node<'_, '_>,
+        name: &str,
+    ) -> Result<String, UpdateError> {
+        match self.get_optional_text(document, parent, name)? {
+            Some(text) => Ok(text),
+            None => Err(self.error(
+                document,
+                parent,
+                &format!("Missing {name} element in {}", parent.tag_name().name()),
+            )),
+        }
+    }
+
+    /// Returns the text of every `name` child element of `parent`, none being empty.
+    fn get_texts(
+        &self,
+        document: &Document<'_>,
+        parent: Node<'_, '_>,
+        name: &str,
+    ) -> Result<Vec<String>, UpdateError> {
+        parent
+            .children()
+            .filter(|child| self.is_element(*child, name))
+            .map(|element| match element.text().map(str::trim) {
+                Some(text) if !text.is_empty() => Ok(text.to_string()),
+                _ => Err(self.error(document, element, &format!("Empty {name} element"))),
+            })
+            .collect()
+    }
+
+    fn get_optional_text(
+        &self,
+        document: &Document<'_>,
+        parent: Node<'_, '_>,
+        name: &str,
+    ) -> Result<Option<String>, UpdateError> {
+        let mut elements = parent
+            .children()
+            .filter(|child| self.is_element(*child, name));
+
+        let element = match elements.next() {
+            Some(element) => element,
+            None => return Ok(None),
+        };
+        if let Some(duplicate) = elements.next() {
+            return Err(self.error(document, duplicate, &format!("Duplicate {name} element")));
+        }
+
+        match element.text().map(str::trim) {
+            Some(text) if !text.is_empty() => Ok(Some(text.to_string())),
+            _ => Err(self.error(document, element, &format!("Empty {name} element"))),
+        }
+    }
+
+    fn is_element(&self, node: Node<'_, '_>, name: &str) -> bool {
+        node.is_element()
+            && node.tag_name().name() == name
+            && node.tag_name().namespace() == Some(self.namespace)
+    }
+
+    fn error(&self, document: &Document<'_>, node: Node<'_, '_>, description: &str) -> UpdateError {
+        UpdateError::InvalidArchive(DocumentError {
+            position: Some(get_position(document, node)),
+            ..DocumentError::new(self.path, self.phase, description)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX: &str = r#"<file_list xmlns="file_list">
+    <file short_name="update_manifest">
+        <path>logical_blocks/update_manifest.xml</path>
+    </file>
+    <file short_name="dummy_FD01">
+        <path>logical_blocks/FD01.bin</path>
+    </file>
+</file_list>"#;
+
+    fn parse_manifest(logical_blocks: &str) -> Result<UpdateManifest, UpdateError> {
+        let manifest = format!(
+            "<logical_blocks xmlns=\"logical_blocks\">\n{logical_blocks}\n</logical_blocks>"
+        );
+        UpdateManifest::parse(
+            "update_manifest.xml",
+            manifest.as_bytes(),
+            &ArchiveIndex::parse(INDEX.as_bytes()).unwrap(),
+        )
+    }
+
+    fn get_position(error: UpdateError) -> (u32, u32) {
+        match error {
+            UpdateError::InvalidArchive(DocumentError {
+                position: Some(position),
+                ..
+            }) => (position.line, position.column),
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn parse_manifest_test() {
+        let manifest = parse_manifest(
+            "<logical_block><id>FD01</id><short_name>dummy_FD01</short_name><signature>c2ln</signature></logical_block>",
+        )
+        .unwrap();
+
+        assert_eq!(
+            manifest.logical_blocks,
+            vec![ManifestLogicalBlock {
+                id: "FD01".to_string(),
+                short_name: "dummy_FD01".to_string(),
+                signature: "c2ln".to_string(),
+                digest: None,
+                path_in_archive: "logical_blocks/FD01.bin".to_string(),
+                phase: 0,
+                depends_on: vec![],
+                install_stage: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_json_and_cbor_manifest_test() {
+        let index = ArchiveIndex::parse(INDEX.as_bytes()).unwrap();
+        let xml_manifest = parse_manifest(
+            "<logical_block><id>FD01</id><short_name>dummy_FD01</short_name><signature>c2ln</signature></logical_block>",
+        )
+        .unwrap();
+
+        let json = br#"{"logical_blocks": [{"id": "FD01", "short_name": "dummy_FD01", "signature": "c2ln"}]}"#;
+        let json_manifest = UpdateManifest::parse("update_manifest.json", json, &index).unwrap();
+
+        let document: ManifestDocument = serde_json::from_slice(json).unwrap();
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&document, &mut cbor).unwrap();
+        let cbor_manifest = UpdateManifest::parse("update_manifest.cbor", &cbor, &index).unwrap();
+
+        assert_eq!(xml_manifest, json_manifest);
+        assert_eq!(xml_manifest, cbor_manifest);
+    }
+
+    #[test]
+    fn malformed_json_manifest_test() {
+        let index = ArchiveIndex::parse(INDEX.as_bytes()).unwrap();
+
+        let malformed = UpdateManifest::parse(
+            "update_manifest.json",
+            b"{\n  \"logical_blocks\": [\n    {\"id\": \"FD01\", \"short_name\": 1}\n  ]\n}",
+            &index,
+        );
+        assert_eq!(get_position(malformed.unwrap_err()), (3, 34));
+
+        let truncated = UpdateManifest::parse("update_manifest.cbor", &[0xa1, 0x6e], &index);
+        assert_eq!(truncated.unwrap_err().phase(), UpdatePhase::Manifest);
+    }
+
+    #[test]
+    fn malformed_manifest_test() {
+        let error = parse_manifest("<logical_block>\n  <id>FD01</i>").unwrap_err();
+
+        assert_eq!(error.phase(), UpdatePhase::Manifest);
+        assert_eq!(get_position(error), (3, 11));
+    }
+
+    #[test]
+    fn invalid_manifest_test() {
+        let missing_signature = parse_manifest(
+            "<logical_block><id>FD01</id><short_name>dummy_FD01</short_name></logical_block>",
+        );
+        assert_eq!(get_position(missing_signature.unwrap_err()), (2, 1));
+
+        let duplicate_id = parse_manifest(
+            "<logical_block><id>FD01</id><short_name>dummy_FD01</short_name><signature>c2ln</signature></logical_block>\n<logical_block><id>FD01</id><short_name>dummy_FD02</short_name><signature>c2ln</signature></logical_block>",
+        );
+        assert!(duplicate_id
+            .unwrap_err()
+            .to_string()
+            .contains("at line 3, column 1: Duplicate logical block id FD01"));
+
+        let unknown_short_name = parse_manifest(
+            "<logical_block><id>FD02</id><short_name>dummy_FD02</short_name><signature>c2ln</signature></logical_block>",
+        );
+        assert!(unknown_short_name
+            .unwrap_err()
+            .to_string()
+            .contains("dummy_FD02 isn't listed in the index"));
+
+        let invalid_signature = parse_manifest(
+            "<logical_block><id>FD01</id><short_name>dummy_FD01</short_name><signature>!!!</signature></logical_block>",
+        )
+        .unwrap_err();
+        assert!(invalid_signature
+            .to_string()
+            .contains("Signature of logical block FD01 isn't valid base64"));
+        assert_eq!(get_position(invalid_signature), (2, 1));
+
+        let wrong_namespace = UpdateManifest::parse(
+            "update_manifest.xml",
+            b"<logical_blocks xmlns=\"file_list\"/>",
+            &ArchiveIndex::parse(INDEX.as_bytes()).unwrap(),
+        );
+        assert_eq!(get_position(wrong_namespace.unwrap_err()), (1, 1));
+    }
+
+    fn parse_json_manifest_with_dependencies(
+        logical_blocks: &[(&str, u32, &[&str])],
+    ) -> Result<UpdateManifest, UpdateError> {
+        let mut index = String::from("<file_list xmlns=\"file_list\">");
+        let mut entries = Vec::new();
+        for (id, phase, depends_on) in logical_blocks {
+            index.push_str(&format!(
+                "<file short_name=\"dummy_{id}\"><path>{id}.bin</path></file>"
+            ));
+            entries.push(ManifestEntry {
+                id: id.to_string(),
+                short_name: format!("dummy_{id}"),
+                signature: "c2ln".to_string(),
+                digest: None,
+                phase: *phase,
+                depends_on: depends_on.iter().map(|id| id.to_string()).collect(),
+            });
+        }
+        index.push_str("</file_list>");
+
+        let manifest = serde_json::to_vec(&ManifestDocument {
+            logical_blocks: entries,
+        })
+        .unwrap();
+        UpdateManifest::parse(
+            "update_manifest.json",
+            &manifest,
+            &ArchiveIndex::parse(index.as_bytes()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn install_order_test() {
+        let manifest = parse_json_manifest_with_dependencies(&[
+            ("BOOT", 1, &["FD02"]),
+            ("FD03", 0, &["FD02", "FD01"]),
+            ("FD02", 0, &["FD01"]),
+            ("FD01", 0, &[]),
+            ("FD04", 0, &[]),
+        ])
+        .unwrap();
+
+        let install_order: Vec<_> = manifest
+            .logical_blocks
+            .iter()
+            .map(|logical_block| (logical_block.id.as_str(), logical_block.install_stage))
+            .collect();
+        assert_eq!(
+            install_order,
+            vec![
+                ("FD01", 0),
+                ("FD04", 0),
+                ("FD02", 1),
+                ("FD03", 2),
+                ("BOOT", 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_dependencies_test() {
+        let cycle = parse_json_manifest_with_dependencies(&[
+            ("FD01", 0, &["FD02"]),
+            ("FD02", 0, &["FD01"]),
+        ]);
+        assert!(cycle
+            .unwrap_err()
+            .to_string()
+            .contains("Logical blocks form a dependency cycle: FD01 -> FD02 -> FD01"));
+
+        let self_dependency = parse_json_manifest_with_dependencies(&[("FD01", 0, &["FD01"])]);
+        assert!(self_dependency
+            .unwrap_err()
+            .to_string()
+            .contains("Logical block FD01 depends on itself"));
+
+        let unknown = parse_json_manifest_with_dependencies(&[("FD01", 0, &["FD02"])]);
+        assert!(unknown
+            .unwrap_err()
+            .to_string()
+            .contains("depends on FD02, which isn't in the manifest"));
+
+        let later_phase =
+            parse_json_manifest_with_dependencies(&[("FD01", 0, &["BOOT"]), ("BOOT", 1, &[])]);
+        assert!(later_phase
+            .unwrap_err()
+            .to_string()
+            .contains("depends on BOOT, which is installed in a later phase"));
+
+        let xml_phase = parse_manifest(
+            "<logical_block><id>FD01</id><short_name>dummy_FD01</short_name><signature>c2ln</signature><phase>last</phase></logical_block>",
+        );
+        assert_eq!(get_position(xml_phase.unwrap_err()), (2, 1));
+    }
+
+    #[test]
+    fn invalid_index_test() {
+        let duplicate_short_name = ArchiveIndex::parse(
+            br#"<file_list xmlns="file_list">
+    <file short_name="dummy_FD01"><path>a.bin</path></file>
+    <file short_name="dummy_FD01"><path>b.bin</path></file>
+</file_list>"#,
+        );
+        assert_eq!(get_position(duplicate_short_name.unwrap_err()), (3, 5));
+
+        let index = ArchiveIndex::parse(b"<file_list xmlns=\"file_list\"/>").unwrap();
+        assert_eq!(index.get_manifest_path().unwrap_err().code(), 100);
+    }
+}
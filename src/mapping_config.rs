@@ -0,0 +1,354 @@
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::reporting::{DocumentError, UpdateError, UpdatePhase};
+
+const INCLUDE_KEY: &str = "include";
+const VARIABLES_KEY: &str = "variables";
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Loads a memory mapping written in JSON, TOML or YAML, as given by its extension.
+///
+/// A mapping can `include` other mapping files, given relative to itself, and overlays them in
+/// order: tables are merged key by key, and lists of tables having an `id` are merged by id.
+/// Any string can then reference the `variables` of the merged mapping as `${NAME}`, or
+/// `${NAME:-default}` for a value used when the variable isn't defined. A default that is a
+/// number or a boolean is substituted as such, so that `${OFFSET:-4096}` gives an offset.
+pub(crate) fn load_mapping_config<T: DeserializeOwned>(
+    mapping_path: &str,
+) -> Result<T, UpdateError> {
+    let mut mapping = load_with_includes(Path::new(mapping_path), &mut vec![])?;
+
+    let variables = match mapping.as_object_mut() {
+        Some(mapping) => match mapping.remove(VARIABLES_KEY) {
+            Some(Value::Object(variables)) => variables,
+            Some(_) => return Err(mapping_error(mapping_path, "Variables must be a table")),
+            None => Map::new(),
+        },
+        None => return Err(mapping_error(mapping_path, "Mapping must be a table")),
+    };
+    let mapping = substitute_variables(mapping, &variables)
+        .map_err(|description| mapping_error(mapping_path, &description))?;
+
+    serde_json::from_value(mapping).map_err(|error| {
+        UpdateError::InvalidMemoryMapping(
+            DocumentError::new(mapping_path, UpdatePhase::Mapping, "Invalid mapping")
+                .caused_by(&error),
+        )
+    })
+}
+
+fn load_with_includes(
+    mapping_path: &Path,
+    include_chain: &mut Vec<PathBuf>,
+) -> Result<Value, UpdateError> {
+    let path = mapping_path.display().to_string();
+
+    // Compared once canonical, so that a file can't include itself through another spelling
+    let canonical_path =
+        fs::canonicalize(mapping_path).map_err(|error| open_error(&path, &error))?;
+    if include_chain.contains(&canonical_path) {
+        return Err(mapping_error(&path, "Mapping includes itself"));
+    }
+    if include_chain.len() == MAX_INCLUDE_DEPTH {
+        return Err(mapping_error(
+            &path,
+            "Mapping includes are nested too deeply",
+        ));
+    }
+
+    let mut mapping = parse_mapping_file(mapping_path)?;
+
+    let includes = match mapping.as_object_mut().and_then(|m| m.remove(INCLUDE_KEY)) {
+        None => vec![],
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(mapping_error(&path, "Includes must be paths")),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err(mapping_error(&path, "Includes must be paths")),
+    };
+
+    include_chain.push(canonical_path);
+
+    let mut merged_mapping = Value::Object(Map::new());
+    for include in includes {
+        let include_path = mapping_path.parent().unwrap_or(Path::new("")).join(include);
+        merge(
+            &mut merged_mapping,
+            load_with_includes(&include_path, include_chain)?,
+        );
+    }
+    merge(&mut merged_mapping, mapping);
+
+    include_chain.pop();
+    Ok(merged_mapping)
+}
+
+fn parse_mapping_file(mapping_path: &Path) -> Result<Value, UpdateError> {
+    let path = mapping_path.display().to_string();
+    let parse_error = |error: &(dyn Error + 'static)| {
+        UpdateError::InvalidMemoryMapping(
+            DocumentError::new(&path, UpdatePhase::Mapping, "Unable to parse mapping")
+                .caused_by(error),
+        )
+    };
+
+    let content = fs::read_to_string(mapping_path).map_err(|error| open_error(&path, &error))?;
+
+    match mapping_path.extension().and_then(OsStr::to_str) {
+        Some("toml") => toml::from_str(&content).map_err(|error| parse_error(&error)),
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|error| parse_error(&error)),
+        _ => serde_json::from_str(&content).map_err(|error| parse_error(&error)),
+    }
+}
+
+/// Overlays `overlay` on top of `base`.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(overlay)) if overlay.iter().all(has_id) => {
+            for value in overlay {
+                match base
+                    .iter_mut()
+                    .find(|base_value| base_value.get("id") == value.get("id"))
+                {
+                    Some(base_value) => merge(base_value, value),
+                    None => base.push(value),
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn has_id(value: &Value) -> bool {
+    value.get("id").is_some()
+}
+
+fn substitute_variables(value: Value, variables: &Map<String, Value>) -> Result<Value, String> {
+    match value {
+        Value::String(text) => substitute_in_string(&text, variables),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| substitute_variables(value, variables))
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        Value::Object(values) => values
+            .into_iter()
+            .map(|(key, value)| Ok((key, substitute_variables(value, variables)?)))
+            .collect::<Result<_, _>>()
+            .map(Value::Object),
+        other => Ok(other),
+    }
+}
+
+/// Replaces the variable references of `text`. A string made of a single reference takes the
+/// value of the variable as is, so that numbers such as offsets can come from variables.
+fn substitute_in_string(text: &str, variables: &Map<String, Value>) -> Result<Value, String> {
+    let mut substituted = String::new();
+    let mut remaining = text;
+
+    while let Some(start) = remaining.find("${") {
+        let end = match remaining[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format!("Unterminated variable reference in \"{text}\"")),
+        };
+        let (name, default) = match remaining[start + 2..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&remaining[start + 2..end], None),
+        };
+
+        let value = match (variables.get(name), default) {
+            (Some(value), _) => value.clone(),
+            (None, Some(default)) => parse_default(default),
+            (None, None) => return Err(format!("Undefined variable {name}")),
+        };
+
+        if start == 0 && end + 1 == text.len() {
+            return Ok(value);
+        }
+
+        substituted.push_str(&remaining[..start]);
+        match value {
+            Value::String(value) => substituted.push_str(&value),
+            other => substituted.push_str(&other.to_string()),
+        }
+        remaining = &remaining[end + 1..];
+    }
+    substituted.push_str(remaining);
+
+    Ok(Value::String(substituted))
+}
+
+/// Reads a default value as a number or a boolean when it is one, as a string otherwise.
+fn parse_default(default: &str) -> Value {
+    match serde_json::from_str(default) {
+        Ok(value @ (Value::Number(_) | Value::Bool(_))) => value,
+        _ => Value::String(default.to_string()),
+    }
+}
+
+fn open_error(mapping_path: &str, error: &(dyn Error + 'static)) -> UpdateError {
+    UpdateError::InvalidMemoryMapping(
+        DocumentError::new(mapping_path, UpdatePhase::Mapping, "Unable to open mapping")
+            .caused_by(error),
+    )
+}
+
+fn mapping_error(mapping_path: &str, description: &str) -> UpdateError {
+    UpdateError::InvalidMemoryMapping(DocumentError::new(
+        mapping_path,
+        UpdatePhase::Mapping,
+        description,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde::Deserialize, PartialEq)]
+    struct Destination {
+        path: String,
+        offset: u64,
+    }
+
+    #[derive(Debug, serde::Deserialize, PartialEq)]
+    struct Mapping {
+        logical_blocks: Vec<Map<String, Value>>,
+    }
+
+    #[test]
+    fn toml_overlay_on_yaml_base_test() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("base.yaml"),
+            r#"
+variables:
+  FD01_OFFSET: 0
+logical_blocks:
+  - id: FD01
+    destination: { path: "${MTD_A:-/dev/mtd0}", offset: "${FD01_OFFSET}" }
+  - id: FD02
+    destination: { path: "${MMC}p1", offset: 4096 }
+  - id: FD03
+    destination: { path: "${MMC}p${PART:-2}", offset: "${FD03_OFFSET:-2048}" }
+"#,
+        )
+        .unwrap();
+        let board_path = dir.path().join("board.toml");
+        std::fs::write(
+            &board_path,
+            r#"
+include = "base.yaml"
+
+[variables]
+MMC = "/dev/mmcblk0"
+FD01_OFFSET = 512
+
+[[logical_blocks]]
+id = "FD02"
+destination = { offset = 8192 }
+"#,
+        )
+        .unwrap();
+
+        let mapping: Mapping = load_mapping_config(board_path.to_str().unwrap()).unwrap();
+        let destinations: Vec<Destination> = mapping
+            .logical_blocks
+            .into_iter()
+            .map(|logical_block| serde_json::from_value(logical_block["destination"].clone()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            destinations,
+            vec![
+                Destination {
+                    path: "/dev/mtd0".to_string(),
+                    offset: 512
+                },
+                Destination {
+                    path: "/dev/mmcblk0p1".to_string(),
+                    offset: 8192
+                },
+                Destination {
+                    path: "/dev/mmcblk0p2".to_string(),
+                    offset: 2048
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_mapping_config_test() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let undefined_path = dir.path().join("undefined.json");
+        std::fs::write(&undefined_path, r#"{"logical_blocks": ["${MTD_A}"]}"#).unwrap();
+        let error = load_mapping_config::<Mapping>(undefined_path.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("Undefined variable MTD_A"));
+
+        let cycle_path = dir.path().join("cycle.yaml");
+        std::fs::write(&cycle_path, "include: cycle.yaml\n").unwrap();
+        let error = load_mapping_config::<Mapping>(cycle_path.to_str().unwrap()).unwrap_err();
+        assert_eq!(error.code(), 200);
+        assert!(error.to_string().contains("Mapping includes itself"));
+
+        // The same file spelled differently is still a cycle
+        let nested_dir = dir.path().join("nested");
+        std::fs::create_dir(&nested_dir).unwrap();
+        std::fs::write(
+            dir.path().join("parent.json"),
+            r#"{"include": "nested/child.json"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            nested_dir.join("child.json"),
+            r#"{"include": "./../parent.json"}"#,
+        )
+        .unwrap();
+        let error =
+            load_mapping_config::<Mapping>(dir.path().join("parent.json").to_str().unwrap())
+                .unwrap_err();
+        assert!(error.to_string().contains("Mapping includes itself"));
+
+        for depth in 0..=MAX_INCLUDE_DEPTH {
+            std::fs::write(
+                dir.path().join(format!("depth_{depth}.json")),
+                format!(r#"{{"include": "depth_{}.json"}}"#, depth + 1),
+            )
+            .unwrap();
+        }
+        std::fs::write(
+            dir.path()
+                .join(format!("depth_{}.json", MAX_INCLUDE_DEPTH + 1)),
+            "{}",
+        )
+        .unwrap();
+        let error =
+            load_mapping_config::<Mapping>(dir.path().join("depth_0.json").to_str().unwrap())
+                .unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"));
+    }
+}
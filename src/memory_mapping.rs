@@ -0,0 +1,731 @@
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tracing::info_span;
+
+use crate::{
+    boot_control::BootControl,
+    chunked_io::{check_chunking, get_chunk_size, DestinationFile, WriteHook, DEFAULT_BUFFER_SIZE},
+    digest::{sha256_hex_of_destination_region, to_hex},
+    mapping_config::load_mapping_config,
+    nand::NandLayout,
+    reporting::{DocumentError, LogicalBlockError, UpdateError, UpdatePhase},
+    retry::RetryPolicy,
+};
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct LogicalBlockCfg {
+    /// How the slots of the device are used, `ab` by default.
+    #[serde(default)]
+    pub layout: SlotLayout,
+    /// Slot the update is installed in, by default `bank_a`, or `single` in the single slot
+    /// layout.
+    #[serde(default)]
+    pub target_slot: Option<String>,
+    /// Slot logical blocks missing from a partial update are cloned from, by default the other
+    /// bank of the `bank_a`/`bank_b` pair.
+    #[serde(default)]
+    pub active_slot: Option<String>,
+    /// Image the device boots when an update of the single slot layout fails.
+    #[serde(default)]
+    pub recovery: Option<RecoveryImage>,
+    /// Path of the boot-control record, switched to the targeted slot once the update is
+    /// verified. Without an explicit `target_slot`, the bank that isn't booted is targeted.
+    #[serde(default)]
+    pub boot_control: Option<String>,
+    pub logical_blocks: Vec<LogicalBlock>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+pub enum SlotLayout {
+    /// Updates are installed in the inactive bank of an A/B pair.
+    #[default]
+    #[serde(rename = "ab")]
+    AB,
+    /// Logical blocks are overwritten in place, a recovery image takes over if that fails.
+    #[serde(rename = "single")]
+    Single,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct RecoveryImage {
+    #[serde(flatten)]
+    pub location: LogicalBlockDestination,
+    /// SHA-256 of the recovery image, checked before overwriting the single slot.
+    pub digest: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct LogicalBlock {
+    pub name: String,
+    pub id: String,
+    /// Destination of the logical block in each named slot (`bank_a`, `recovery`...).
+    pub destination: BTreeMap<String, LogicalBlockDestination>,
+}
+impl LogicalBlock {
+    fn get_location_from_slot(&self, slot: &str) -> Option<&LogicalBlockDestination> {
+        self.destination.get(slot)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+
+pub struct LogicalBlockDestination {
+    path: String,
+    offset: u64,
+    size: usize,
+    /// Size of the chunks written to and read back from the destination, 4096 bytes by
+    /// default. eMMC and NAND devices want 128 KiB or more.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    buffer_size: Option<usize>,
+    /// Chunks written after the first one start on a multiple of this many bytes of the
+    /// destination, 1 by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alignment: Option<usize>,
+    /// How failed writes are retried, not at all by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry: Option<RetryPolicy>,
+    /// Layout of the raw NAND device `path` holds, addressed around its bad erase blocks. Plain
+    /// files and block devices have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nand: Option<NandLayout>,
+    /// Hook the writes to the destination go through, from the control of the update.
+    #[serde(skip)]
+    write_hook: Option<Arc<dyn WriteHook>>,
+}
+
+impl LogicalBlockDestination {
+    pub fn get_path(&self) -> &str {
+        self.path.as_ref()
+    }
+
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get_buffer_size(&self) -> usize {
+        self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE)
+    }
+
+    pub fn get_alignment(&self) -> usize {
+        self.alignment.unwrap_or(1)
+    }
+
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        self.retry.clone().unwrap_or_default()
+    }
+
+    pub fn get_nand_layout(&self) -> Option<&NandLayout> {
+        self.nand.as_ref()
+    }
+
+    /// Opens the destination for reading, through the bad-block mapping of its NAND device.
+    pub(crate) fn open_file(&self) -> io::Result<DestinationFile> {
+        DestinationFile::open(&self.path, self.nand.as_ref())
+    }
+
+    /// Opens the destination for writing, see [`DestinationFile::create`].
+    pub(crate) fn create_file(&self) -> io::Result<DestinationFile> {
+        let file = DestinationFile::create(&self.path, self.nand.as_ref())?;
+        match &self.write_hook {
+            Some(write_hook) => Ok(file.hooked(&self.path, write_hook.clone())),
+            None => Ok(file),
+        }
+    }
+
+    /// Returns the size of the chunk to write once `written_bytes` of the destination are
+    /// written, keeping the following chunks aligned.
+    pub fn get_chunk_size(&self, written_bytes: usize) -> usize {
+        get_chunk_size(
+            self.offset + written_bytes as u64,
+            self.get_buffer_size(),
+            self.get_alignment(),
+        )
+    }
+
+    pub fn overlaps(&self, other: &LogicalBlockDestination) -> bool {
+        self.path == other.path
+            && self.offset < other.offset + other.size as u64
+            && other.offset < self.offset + self.size as u64
+    }
+
+    /// Fills the destination region with erased bytes, before writing a logical block again.
+    pub fn erase(&self) -> io::Result<()> {
+        self.create_file()?
+            .erase(self.offset, self.size, self.get_buffer_size())
+    }
+
+    /// Returns the SHA-256 digest of the destination region, or `None` if it can't be read.
+    pub fn get_content_digest(&self) -> Option<String> {
+        let file = self.open_file().ok()?;
+        sha256_hex_of_destination_region(&file, self.offset, self.size, self.get_buffer_size()).ok()
+    }
+
+    pub fn holds_content_with_digest(&self, digest: Option<&str>) -> bool {
+        match digest {
+            Some(digest) => self.get_content_digest().as_deref() == Some(digest),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LogicalBlockClone {
+    pub id: String,
+    pub source: LogicalBlockDestination,
+    pub destination: LogicalBlockDestination,
+}
+
+impl LogicalBlockClone {
+    /// Copies the logical block from its source to its destination, then checks that the
+    /// destination content has the same digest as what was read from the source.
+    ///
+    /// Returns the SHA-256 digest of the copied content.
+    pub fn copy(&self) -> Result<String, UpdateError> {
+        self.check_size()?;
+
+        let source_file = self
+            .source
+            .open_file()
+            .map_err(|error| self.read_error(error))?;
+        let destination_file = self
+            .destination
+            .create_file()
+            .map_err(|error| self.write_error(error))?;
+
+        let mut read_buffer = vec![0; self.destination.get_buffer_size()];
+        let mut hasher = Sha256::new();
+        let mut remaining_bytes = self.source.get_size();
+
+        while remaining_bytes > 0 {
+            let copied_bytes = self.source.get_size() - remaining_bytes;
+            let bytes_to_copy = remaining_bytes.min(self.destination.get_chunk_size(copied_bytes));
+
+            if let Err(error) = source_file.read_exact_at(
+                &mut read_buffer[..bytes_to_copy],
+                self.source.get_offset() + copied_bytes as u64,
+            ) {
+                return Err(self.read_error(error));
+            }
+            if let Err(error) = destination_file.write_all_at(
+                &read_buffer[..bytes_to_copy],
+                self.destination.get_offset() + copied_bytes as u64,
+            ) {
+                return Err(self.write_error(error));
+            }
+
+            hasher.update(&read_buffer[..bytes_to_copy]);
+            remaining_bytes -= bytes_to_copy;
+        }
+
+        self.check_copy(
+            to_hex(&hasher.finish()),
+            self.destination.get_content_digest(),
+        )
+    }
+
+    /// Checks that the source and destination have the same size, before copying.
+    pub(crate) fn check_size(&self) -> Result<(), UpdateError> {
+        match self.source.get_size() == self.destination.get_size() {
+            true => Ok(()),
+            false => Err(UpdateError::LogicalBlockSize(LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Write,
+                format!(
+                    "Unable to clone logical block: source size ({}) doesn't match destination size ({})",
+                    self.source.get_size(),
+                    self.destination.get_size()
+                ),
+            ))),
+        }
+    }
+
+    /// Checks that the destination content, with digest `destination_digest`, matches what was
+    /// read from the source, and returns the digest of the copied content.
+    pub(crate) fn check_copy(
+        &self,
+        source_digest: String,
+        destination_digest: Option<String>,
+    ) -> Result<String, UpdateError> {
+        match destination_digest.as_ref() == Some(&source_digest) {
+            true => Ok(source_digest),
+            false => Err(UpdateError::VerificationError(LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Verify,
+                format!(
+                    "Cloned logical block doesn't match the digest of its source ({source_digest})"
+                ),
+            ))),
+        }
+    }
+
+    pub(crate) fn read_error(&self, error: io::Error) -> UpdateError {
+        UpdateError::LogicalBlockRead(
+            LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Write,
+                format!(
+                    "Unable to read logical block to clone from {}",
+                    self.source.get_path()
+                ),
+            )
+            .caused_by(&error),
+        )
+    }
+
+    pub(crate) fn write_error(&self, error: io::Error) -> UpdateError {
+        UpdateError::LogicalBlockWrite(
+            LogicalBlockError::new(
+                self.id.clone(),
+                UpdatePhase::Write,
+                format!(
+                    "Unable to write cloned logical block to {}",
+                    self.destination.get_path()
+                ),
+            )
+            .caused_by(&error),
+        )
+    }
+}
+
+/// Destinations of the logical blocks in the slot an update targets, shared by the update
+/// strategies, which only differ in how they do the I/O.
+#[derive(Debug)]
+pub struct MemoryMapping {
+    targeted_bank: String,
+    logical_blocks: HashMap<String, LogicalBlockDestination>,
+    logical_block_names: HashMap<String, String>,
+    active_logical_blocks: HashMap<String, LogicalBlockDestination>,
+    layout: SlotLayout,
+    recovery: Option<RecoveryImage>,
+    boot_control: Option<BootControl>,
+    /// Whether a destination of the targeted slot started being overwritten.
+    slot_written: AtomicBool,
+}
+
+impl MemoryMapping {
+    pub fn from(mapping_path: &str) -> Result<MemoryMapping, UpdateError> {
+        let _mapping_load = info_span!("mapping_load", path = mapping_path).entered();
+
+        Self::from_config(load_mapping_config(mapping_path)?, mapping_path)
+    }
+
+    /// Same as [`MemoryMapping::from`], targeting `slot` whatever the mapping targets.
+    pub fn from_slot(mapping_path: &str, slot: &str) -> Result<MemoryMapping, UpdateError> {
+        let _mapping_load = info_span!("mapping_load", path = mapping_path, slot).entered();
+
+        let lb_cfg = LogicalBlockCfg {
+            target_slot: Some(slot.to_string()),
+            ..load_mapping_config(mapping_path)?
+        };
+        Self::from_config(lb_cfg, mapping_path)
+    }
+
+    fn from_config(
+        lb_cfg: LogicalBlockCfg,
+        mapping_path: &str,
+    ) -> Result<MemoryMapping, UpdateError> {
+        let boot_control = lb_cfg.boot_control.as_deref().map(BootControl::from);
+        let booted_bank = match &boot_control {
+            Some(boot_control) => boot_control.read_record()?.active_slot,
+            None => None,
+        };
+
+        let targeted_bank = Self::get_target_bank(&lb_cfg, booted_bank.as_deref());
+        let target_bank_mapping = Self::get_bank_mapping(&lb_cfg, &targeted_bank);
+        if target_bank_mapping.is_empty() {
+            return Err(UpdateError::InvalidMemoryMapping(DocumentError::new(
+                mapping_path,
+                UpdatePhase::Mapping,
+                format!("No logical block has a destination in the {targeted_bank} slot"),
+            )));
+        }
+        Self::check_destinations(&target_bank_mapping, &targeted_bank, mapping_path)?;
+        let active_bank_mapping =
+            match Self::get_active_bank(&lb_cfg, booted_bank.as_deref(), &targeted_bank) {
+                Some(active_bank) => Self::get_bank_mapping(&lb_cfg, &active_bank),
+                None => HashMap::new(),
+            };
+        let recovery = Self::get_recovery(&lb_cfg, &target_bank_mapping, mapping_path)?;
+
+        Ok(MemoryMapping {
+            targeted_bank,
+            logical_blocks: target_bank_mapping,
+            logical_block_names: lb_cfg
+                .logical_blocks
+                .iter()
+                .map(|lb| (lb.id.clone(), lb.name.clone()))
+                .collect(),
+            active_logical_blocks: active_bank_mapping,
+            layout: lb_cfg.layout,
+            recovery,
+            boot_control,
+            slot_written: AtomicBool::new(false),
+        })
+    }
+
+    /// Makes the writes to every destination of the mapping go through `write_hook` first.
+    pub(crate) fn with_write_hook(mut self, write_hook: Option<&Arc<dyn WriteHook>>) -> Self {
+        for destination in self
+            .logical_blocks
+            .values_mut()
+            .chain(self.active_logical_blocks.values_mut())
+        {
+            destination.write_hook = write_hook.cloned();
+        }
+        self
+    }
+
+    pub fn get_logical_block_destination(
+        &self,
+        logical_block_id: &str,
+    ) -> Result<&LogicalBlockDestination, UpdateError> {
+        match self.logical_blocks.get(logical_block_id) {
+            Some(destination) => Ok(destination),
+            None => Err(UpdateError::MissingLogicalBlock(LogicalBlockError::new(
+                logical_block_id.to_string(),
+                UpdatePhase::Mapping,
+                format!(
+                    "Logical block {logical_block_id} has no destination in the memory mapping"
+                ),
+            ))),
+        }
+    }
+
+    pub fn get_targeted_bank(&self) -> &str {
+        self.targeted_bank.as_ref()
+    }
+
+    /// Lists the logical blocks of the targeted bank as `(id, name, destination)`, sorted by id.
+    pub fn get_logical_blocks(&self) -> Vec<(&str, &str, &LogicalBlockDestination)> {
+        let mut logical_blocks: Vec<_> = self
+            .logical_blocks
+            .iter()
+            .map(|(id, destination)| {
+                (
+                    id.as_str(),
+                    self.logical_block_names[id].as_str(),
+                    destination,
+                )
+            })
+            .collect();
+        logical_blocks.sort_by_key(|(id, _, _)| *id);
+        logical_blocks
+    }
+
+    fn check_destinations(
+        bank_mapping: &HashMap<String, LogicalBlockDestination>,
+        bank: &str,
+        mapping_path: &str,
+    ) -> Result<(), UpdateError> {
+        for (id, destination) in bank_mapping {
+            let invalid_destination = |setting: &str, description: String| {
+                UpdateError::InvalidMemoryMapping(DocumentError::new(
+                    mapping_path,
+                    UpdatePhase::Mapping,
+                    format!(
+                        "Invalid {setting} of logical block {id} in the {bank} slot: {description}"
+                    ),
+                ))
+            };
+
+            check_chunking(destination.get_buffer_size(), destination.get_alignment())
+                .map_err(|description| invalid_destination("chunking", description))?;
+            destination
+                .get_retry_policy()
+                .check()
+                .map_err(|description| invalid_destination("retry policy", description))?;
+            if let Some(nand_layout) = destination.get_nand_layout() {
+                nand_layout
+                    .check(
+                        destination.get_offset(),
+                        destination.get_buffer_size(),
+                        destination.get_alignment(),
+                    )
+                    .map_err(|description| invalid_destination("NAND layout", description))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_bank_mapping(
+        lb_cfg: &LogicalBlockCfg,
+        bank: &str,
+    ) -> HashMap<String, LogicalBlockDestination> {
+        lb_cfg
+            .logical_blocks
+            .iter()
+            .filter_map(|lb| {
+                lb.get_location_from_slot(bank)
+                    .map(|location| (lb.id.clone(), location.clone()))
+            })
+            .collect()
+    }
+
+    /// Lists the logical blocks of the mapping that aren't part of the update, along with
+    /// where to copy them from so that the targeted bank ends up complete.
+    pub fn get_logical_block_clones(
+        &self,
+        updated_logical_block_ids: &[String],
+    ) -> Result<Vec<LogicalBlockClone>, UpdateError> {
+        // Logical blocks that aren't part of the update are already in place in a single slot
+        if self.layout == SlotLayout::Single {
+            return Ok(vec![]);
+        }
+
+        let mut logical_block_clones = self
+            .logical_blocks
+            .iter()
+            .filter(|(id, _)| !updated_logical_block_ids.contains(id))
+            .map(|(id, destination)| match self.active_logical_blocks.get(id) {
+                Some(source) => Ok(LogicalBlockClone {
+                    id: id.clone(),
+                    source: source.clone(),
+                    destination: destination.clone(),
+                }),
+                None => Err(UpdateError::MissingLogicalBlock(LogicalBlockError::new(
+                    id.clone(),
+                    UpdatePhase::Mapping,
+                    format!(
+                        "Logical block {id} isn't part of the update and has no destination in the active slot to clone it from"
+                    ),
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        logical_block_clones.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(logical_block_clones)
+    }
+
+    fn get_target_bank(lb_cfg: &LogicalBlockCfg, booted_bank: Option<&str>) -> String {
+        match (&lb_cfg.target_slot, lb_cfg.layout, booted_bank) {
+            (Some(target_slot), _, _) => target_slot.clone(),
+            (None, SlotLayout::AB, Some("bank_a")) => "bank_b".to_string(),
+            (None, SlotLayout::AB, _) => "bank_a".to_string(),
+            (None, SlotLayout::Single, _) => "single".to_string(),
+        }
+    }
+
+    fn get_active_bank(
+        lb_cfg: &LogicalBlockCfg,
+        booted_bank: Option<&str>,
+        targeted_bank: &str,
+    ) -> Option<String> {
+        match (&lb_cfg.active_slot, lb_cfg.layout, targeted_bank) {
+            (Some(active_slot), _, _) => Some(active_slot.clone()),
+            (None, _, _) if booted_bank.is_some_and(|booted_bank| booted_bank != targeted_bank) => {
+                booted_bank.map(str::to_string)
+            }
+            (None, SlotLayout::AB, "bank_a") => Some("bank_b".to_string()),
+            (None, SlotLayout::AB, "bank_b") => Some("bank_a".to_string()),
+            (None, _, _) => None,
+        }
+    }
+
+    fn get_recovery(
+        lb_cfg: &LogicalBlockCfg,
+        target_bank_mapping: &HashMap<String, LogicalBlockDestination>,
+        mapping_path: &str,
+    ) -> Result<Option<RecoveryImage>, UpdateError> {
+        let mapping_error = |description: String| {
+            UpdateError::InvalidMemoryMapping(DocumentError::new(
+                mapping_path,
+                UpdatePhase::Mapping,
+                description,
+            ))
+        };
+
+        let recovery = match (lb_cfg.layout, &lb_cfg.recovery) {
+            (SlotLayout::AB, _) => return Ok(None),
+            (SlotLayout::Single, Some(recovery)) => recovery,
+            (SlotLayout::Single, None) => {
+                return Err(mapping_error(
+                    "The single slot layout requires a recovery image".to_string(),
+                ))
+            }
+        };
+
+        match target_bank_mapping
+            .iter()
+            .find(|(_, destination)| destination.overlaps(&recovery.location))
+        {
+            Some((id, _)) => Err(mapping_error(format!(
+                "Logical block {id} overlaps the recovery image"
+            ))),
+            None => Ok(Some(recovery.clone())),
+        }
+    }
+
+    /// Marks the targeted bank invalid in the boot-control record, if any, so that it isn't
+    /// booted while partially written.
+    pub fn stage_update(&self) -> Result<(), UpdateError> {
+        match (&self.boot_control, self.layout) {
+            (Some(boot_control), SlotLayout::AB) => {
+                boot_control.begin_staging(&self.targeted_bank).map(|_| ())
+            }
+            (Some(boot_control), SlotLayout::Single) => boot_control
+                .begin_staging_in_place(&self.targeted_bank)
+                .map(|_| ()),
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Switches boot to the targeted bank in the boot-control record, if any. Must only be
+    /// called once every logical block of the bank is verified.
+    pub fn commit_update(&self) -> Result<(), UpdateError> {
+        match &self.boot_control {
+            Some(boot_control) => boot_control.commit(&self.targeted_bank).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// In the single slot layout, checks that the recovery image is intact before each logical
+    /// block of the slot it would take over from gets overwritten.
+    pub fn check_recovery(&self) -> Result<(), UpdateError> {
+        match &self.recovery {
+            Some(recovery)
+                if !recovery
+                    .location
+                    .holds_content_with_digest(Some(&recovery.digest)) =>
+            {
+                Err(Self::recovery_check_error())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn get_recovery_image(&self) -> Option<&RecoveryImage> {
+        self.recovery.as_ref()
+    }
+
+    pub(crate) fn recovery_check_error() -> UpdateError {
+        UpdateError::VerificationError(LogicalBlockError::new(
+            "recovery",
+            UpdatePhase::Verify,
+            "Recovery image doesn't match its digest, refusing to overwrite the single slot",
+        ))
+    }
+
+    /// Records that a destination of the targeted slot is about to be written or erased.
+    pub(crate) fn record_slot_write(&self) {
+        self.slot_written.store(true, Ordering::Relaxed);
+    }
+
+    /// In the single slot layout, turns an error that happened once the slot started being
+    /// overwritten into a request to boot the recovery image, as the slot is left partially
+    /// written. Cancellations are reported as such, along with the logical blocks completed.
+    pub fn require_recovery_on_failure(&self, error: UpdateError) -> UpdateError {
+        let slot_written = self.slot_written.load(Ordering::Relaxed);
+
+        match (self.layout, &error) {
+            (SlotLayout::AB, _) | (SlotLayout::Single, UpdateError::Cancelled(_)) => error,
+            (SlotLayout::Single, _) if !slot_written => error,
+            (SlotLayout::Single, _) => UpdateError::RecoveryRequired(
+                LogicalBlockError::new(
+                    error.get_logical_block_id().unwrap_or_default(),
+                    error.phase(),
+                    "Single slot left partially updated, the device must boot its recovery image",
+                )
+                .caused_by(&error),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn real_mapping_test() {
+        let mapping = MemoryMapping::from("./resources/test/test_lb_cfg.json").unwrap();
+
+        for (id, location) in mapping.logical_blocks {
+            println!("id: {}, location: {:#?}", id, location);
+        }
+    }
+
+    #[test]
+    fn named_slots_mapping_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mapping_path = dir.path().join("slots.yaml");
+        std::fs::write(
+            &mapping_path,
+            r#"
+target_slot: recovery
+active_slot: factory
+logical_blocks:
+  - name: dummy_FD01
+    id: FD01
+    destination:
+      bank_a: { path: ./mtd_a, offset: 0, size: 16 }
+      recovery: { path: ./mtd_recovery, offset: 0, size: 16 }
+      factory: { path: ./mtd_factory, offset: 0, size: 16 }
+  - name: dummy_FD02
+    id: FD02
+    destination:
+      recovery: { path: ./mtd_recovery, offset: 16, size: 16 }
+"#,
+        )
+        .unwrap();
+
+        let mapping = MemoryMapping::from(mapping_path.to_str().unwrap()).unwrap();
+        assert_eq!(mapping.get_targeted_bank(), "recovery");
+
+        let clones = mapping
+            .get_logical_block_clones(&["FD02".to_string()])
+            .unwrap();
+        assert_eq!(clones[0].source.get_path(), "./mtd_factory");
+        assert_eq!(clones[0].destination.get_path(), "./mtd_recovery");
+
+        let error = mapping.get_logical_block_clones(&[]).unwrap_err();
+        assert_eq!(error.code(), 201);
+
+        let unknown_slot_path = dir.path().join("unknown_slot.yaml");
+        std::fs::write(
+            &unknown_slot_path,
+            std::fs::read_to_string(&mapping_path)
+                .unwrap()
+                .replace("target_slot: recovery", "target_slot: bank_c"),
+        )
+        .unwrap();
+        let error = MemoryMapping::from(unknown_slot_path.to_str().unwrap()).unwrap_err();
+        assert_eq!(error.code(), 200);
+    }
+
+    #[test]
+    fn toml_and_yaml_mapping_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_mapping: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("./resources/test/test_lb_cfg.json").unwrap(),
+        )
+        .unwrap();
+
+        let toml_path = dir.path().join("test_lb_cfg.toml");
+        std::fs::write(&toml_path, toml::to_string(&json_mapping).unwrap()).unwrap();
+        let yaml_path = dir.path().join("test_lb_cfg.yaml");
+        std::fs::write(&yaml_path, serde_yaml::to_string(&json_mapping).unwrap()).unwrap();
+
+        let expected = MemoryMapping::from("./resources/test/test_lb_cfg.json").unwrap();
+        for path in [toml_path, yaml_path] {
+            let mapping = MemoryMapping::from(path.to_str().unwrap()).unwrap();
+
+            assert_eq!(mapping.logical_blocks, expected.logical_blocks);
+            assert_eq!(
+                mapping.active_logical_blocks,
+                expected.active_logical_blocks
+            );
+        }
+    }
+}
@@ -0,0 +1,603 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::reporting::{UpdateError, UpdateReport};
+
+/// Upper bounds of the buckets of duration histograms, in seconds.
+const DURATION_BUCKETS: [f64; 14] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 60.0, 300.0,
+];
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the endpoint checks for scrapers and for being stopped.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricsFormat {
+    /// Prometheus text exposition format 0.0.4.
+    Prometheus,
+    /// OpenMetrics text format 1.0.0.
+    OpenMetrics,
+}
+
+impl MetricsFormat {
+    pub fn get_content_type(&self) -> &'static str {
+        match self {
+            MetricsFormat::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            MetricsFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+        }
+    }
+}
+
+/// Metrics of the updates run with an [`crate::UpdateControl`] holding them, for fleet
+/// dashboards.
+///
+/// Clones share the same metrics, which can be rendered at any time, written to a file for a
+/// textfile collector or scraped from a local HTTP endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateMetrics {
+    state: Arc<Mutex<MetricsState>>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    /// Updates by outcome.
+    updates: BTreeMap<&'static str, u64>,
+    /// Failed updates by error code.
+    errors: BTreeMap<u16, u64>,
+    update_duration: Histogram,
+    /// Bytes written per second by the last successful update.
+    throughput: Option<f64>,
+    index_parsing_duration: Histogram,
+    /// Keyed by logical block id, destination path and phase.
+    phase_durations: BTreeMap<(String, String, &'static str), Histogram>,
+    /// Keyed by logical block id and destination path.
+    written_bytes: BTreeMap<(String, String), u64>,
+    /// Keyed by logical block id and destination path.
+    retries: BTreeMap<(String, String), u64>,
+    /// Keyed by logical block id.
+    verification_failures: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    bucket_counts: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.bucket_counts[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+impl UpdateMetrics {
+    pub fn new() -> UpdateMetrics {
+        UpdateMetrics::default()
+    }
+
+    /// Records the outcome of an update that took `duration`. `update_report` holds the logical
+    /// blocks completed, before `update_error` if the update failed, whose bytes and retries count
+    /// all the same.
+    pub(crate) fn record_update(
+        &self,
+        update_report: &UpdateReport,
+        update_error: Option<&UpdateError>,
+        duration: Duration,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.update_duration.observe(duration);
+
+        let mut total_written_bytes = 0;
+        for logical_block in &update_report.logical_blocks {
+            let key = (
+                logical_block.logical_block_id.clone(),
+                logical_block.destination_path.clone(),
+            );
+            *state.written_bytes.entry(key.clone()).or_default() += logical_block.written_bytes;
+            total_written_bytes += logical_block.written_bytes;
+            if logical_block.retries > 0 {
+                *state.retries.entry(key.clone()).or_default() += logical_block.retries as u64;
+            }
+
+            let phase_durations = logical_block.phase_durations;
+            for (phase, phase_duration) in [
+                ("decompression", phase_durations.decompression),
+                ("write", phase_durations.write),
+                ("verify", phase_durations.verify),
+            ] {
+                // Phases a logical block didn't go through, like writing a skipped one
+                if phase_duration.is_zero() {
+                    continue;
+                }
+                state
+                    .phase_durations
+                    .entry((key.0.clone(), key.1.clone(), phase))
+                    .or_default()
+                    .observe(phase_duration);
+            }
+        }
+
+        match update_error {
+            None => {
+                *state.updates.entry("success").or_default() += 1;
+                state
+                    .index_parsing_duration
+                    .observe(update_report.index_parsing_duration);
+                state.throughput = Some(total_written_bytes as f64 / duration.as_secs_f64());
+            }
+            Some(error) => {
+                *state.updates.entry("failure").or_default() += 1;
+                *state.errors.entry(error.code()).or_default() += 1;
+
+                if let (UpdateError::VerificationError(_), Some(logical_block_id)) =
+                    (error, error.get_logical_block_id())
+                {
+                    *state
+                        .verification_failures
+                        .entry(logical_block_id.to_string())
+                        .or_default() += 1;
+                }
+            }
+        }
+    }
+
+    pub fn render(&self, format: MetricsFormat) -> String {
+        let state = self.state.lock().unwrap();
+        let mut output = MetricsOutput::new(format);
+
+        output.family("update_runs", "counter", "Updates run, by outcome.");
+        for (outcome, count) in &state.updates {
+            output.sample("update_runs_total", &[("outcome", outcome)], *count as f64);
+        }
+
+        output.family("update_errors", "counter", "Failed updates, by error code.");
+        for (code, count) in &state.errors {
+            output.sample(
+                "update_errors_total",
+                &[("code", &code.to_string())],
+                *count as f64,
+            );
+        }
+
+        output.family(
+            "update_duration_seconds",
+            "histogram",
+            "Duration of the updates.",
+        );
+        output.histogram("update_duration_seconds", &[], &state.update_duration);
+
+        output.family(
+            "update_throughput_bytes_per_second",
+            "gauge",
+            "Bytes written per second by the last successful update.",
+        );
+        if let Some(throughput) = state.throughput {
+            output.sample("update_throughput_bytes_per_second", &[], throughput);
+        }
+
+        output.family(
+            "update_index_parsing_duration_seconds",
+            "histogram",
+            "Time spent opening the archive and parsing its index and manifest.",
+        );
+        output.histogram(
+            "update_index_parsing_duration_seconds",
+            &[],
+            &state.index_parsing_duration,
+        );
+
+        output.family(
+            "update_logical_block_phase_duration_seconds",
+            "histogram",
+            "Time spent in each phase of the installation of a logical block.",
+        );
+        for ((logical_block_id, destination_path, phase), histogram) in &state.phase_durations {
+            output.histogram(
+                "update_logical_block_phase_duration_seconds",
+                &[
+                    ("logical_block_id", logical_block_id),
+                    ("destination_path", destination_path),
+                    ("phase", phase),
+                ],
+                histogram,
+            );
+        }
+
+        output.family(
+            "update_written_bytes",
+            "counter",
+            "Bytes written to the destination of a logical block.",
+        );
+        for ((logical_block_id, destination_path), written_bytes) in &state.written_bytes {
+            output.sample(
+                "update_written_bytes_total",
+                &[
+                    ("logical_block_id", logical_block_id),
+                    ("destination_path", destination_path),
+                ],
+                *written_bytes as f64,
+            );
+        }
+
+        output.family(
+            "update_logical_block_retries",
+            "counter",
+            "Chunk or logical block writes retried, as allowed by the destination.",
+        );
+        for ((logical_block_id, destination_path), retries) in &state.retries {
+            output.sample(
+                "update_logical_block_retries_total",
+                &[
+                    ("logical_block_id", logical_block_id),
+                    ("destination_path", destination_path),
+                ],
+                *retries as f64,
+            );
+        }
+
+        output.family(
+            "update_verification_failures",
+            "counter",
+            "Logical blocks that didn't match their signature once installed.",
+        );
+        for (logical_block_id, count) in &state.verification_failures {
+            output.sample(
+                "update_verification_failures_total",
+                &[("logical_block_id", logical_block_id)],
+                *count as f64,
+            );
+        }
+
+        output.finish()
+    }
+
+    /// Renders the metrics to `path`, replacing its content at once so that collectors never
+    /// read a partial file.
+    pub fn write_to_file(&self, path: &str, format: MetricsFormat) -> io::Result<()> {
+        let temporary_path = format!("{path}.tmp");
+
+        fs::write(&temporary_path, self.render(format))?;
+        fs::rename(&temporary_path, Path::new(path))
+    }
+
+    /// Serves the metrics on `GET /metrics` at `address` from a background thread, in the
+    /// OpenMetrics format when the scraper accepts it and the Prometheus one otherwise. Each
+    /// scraper is answered from a thread of its own, so that a slow one doesn't hold the others.
+    pub fn serve(&self, address: impl ToSocketAddrs) -> io::Result<MetricsEndpoint> {
+        let listener = TcpListener::bind(address)?;
+        let local_address = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let server = {
+            let update_metrics = self.clone();
+            let stopped = stopped.clone();
+
+            thread::spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let update_metrics = update_metrics.clone();
+                            // A scraper failing mid-request doesn't stop the endpoint
+                            thread::spawn(move || {
+                                let _ = stream
+                                    .set_nonblocking(false)
+                                    .and_then(|()| update_metrics.answer(stream));
+                            });
+                        }
+                        Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                            thread::sleep(ACCEPT_INTERVAL)
+                        }
+                        Err(_) => {}
+                    }
+                }
+            })
+        };
+
+        Ok(MetricsEndpoint {
+            local_address,
+            stopped,
+            server: Some(server),
+        })
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let request = read_request_head(&mut stream)?;
+
+        let (status, content_type, body) = match request.lines().next() {
+            Some(request_line) if request_line.starts_with("GET /metrics ") => {
+                let format = match request.lines().any(|line| {
+                    line.to_ascii_lowercase().starts_with("accept:")
+                        && line.contains("application/openmetrics-text")
+                }) {
+                    true => MetricsFormat::OpenMetrics,
+                    false => MetricsFormat::Prometheus,
+                };
+                ("200 OK", format.get_content_type(), self.render(format))
+            }
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        stream.flush()
+    }
+}
+
+fn read_request_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read_bytes = stream.read(&mut buffer)?;
+        if read_bytes == 0 || request.len() > MAX_REQUEST_SIZE {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read_bytes]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+/// Local HTTP endpoint serving [`UpdateMetrics`], stopped when dropped.
+#[derive(Debug)]
+pub struct MetricsEndpoint {
+    local_address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    server: Option<JoinHandle<()>>,
+}
+
+impl MetricsEndpoint {
+    pub fn get_local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+}
+
+impl Drop for MetricsEndpoint {
+    /// Stops accepting scrapers, waiting a few accept intervals for the listener to be released.
+    /// The scrapers being answered are left to finish on their own.
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        if let Some(server) = self.server.take() {
+            for _ in 0..5 {
+                if server.is_finished() {
+                    let _ = server.join();
+                    return;
+                }
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Text of the metrics being rendered in either format.
+struct MetricsOutput {
+    format: MetricsFormat,
+    text: String,
+}
+
+impl MetricsOutput {
+    fn new(format: MetricsFormat) -> MetricsOutput {
+        MetricsOutput {
+            format,
+            text: String::new(),
+        }
+    }
+
+    fn family(&mut self, name: &str, metric_type: &str, help: &str) {
+        // Prometheus names counter families after their samples, OpenMetrics without the suffix
+        let name = match (self.format, metric_type) {
+            (MetricsFormat::Prometheus, "counter") => format!("{name}_total"),
+            _ => name.to_string(),
+        };
+
+        writeln!(self.text, "# HELP {name} {help}").unwrap();
+        writeln!(self.text, "# TYPE {name} {metric_type}").unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape_label_value(value)))
+            .collect();
+
+        match labels.is_empty() {
+            true => writeln!(self.text, "{name} {value}").unwrap(),
+            false => writeln!(self.text, "{name}{{{}}} {value}", labels.join(",")).unwrap(),
+        }
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let mut cumulative_count = 0;
+
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.bucket_counts) {
+            cumulative_count += count;
+            let bound = bound.to_string();
+            self.sample(
+                &format!("{name}_bucket"),
+                &[labels, &[("le", &bound)]].concat(),
+                cumulative_count as f64,
+            );
+        }
+        self.sample(
+            &format!("{name}_bucket"),
+            &[labels, &[("le", "+Inf")]].concat(),
+            histogram.count as f64,
+        );
+        self.sample(&format!("{name}_sum"), labels, histogram.sum);
+        self.sample(&format!("{name}_count"), labels, histogram.count as f64);
+    }
+
+    fn finish(mut self) -> String {
+        if self.format == MetricsFormat::OpenMetrics {
+            self.text.push_str("# EOF\n");
+        }
+        self.text
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reporting::{
+        LogicalBlockError, LogicalBlockReport, LogicalBlockStatus, PhaseDurations, UpdatePhase,
+    };
+
+    fn logical_block_report(
+        logical_block_id: &str,
+        destination_path: &str,
+        retries: u32,
+    ) -> LogicalBlockReport {
+        LogicalBlockReport {
+            logical_block_id: logical_block_id.to_string(),
+            status: LogicalBlockStatus::Written,
+            digest: None,
+            signature: None,
+            destination_path: destination_path.to_string(),
+            written_bytes: 4096,
+            retries,
+            phase_durations: PhaseDurations {
+                write: Duration::from_millis(20),
+                verify: Duration::from_millis(3),
+                ..PhaseDurations::default()
+            },
+        }
+    }
+
+    fn record_updates(update_metrics: &UpdateMetrics) {
+        let update_report = UpdateReport {
+            logical_blocks: vec![logical_block_report("FD01", "/dev/mtd\"0\"", 2)],
+            targeted_bank: Some("bank_a".to_string()),
+            index_parsing_duration: Duration::from_millis(2),
+        };
+        update_metrics.record_update(&update_report, None, Duration::from_millis(500));
+
+        // FD03 was installed before FD02 failed
+        let failed_update_report = UpdateReport {
+            logical_blocks: vec![logical_block_report("FD03", "/dev/mmcblk0", 1)],
+            targeted_bank: Some("bank_a".to_string()),
+            index_parsing_duration: Duration::from_millis(2),
+        };
+        update_metrics.record_update(
+            &failed_update_report,
+            Some(&UpdateError::VerificationError(LogicalBlockError::new(
+                "FD02",
+                UpdatePhase::Verify,
+                "Verification failed",
+            ))),
+            Duration::from_secs(2),
+        );
+    }
+
+    #[test]
+    fn render_prometheus_metrics_test() {
+        let update_metrics = UpdateMetrics::new();
+        record_updates(&update_metrics);
+
+        let metrics = update_metrics.render(MetricsFormat::Prometheus);
+
+        for line in [
+            "# TYPE update_runs_total counter",
+            "update_runs_total{outcome=\"failure\"} 1",
+            "update_runs_total{outcome=\"success\"} 1",
+            "update_errors_total{code=\"400\"} 1",
+            "update_duration_seconds_bucket{le=\"0.5\"} 1",
+            "update_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "update_duration_seconds_sum 2.5",
+            "update_throughput_bytes_per_second 8192",
+            "update_logical_block_phase_duration_seconds_count{logical_block_id=\"FD01\",destination_path=\"/dev/mtd\\\"0\\\"\",phase=\"write\"} 1",
+            "update_written_bytes_total{logical_block_id=\"FD01\",destination_path=\"/dev/mtd\\\"0\\\"\"} 4096",
+            "update_logical_block_retries_total{logical_block_id=\"FD01\",destination_path=\"/dev/mtd\\\"0\\\"\"} 2",
+            "update_verification_failures_total{logical_block_id=\"FD02\"} 1",
+            "update_written_bytes_total{logical_block_id=\"FD03\",destination_path=\"/dev/mmcblk0\"} 4096",
+            "update_logical_block_retries_total{logical_block_id=\"FD03\",destination_path=\"/dev/mmcblk0\"} 1",
+        ] {
+            assert!(metrics.lines().any(|rendered| rendered == line), "{line}");
+        }
+        // A skipped phase isn't observed
+        assert!(!metrics.contains("phase=\"decompression\""));
+        assert!(!metrics.contains("# EOF"));
+    }
+
+    #[test]
+    fn serve_open_metrics_test() {
+        let update_metrics = UpdateMetrics::new();
+        record_updates(&update_metrics);
+        let endpoint = update_metrics.serve("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(endpoint.get_local_address()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nAccept: application/openmetrics-text\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/openmetrics-text"));
+        assert!(response.contains("# TYPE update_runs counter\n"));
+        assert!(response.ends_with("# EOF\n"));
+
+        let metrics_dir = tempfile::tempdir().unwrap();
+        let metrics_path = metrics_dir.path().join("update.prom");
+        let metrics_path = metrics_path.to_str().unwrap();
+        update_metrics
+            .write_to_file(metrics_path, MetricsFormat::Prometheus)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(metrics_path).unwrap(),
+            update_metrics.render(MetricsFormat::Prometheus)
+        );
+    }
+
+    #[test]
+    fn stalled_scraper_test() {
+        let update_metrics = UpdateMetrics::new();
+        let endpoint = update_metrics.serve("127.0.0.1:0").unwrap();
+
+        // Connects without ever sending its request
+        let _stalled_stream = TcpStream::connect(endpoint.get_local_address()).unwrap();
+        let mut stream = TcpStream::connect(endpoint.get_local_address()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT / 2)).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let drop_start = std::time::Instant::now();
+        drop(endpoint);
+        assert!(drop_start.elapsed() < REQUEST_TIMEOUT / 2);
+    }
+}
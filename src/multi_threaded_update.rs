@@ -1,4 +1,3 @@
 mod logical_blocks;
-mod memory;
 mod software_archive;
 pub mod update_sequence;
@@ -1,18 +1,26 @@
-use std::fs::File;
+use std::{error::Error, fs::File, io::Cursor, time::Instant};
 
 use memmap2::Mmap;
 use piz::{
     read::{as_tree, FileTree},
-    ZipArchive,
+    CompressionMethod, ZipArchive,
 };
 use rayon::prelude::*;
-
-use crate::{multi_threaded_update::memory::MemoryMapping, reporting::UpdateError};
+use tracing::{debug, field, info_span, Span};
+
+use crate::{
+    manifest::{ArchiveIndex, UpdateManifest, INDEX_PATH},
+    memory_mapping::MemoryMapping,
+    reporting::{
+        invalid_archive, DocumentError, LogicalBlockReport, LogicalBlockStatus, PhaseDurations,
+        UpdateError, UpdatePhase, UpdateProgress, UpdateReport,
+    },
+    retry::LogicalBlockInstall,
+    update_control::UpdateControl,
+};
 
 use super::logical_blocks::{LogicalBlock, LogicalBlockSource};
 
-const MANIFEST_XML_NAMESPACE: &str = "logical_blocks";
-
 pub struct SoftwareArchive {
     archive_bytes: Mmap,
 }
@@ -24,177 +32,394 @@ impl SoftwareArchive {
     }
 
     fn read_archive(archive_path: &str) -> Result<Mmap, UpdateError> {
-        let zip_file = File::open(archive_path).unwrap();
-        Ok(unsafe { Mmap::map(&zip_file).unwrap() })
+        let _archive_open = info_span!("archive_open", path = archive_path).entered();
+
+        let zip_file = File::open(archive_path).map_err(|error| {
+            invalid_archive(
+                archive_path,
+                UpdatePhase::Index,
+                "Unable to open archive",
+                &error,
+            )
+        })?;
+        unsafe { Mmap::map(&zip_file) }.map_err(|error| {
+            invalid_archive(
+                archive_path,
+                UpdatePhase::Index,
+                "Unable to map archive",
+                &error,
+            )
+        })
     }
 
-    pub fn extract_logical_blocks(&self, memory_mapping: MemoryMapping) -> Result<(), UpdateError> {
+    /// Installs the logical blocks of the archive, recording them in `update_report` as they
+    /// complete.
+    pub fn extract_logical_blocks(
+        &self,
+        memory_mapping: MemoryMapping,
+        update_control: &UpdateControl,
+        update_report: &mut UpdateReport,
+    ) -> Result<(), UpdateError> {
+        let index_parsing_start = Instant::now();
         let archive = self.get_archive()?;
 
-        let logical_blocks = self.get_logical_blocks(&archive, memory_mapping)?;
+        let logical_blocks = self.get_logical_blocks(&archive, &memory_mapping)?;
+        update_report.index_parsing_duration = index_parsing_start.elapsed();
 
-        self.write_logical_blocks(logical_blocks)
+        memory_mapping.stage_update()?;
+
+        self.write_logical_blocks(
+            logical_blocks,
+            &memory_mapping,
+            update_control,
+            update_report,
+        )
+        .map_err(|error| memory_mapping.require_recovery_on_failure(error))?;
+
+        memory_mapping.commit_update()
     }
 
     fn get_archive(&self) -> Result<ZipArchive<'_>, UpdateError> {
-        Ok(ZipArchive::new(&self.archive_bytes).unwrap())
+        ZipArchive::new(&self.archive_bytes).map_err(|error| {
+            invalid_archive(
+                "archive",
+                UpdatePhase::Index,
+                "Unable to read archive",
+                &error,
+            )
+        })
     }
 
     fn get_logical_blocks<'a>(
         &'a self,
         archive: &'a ZipArchive<'_>,
-        memory_mapping: MemoryMapping,
-    ) -> Result<Vec<LogicalBlock>, UpdateError> {
+        memory_mapping: &MemoryMapping,
+    ) -> Result<Vec<LogicalBlock<'a>>, UpdateError> {
         let index = self.read_archive_index(archive)?;
-        let manifest = self.create_update_manifest(archive, index)?;
+        let manifest = self.read_update_manifest(archive, &index)?;
 
         self.get_logical_blocks_from_manifest_and_memory_map(manifest, memory_mapping, archive)
     }
 
-    fn read_archive_index(
-        &self,
-        archive: &ZipArchive<'_>,
-    ) -> Result<minidom::Element, UpdateError> {
-        let index = self.read_file_content(archive, "index.xml").unwrap();
-        Ok(index.parse().unwrap())
+    fn read_archive_index(&self, archive: &ZipArchive<'_>) -> Result<ArchiveIndex, UpdateError> {
+        let _index_parse = info_span!("index_parse", path = INDEX_PATH).entered();
+
+        let index = self.read_file_content(archive, INDEX_PATH, UpdatePhase::Index)?;
+        ArchiveIndex::parse(&index)
     }
 
     fn read_file_content(
         &self,
         archive: &ZipArchive<'_>,
         path_in_archive: &str,
-    ) -> Result<String, UpdateError> {
-        let tree = as_tree(archive.entries()).unwrap();
-        let metadata = tree.lookup(path_in_archive).unwrap();
-
-        let mut reader = archive.read(metadata).unwrap();
-
-        let mut file_content = String::new();
-
-        reader.read_to_string(&mut file_content).unwrap();
+        phase: UpdatePhase,
+    ) -> Result<Vec<u8>, UpdateError> {
+        let read_error = |error: &(dyn Error + 'static)| {
+            invalid_archive(
+                path_in_archive,
+                phase,
+                "Unable to read file from archive",
+                error,
+            )
+        };
+
+        let tree = as_tree(archive.entries()).map_err(|error| read_error(&error))?;
+        let metadata = tree
+            .lookup(path_in_archive)
+            .map_err(|error| read_error(&error))?;
+
+        let mut reader = archive.read(metadata).map_err(|error| read_error(&error))?;
+
+        let mut file_content = Vec::new();
+
+        reader
+            .read_to_end(&mut file_content)
+            .map_err(|error| read_error(&error))?;
 
         Ok(file_content)
     }
 
-    fn create_update_manifest(
+    fn read_update_manifest(
         &self,
         archive: &ZipArchive<'_>,
-        index: minidom::Element,
-    ) -> Result<minidom::Element, UpdateError> {
-        let manifest_path = self.get_manifest_path(&index)?;
-        let manifest = self.read_file_content(archive, &manifest_path)?;
-
-        let manifest = manifest.parse().unwrap();
-
-        self.get_manifest_with_logical_block_paths(manifest, index)
-    }
-
-    fn get_manifest_path(&self, index: &minidom::Element) -> Result<String, UpdateError> {
-        let manifest_info = index
-            .children()
-            .find(|elem| elem.attr("short_name") == Some("update_manifest"))
-            .unwrap();
-
-        let manifest_path = manifest_info.get_child("path", "file_list").unwrap().text();
-
-        Ok(manifest_path)
-    }
+        index: &ArchiveIndex,
+    ) -> Result<UpdateManifest, UpdateError> {
+        let manifest_path = index.get_manifest_path()?;
+        let _manifest_parse = info_span!("manifest_parse", path = manifest_path).entered();
 
-    fn get_manifest_with_logical_block_paths(
-        &self,
-        mut manifest: minidom::Element,
-        index: minidom::Element,
-    ) -> Result<minidom::Element, UpdateError> {
-        for elem in manifest.children_mut() {
-            let name = elem
-                .get_child("short_name", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let path_in_archive = index
-                .children()
-                .find(|elem| elem.attr("short_name") == Some(&name))
-                .unwrap()
-                .get_child("path", "file_list")
-                .unwrap()
-                .text();
-
-            let path_node =
-                elem.append_child(minidom::Element::bare("path", MANIFEST_XML_NAMESPACE));
-            path_node.append_node(minidom::Node::Text(path_in_archive))
-        }
+        let manifest = self.read_file_content(archive, manifest_path, UpdatePhase::Manifest)?;
 
-        Ok(manifest)
+        UpdateManifest::parse(manifest_path, &manifest, index)
     }
 
     fn get_logical_blocks_from_manifest_and_memory_map<'a>(
         &'a self,
-        manifest: minidom::Element,
-        memory_mapping: MemoryMapping,
+        manifest: UpdateManifest,
+        memory_mapping: &MemoryMapping,
         archive: &'a ZipArchive<'_>,
     ) -> Result<Vec<LogicalBlock<'a>>, UpdateError> {
         let mut logical_blocks = Vec::new();
 
-        let tree = as_tree(archive.entries()).unwrap();
-
-        for elem in manifest.children() {
-            let id = elem.get_child("id", MANIFEST_XML_NAMESPACE).unwrap().text();
-
-            let name = elem
-                .get_child("short_name", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let signature = elem
-                .get_child("signature", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let path_in_archive = elem
-                .get_child("path", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
+        let tree = as_tree(archive.entries()).map_err(|error| {
+            invalid_archive(
+                "archive",
+                UpdatePhase::Manifest,
+                "Unable to list archive entries",
+                &error,
+            )
+        })?;
+        // Parsed once, to look up where the content of stored logical blocks starts
+        let mut stored_entries = zip::ZipArchive::new(Cursor::new(&self.archive_bytes[..]))
+            .map_err(|error| {
+                invalid_archive(
+                    "archive",
+                    UpdatePhase::Manifest,
+                    "Unable to list archive entries",
+                    &error,
+                )
+            })?;
+
+        for logical_block in manifest.logical_blocks {
+            let path_in_archive = logical_block.path_in_archive;
+            let missing_error = |error: &(dyn Error + 'static)| {
+                invalid_archive(
+                    &path_in_archive,
+                    UpdatePhase::Manifest,
+                    "Logical block listed in the manifest is missing from the archive",
+                    error,
+                )
+            };
 
-            let metadata = tree.lookup(path_in_archive).unwrap();
-            let logical_block_reader = archive.read(metadata).unwrap();
+            let metadata = tree
+                .lookup(&path_in_archive)
+                .map_err(|error| missing_error(&error))?;
+            // Checked first, the archive reader panics on a stored entry past the end of the archive
+            let stored_content = match metadata.compression_method {
+                CompressionMethod::None => {
+                    Some(self.get_stored_content(&mut stored_entries, &path_in_archive)?)
+                }
+                _ => None,
+            };
+            let logical_block_reader = archive
+                .read(metadata)
+                .map_err(|error| missing_error(&error))?;
 
             let logical_block_source = LogicalBlockSource {
                 file: logical_block_reader,
+                stored_content,
+                archive,
+                metadata,
             };
 
             let logical_block_destination = memory_mapping
-                .get_logical_block_destination(&id)
-                .unwrap()
+                .get_logical_block_destination(&logical_block.id)?
                 .clone();
 
             logical_blocks.push(LogicalBlock {
-                id,
-                name,
-                signature,
+                id: logical_block.id,
+                name: logical_block.short_name,
+                signature: logical_block.signature,
+                digest: logical_block.digest,
+                install_stage: logical_block.install_stage,
                 source: logical_block_source,
                 destination: logical_block_destination,
+                retries: 0,
             })
         }
         Ok(logical_blocks)
     }
 
+    /// Returns the bytes of an uncompressed archive entry, directly from the mapped archive.
+    fn get_stored_content<'a>(
+        &'a self,
+        stored_entries: &mut zip::ZipArchive<Cursor<&[u8]>>,
+        path_in_archive: &str,
+    ) -> Result<&'a [u8], UpdateError> {
+        let read_error = |error: &(dyn Error + 'static)| {
+            invalid_archive(
+                path_in_archive,
+                UpdatePhase::Manifest,
+                "Unable to locate stored file in archive",
+                error,
+            )
+        };
+
+        let file = stored_entries
+            .by_name(path_in_archive)
+            .map_err(|error| read_error(&error))?;
+
+        let data_start = file.data_start() as usize;
+        self.archive_bytes
+            .get(data_start..data_start + file.size() as usize)
+            .ok_or_else(|| {
+                UpdateError::InvalidArchive(DocumentError::new(
+                    path_in_archive,
+                    UpdatePhase::Manifest,
+                    "Stored file extends past the end of the archive",
+                ))
+            })
+    }
+
     fn write_logical_blocks(
         &self,
         mut logical_blocks: Vec<LogicalBlock<'_>>,
+        memory_mapping: &MemoryMapping,
+        update_control: &UpdateControl,
+        update_report: &mut UpdateReport,
     ) -> Result<(), UpdateError> {
-        let logical_block_failure: Vec<_> = logical_blocks
-            .par_iter_mut()
-            .map(|logical_block| -> Result<(), UpdateError> {
-                logical_block.write()?;
-                logical_block.verify()?;
-                Ok(())
-            })
-            .filter_map(|x| x.err())
-            .collect();
-
-        match logical_block_failure.len() == 0 {
-            true => Ok(()),
-            false => panic!("TODO: Handle errors"),
+        // Rayon threads don't inherit the span of the update, logical block spans name it as
+        // their parent instead
+        let update_span = Span::current();
+
+        // Stages are installed one after the other, only the logical blocks of a stage are
+        // written in parallel
+        for install_stage in logical_blocks
+            .chunk_by_mut(|logical_block, next| logical_block.install_stage == next.install_stage)
+        {
+            let (logical_block_reports, logical_block_failure): (Vec<_>, Vec<_>) = install_stage
+                .par_iter_mut()
+                .map(|logical_block| -> Result<LogicalBlockReport, UpdateError> {
+                    if !update_control.proceed() {
+                        return Err(UpdateError::Cancelled(UpdateProgress::new(
+                            UpdatePhase::Write,
+                        )));
+                    }
+
+                    let _logical_block_span = info_span!(
+                        parent: &update_span,
+                        "logical_block",
+                        logical_block_id = %logical_block.id
+                    )
+                    .entered();
+                    memory_mapping.check_recovery()?;
+
+                    let mut phase_durations = PhaseDurations::default();
+
+                    let verify_start = Instant::now();
+                    let is_up_to_date = logical_block.is_up_to_date();
+                    phase_durations.verify += verify_start.elapsed();
+
+                    LogicalBlockInstall {
+                        logical_block_id: logical_block.id.clone(),
+                        digest: logical_block.digest.clone(),
+                        signature: logical_block.signature.clone(),
+                        destination: logical_block.destination.clone(),
+                        memory_mapping,
+                    }
+                    .run(
+                        is_up_to_date,
+                        phase_durations,
+                        |attempt, is_up_to_date, phase_durations| {
+                            if attempt > 1 {
+                                logical_block.reopen()?;
+                            }
+                            let status = install_logical_block(
+                                logical_block,
+                                is_up_to_date,
+                                phase_durations,
+                                memory_mapping,
+                                update_control,
+                            )?;
+                            Ok((status, logical_block.retries))
+                        },
+                    )
+                })
+                .partition(|result| result.is_ok());
+
+            update_report
+                .logical_blocks
+                .extend(logical_block_reports.into_iter().map(Result::unwrap));
+
+            if let Some(Err(error)) = logical_block_failure.into_iter().next() {
+                return Err(error.after_completing(update_report.get_logical_block_ids()));
+            }
         }
+
+        self.clone_logical_blocks_missing_from_update(memory_mapping, update_report, update_control)
     }
+
+    fn clone_logical_blocks_missing_from_update(
+        &self,
+        memory_mapping: &MemoryMapping,
+        update_report: &mut UpdateReport,
+        update_control: &UpdateControl,
+    ) -> Result<(), UpdateError> {
+        let updated_logical_block_ids = update_report.get_logical_block_ids();
+        let update_span = Span::current();
+
+        let logical_block_clone_reports = memory_mapping
+            .get_logical_block_clones(&updated_logical_block_ids)?
+            .into_par_iter()
+            .map(
+                |logical_block_clone| -> Result<LogicalBlockReport, UpdateError> {
+                    if !update_control.proceed() {
+                        return Err(UpdateError::Cancelled(UpdateProgress::new(
+                            UpdatePhase::Write,
+                        )));
+                    }
+
+                    let write_start = Instant::now();
+                    let digest = info_span!(
+                        parent: &update_span,
+                        "clone",
+                        logical_block_id = %logical_block_clone.id,
+                        offset = logical_block_clone.destination.get_offset(),
+                        size = logical_block_clone.destination.get_size(),
+                    )
+                    .in_scope(|| logical_block_clone.copy())?;
+
+                    Ok(LogicalBlockReport {
+                        logical_block_id: logical_block_clone.id,
+                        status: LogicalBlockStatus::Cloned,
+                        digest: Some(digest),
+                        signature: None,
+                        destination_path: logical_block_clone.destination.get_path().to_string(),
+                        written_bytes: logical_block_clone.destination.get_size() as u64,
+                        retries: 0,
+                        phase_durations: PhaseDurations {
+                            write: write_start.elapsed(),
+                            ..PhaseDurations::default()
+                        },
+                    })
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| error.after_completing(update_report.get_logical_block_ids()))?;
+
+        update_report
+            .logical_blocks
+            .extend(logical_block_clone_reports);
+        Ok(())
+    }
+}
+
+/// Writes the logical block unless it is up to date, then verifies it against its signature.
+fn install_logical_block(
+    logical_block: &mut LogicalBlock<'_>,
+    is_up_to_date: bool,
+    phase_durations: &mut PhaseDurations,
+    memory_mapping: &MemoryMapping,
+    update_control: &UpdateControl,
+) -> Result<LogicalBlockStatus, UpdateError> {
+    let offset = logical_block.destination.get_offset();
+    let size = logical_block.destination.get_size();
+
+    let status = if is_up_to_date {
+        debug!("logical block already up to date");
+        LogicalBlockStatus::Skipped
+    } else {
+        memory_mapping.record_slot_write();
+        let write_start = Instant::now();
+        let decompression_duration = info_span!("write", offset, size, bytes = field::Empty)
+            .in_scope(|| logical_block.write(update_control))?;
+        phase_durations.decompression += decompression_duration;
+        phase_durations.write += write_start.elapsed().saturating_sub(decompression_duration);
+        LogicalBlockStatus::Written
+    };
+
+    let verify_start = Instant::now();
+    info_span!("verify", offset, size).in_scope(|| logical_block.verify(update_control))?;
+    phase_durations.verify += verify_start.elapsed();
+
+    Ok(status)
 }
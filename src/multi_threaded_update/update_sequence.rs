@@ -1,29 +1,366 @@
-use crate::{multi_threaded_update::memory::MemoryMapping, reporting::UpdateError};
+use std::time::Instant;
+
+use tracing::info_span;
+
+use crate::{
+    memory_mapping::MemoryMapping,
+    reporting::{trace_update_result, UpdateError, UpdateReport},
+    update_control::UpdateControl,
+};
 
 use super::software_archive::SoftwareArchive;
 
 pub fn multi_threaded_update(
     memory_mapping_path: &str,
     software_archive_path: &str,
+) -> Result<UpdateReport, UpdateError> {
+    multi_threaded_update_with_control(
+        memory_mapping_path,
+        software_archive_path,
+        &UpdateControl::new(),
+    )
+}
+
+/// Same as [`multi_threaded_update`], letting `update_control` cancel, pause, throttle or hook the
+/// writes of the update while it runs, and record its outcome.
+pub fn multi_threaded_update_with_control(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    update_control: &UpdateControl,
+) -> Result<UpdateReport, UpdateError> {
+    let update_start = Instant::now();
+
+    let update_span = info_span!(
+        "update",
+        strategy = "multi_threaded",
+        archive = software_archive_path,
+        mapping = memory_mapping_path
+    );
+    let mut update_report = UpdateReport::default();
+    let update_result = update_span.in_scope(|| {
+        let update_result = install_update(
+            memory_mapping_path,
+            software_archive_path,
+            update_control,
+            &mut update_report,
+        );
+        trace_update_result(&update_result, &update_report);
+        update_result
+    });
+
+    let record_result = update_control.record_update(
+        software_archive_path,
+        &update_report,
+        update_result.as_ref().err(),
+        update_start.elapsed(),
+    );
+    update_result.and(record_result).map(|()| update_report)
+}
+
+fn install_update(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    update_control: &UpdateControl,
+    update_report: &mut UpdateReport,
 ) -> Result<(), UpdateError> {
-    let memory_mapping = MemoryMapping::from(memory_mapping_path)?;
+    let memory_mapping =
+        MemoryMapping::from(memory_mapping_path)?.with_write_hook(update_control.get_write_hook());
+    update_report.targeted_bank = Some(memory_mapping.get_targeted_bank().to_string());
 
     let software_archive = SoftwareArchive::from(software_archive_path)?;
 
-    software_archive.extract_logical_blocks(memory_mapping)
+    software_archive.extract_logical_blocks(memory_mapping, update_control, update_report)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
     use super::*;
+    use crate::{
+        audit_log::{AuditLog, AuditOutcome},
+        manifest::ManifestFormat,
+        metrics::{MetricsFormat, UpdateMetrics},
+        reporting::{LogicalBlockStatus, UpdatePhase},
+        test_utils::{
+            assert_written_in_stages, fail_failing_writes, ArchiveBuilder, MappingBuilder,
+            WriteRecorder,
+        },
+        throttle::{IoBudget, IoThrottle},
+    };
 
     #[test]
     fn multi_threaded_update_test() {
-        let result = multi_threaded_update(
-            "./resources/test/test_lb_cfg.json",
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        let result = multi_threaded_update(&mapping_path, "./resources/test/update_folder.zip");
+
+        assert_eq!(result.unwrap().logical_blocks.len(), 9)
+    }
+
+    #[test]
+    fn multi_threaded_update_installs_stages_in_order_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let archive_path = ArchiveBuilder::new(destination_dir.path())
+            .depends_on("FD03", &["FD01"])
+            .depends_on("FD07", &["FD01"])
+            .phase(1, &["FD02", "FD05", "FD08"])
+            .build();
+        let write_recorder = WriteRecorder::new(&mapping_path);
+        let update_control = UpdateControl::new().with_write_hook(write_recorder.clone());
+
+        // Each stage is written in parallel, the next one only once it is done, whatever the
+        // number of cores of the machine running the test
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap()
+            .install(|| {
+                multi_threaded_update_with_control(&mapping_path, &archive_path, &update_control)
+            })
+            .unwrap();
+
+        assert_written_in_stages(
+            &write_recorder.get_writes(),
+            &[
+                &["FD01", "FD04", "FD06", "FD09"],
+                &["FD03", "FD07"],
+                &["FD02", "FD05", "FD08"],
+            ],
+        );
+    }
+
+    #[test]
+    fn cancelled_multi_threaded_update_stops_cloning_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let active_bank_mapping_path = MappingBuilder::new(destination_dir.path())
+            .swapped()
+            .build();
+        // Nothing to write, the update goes straight to cloning the whole active bank
+        let empty_archive_path = ArchiveBuilder::new(destination_dir.path())
+            .partial(&[])
+            .build();
+
+        multi_threaded_update(
+            &active_bank_mapping_path,
             "./resources/test/update_folder.zip",
+        )
+        .unwrap();
+
+        let update_control = UpdateControl::new();
+        update_control.cancel();
+        let error =
+            multi_threaded_update_with_control(&mapping_path, &empty_archive_path, &update_control)
+                .unwrap_err();
+
+        assert_eq!(error.code(), 800);
+        assert_eq!(error.phase(), UpdatePhase::Write);
+        match &error {
+            UpdateError::Cancelled(progress) => {
+                assert!(progress.completed_logical_blocks.is_empty())
+            }
+            _ => panic!("Unexpected error: {error}"),
+        }
+    }
+
+    #[test]
+    fn multi_threaded_update_with_stored_archive_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let stored_archive_path = ArchiveBuilder::new(destination_dir.path()).stored().build();
+
+        let first_report = multi_threaded_update(&mapping_path, &stored_archive_path).unwrap();
+        assert_eq!(
+            first_report
+                .get_logical_blocks_with_status(LogicalBlockStatus::Written)
+                .len(),
+            9
         );
+        // Stored logical blocks are written straight from the mapped archive
+        assert!(first_report.get_phase_durations().decompression.is_zero());
+        assert!(!first_report.get_phase_durations().write.is_zero());
 
-        assert_eq!(result, Ok(()))
+        let second_report = multi_threaded_update(&mapping_path, &stored_archive_path).unwrap();
+        assert_eq!(
+            second_report
+                .get_logical_blocks_with_status(LogicalBlockStatus::Skipped)
+                .len(),
+            9
+        );
+    }
+
+    #[test]
+    fn multi_threaded_update_with_cbor_manifest_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let archive_path = ArchiveBuilder::new(destination_dir.path())
+            .stored()
+            .manifest_format(ManifestFormat::Cbor)
+            .build();
+
+        let update_report = multi_threaded_update(&mapping_path, &archive_path).unwrap();
+        assert_eq!(
+            update_report
+                .get_logical_blocks_with_status(LogicalBlockStatus::Written)
+                .len(),
+            9
+        );
+    }
+
+    #[test]
+    fn multi_threaded_update_with_metrics_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let update_metrics = UpdateMetrics::new();
+        let update_control = UpdateControl::new().with_metrics(update_metrics.clone());
+
+        multi_threaded_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap();
+        multi_threaded_update_with_control(
+            &mapping_path,
+            "./resources/test/missing_update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+
+        let metrics = update_metrics.render(MetricsFormat::Prometheus);
+        assert!(metrics.contains("update_runs_total{outcome=\"success\"} 1\n"));
+        assert!(metrics.contains("update_errors_total{code=\"100\"} 1\n"));
+        assert!(metrics.contains(&format!(
+            "update_written_bytes_total{{logical_block_id=\"FD05\",destination_path=\"{}/mtd_a\"}} 16777035\n",
+            destination_dir.path().display()
+        )));
+    }
+
+    #[test]
+    fn failed_multi_threaded_update_metrics_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .retry(serde_json::json!({ "max_attempts": 1 }))
+            .failing(&["FD09"])
+            .build();
+        let update_metrics = UpdateMetrics::new();
+        let update_control = UpdateControl::new()
+            .with_metrics(update_metrics.clone())
+            .with_write_hook(fail_failing_writes);
+
+        multi_threaded_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+
+        // The logical blocks installed before the failure still count
+        let metrics = update_metrics.render(MetricsFormat::Prometheus);
+        assert!(metrics.contains("update_runs_total{outcome=\"failure\"} 1\n"));
+        assert!(metrics.contains(&format!(
+            "update_written_bytes_total{{logical_block_id=\"FD05\",destination_path=\"{}/mtd_a\"}} 16777035\n",
+            destination_dir.path().display()
+        )));
+    }
+
+    #[test]
+    fn multi_threaded_update_with_audit_log_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let audit_log = AuditLog::from(destination_dir.path().join("audit.log").to_str().unwrap());
+        let update_control = UpdateControl::new().with_audit_log(audit_log.clone());
+
+        multi_threaded_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap();
+        multi_threaded_update_with_control(
+            &mapping_path,
+            "./resources/test/missing_update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+
+        let entries = audit_log.read_entries().unwrap();
+        assert_eq!(audit_log.verify().unwrap(), 2);
+        assert_eq!(entries[0].record.outcome, AuditOutcome::Success);
+        assert_eq!(entries[0].record.logical_blocks.len(), 9);
+        assert!(matches!(
+            entries[1].record.outcome,
+            AuditOutcome::Failure {
+                error_code: 100,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn multi_threaded_update_with_throttle_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        // The archive holds about 18.7 MB of logical blocks
+        let io_throttle = IoThrottle::new(IoBudget {
+            bytes_per_second: Some(100_000_000),
+            operations_per_second: None,
+            niceness: None,
+        });
+
+        let start = Instant::now();
+        let update_report = multi_threaded_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &UpdateControl::new().with_io_throttle(io_throttle),
+        )
+        .unwrap();
+
+        assert!(start.elapsed() > Duration::from_millis(150));
+        assert_eq!(
+            update_report
+                .get_logical_blocks_with_status(LogicalBlockStatus::Written)
+                .len(),
+            9
+        );
+    }
+
+    #[test]
+    fn paused_multi_threaded_update_resumes_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let update_control = UpdateControl::new();
+        update_control.pause();
+
+        let resumer = {
+            let update_control = update_control.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                update_control.resume();
+            })
+        };
+
+        let start = Instant::now();
+        let update_report = multi_threaded_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap();
+        resumer.join().unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            update_report
+                .get_logical_blocks_with_status(LogicalBlockStatus::Written)
+                .len(),
+            9
+        );
     }
 }
@@ -0,0 +1,276 @@
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+use serde::{Deserialize, Serialize};
+
+/// Value erased NAND and NOR flash reads back as.
+pub(crate) const ERASED_BYTE: u8 = 0xFF;
+
+/// Layout of a raw NAND device, or of an image simulating one, that holds each page followed by
+/// its out-of-band (OOB) area, as `nanddump --oob` dumps them.
+///
+/// Destinations on a NAND device are addressed logically: their offset and size only count page
+/// data, and bad erase blocks are skipped, so that the nth erase block of the destinations is the
+/// nth good erase block of the device.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct NandLayout {
+    /// Bytes of data per page, 2048 on most SLC NAND.
+    page_size: usize,
+    /// Bytes of the OOB area following each page. It holds the bad-block marker and the ECC the
+    /// controller computes over whole pages, so it is left erased.
+    oob_size: usize,
+    pages_per_block: usize,
+    /// Erase blocks of the device known to be bad, counted from 0, on top of those whose
+    /// bad-block marker is set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bad_blocks: Vec<u64>,
+}
+
+impl NandLayout {
+    /// Returns the number of data bytes of an erase block, OOB areas left out.
+    pub fn get_block_size(&self) -> usize {
+        self.page_size * self.pages_per_block
+    }
+
+    fn get_raw_page_size(&self) -> u64 {
+        (self.page_size + self.oob_size) as u64
+    }
+
+    fn get_raw_block_size(&self) -> u64 {
+        self.get_raw_page_size() * self.pages_per_block as u64
+    }
+
+    /// Checks that a destination starting at `offset` and copied in chunks of `buffer_size`
+    /// bytes aligned on `alignment` only programs whole erase blocks, and tells why it doesn't.
+    pub(crate) fn check(
+        &self,
+        offset: u64,
+        buffer_size: usize,
+        alignment: usize,
+    ) -> Result<(), String> {
+        if self.page_size == 0 || self.pages_per_block == 0 {
+            return Err("Page size and pages per block must not be 0".to_string());
+        }
+        let block_size = self.get_block_size();
+
+        if !offset.is_multiple_of(block_size as u64) {
+            return Err(format!(
+                "Offset ({offset}) must start an erase block of {block_size} bytes"
+            ));
+        }
+        if !buffer_size.is_multiple_of(block_size) {
+            return Err(format!(
+                "Buffer size ({buffer_size}) must be a multiple of the erase block size ({block_size})"
+            ));
+        }
+        if !block_size.is_multiple_of(alignment) {
+            return Err(format!(
+                "Alignment ({alignment}) must divide the erase block size ({block_size})"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// NAND device whose page data is read and written at logical offsets, around its bad erase
+/// blocks.
+#[derive(Debug)]
+pub(crate) struct NandDevice {
+    file: File,
+    layout: NandLayout,
+    /// Good erase blocks of the device in order, logical erase block n being `good_blocks[n]`.
+    good_blocks: Vec<u64>,
+}
+
+impl NandDevice {
+    /// Scans the NAND device stored in `file` for bad erase blocks: those listed by `layout`,
+    /// and those whose first page has an OOB area starting with anything but [`ERASED_BYTE`],
+    /// as factory bad-block markers do.
+    pub(crate) fn from(file: File, layout: &NandLayout) -> io::Result<NandDevice> {
+        let block_count = file.metadata()?.len() / layout.get_raw_block_size();
+        let mut good_blocks = Vec::new();
+        let mut marker = [0; 1];
+
+        for block in 0..block_count {
+            if layout.bad_blocks.contains(&block) {
+                continue;
+            }
+            file.read_exact_at(
+                &mut marker,
+                block * layout.get_raw_block_size() + layout.page_size as u64,
+            )?;
+            if marker[0] == ERASED_BYTE {
+                good_blocks.push(block);
+            }
+        }
+
+        Ok(NandDevice {
+            file,
+            layout: layout.clone(),
+            good_blocks,
+        })
+    }
+
+    /// Reads the page data at logical `offset` until `buffer` is full.
+    pub(crate) fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut read_bytes = 0;
+
+        while read_bytes < buffer.len() {
+            let position = offset + read_bytes as u64;
+            let run_size = self.get_run_size(position, buffer.len() - read_bytes);
+
+            self.file.read_exact_at(
+                &mut buffer[read_bytes..read_bytes + run_size],
+                self.get_physical_offset(position)?,
+            )?;
+            read_bytes += run_size;
+        }
+        Ok(())
+    }
+
+    /// Programs `data` as page data at logical `offset`. Pages can't be programmed twice without
+    /// an erase in between, so erase blocks get erased when `data` reaches their first page.
+    pub(crate) fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let mut written_bytes = 0;
+
+        while written_bytes < data.len() {
+            let position = offset + written_bytes as u64;
+            let run_size = self.get_run_size(position, data.len() - written_bytes);
+
+            if position.is_multiple_of(self.layout.get_block_size() as u64) {
+                self.erase_block(self.get_physical_block(position)?)?;
+            }
+            self.file.write_all_at(
+                &data[written_bytes..written_bytes + run_size],
+                self.get_physical_offset(position)?,
+            )?;
+            written_bytes += run_size;
+        }
+        Ok(())
+    }
+
+    /// Erases the erase blocks holding the `size` bytes at logical `offset`.
+    pub(crate) fn erase(&self, offset: u64, size: usize) -> io::Result<()> {
+        let block_size = self.layout.get_block_size() as u64;
+        let mut position = offset - offset % block_size;
+
+        while position < offset + size as u64 {
+            self.erase_block(self.get_physical_block(position)?)?;
+            position += block_size;
+        }
+        Ok(())
+    }
+
+    /// Fills the pages and OOB areas of erase block `block` with [`ERASED_BYTE`], which also
+    /// leaves its bad-block marker unset.
+    fn erase_block(&self, block: u64) -> io::Result<()> {
+        let raw_block_size = self.layout.get_raw_block_size();
+
+        self.file.write_all_at(
+            &vec![ERASED_BYTE; raw_block_size as usize],
+            block * raw_block_size,
+        )
+    }
+
+    /// Returns how many of the `remaining_bytes` at logical `position` fit in its page.
+    fn get_run_size(&self, position: u64, remaining_bytes: usize) -> usize {
+        let page_size = self.layout.page_size;
+        remaining_bytes.min(page_size - (position % page_size as u64) as usize)
+    }
+
+    fn get_physical_block(&self, position: u64) -> io::Result<u64> {
+        let logical_block = position / self.layout.get_block_size() as u64;
+
+        match self.good_blocks.get(logical_block as usize) {
+            Some(block) => Ok(*block),
+            None => Err(io::Error::other(format!(
+                "Offset {position} is past the last of the {} good erase blocks of the NAND device",
+                self.good_blocks.len()
+            ))),
+        }
+    }
+
+    fn get_physical_offset(&self, position: u64) -> io::Result<u64> {
+        let page_size = self.layout.page_size as u64;
+        let position_in_block = position % self.layout.get_block_size() as u64;
+
+        Ok(
+            self.get_physical_block(position)? * self.layout.get_raw_block_size()
+                + position_in_block / page_size * self.layout.get_raw_page_size()
+                + position_in_block % page_size,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn create_layout(bad_blocks: Vec<u64>) -> NandLayout {
+        NandLayout {
+            page_size: 8,
+            oob_size: 2,
+            pages_per_block: 2,
+            bad_blocks,
+        }
+    }
+
+    #[test]
+    fn nand_device_skips_bad_blocks_test() {
+        let image_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        // 5 erase blocks of 2 pages of 8 bytes followed by 2 OOB bytes, block 1 marked bad and
+        // block 3 listed as bad
+        let mut image = vec![ERASED_BYTE; 100];
+        image[20..40].fill(0);
+        image[60..68].fill(0xAA);
+        fs::write(&image_path, &image).unwrap();
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(&image_path)
+            .unwrap();
+        let device = NandDevice::from(file, &create_layout(vec![3])).unwrap();
+        assert_eq!(device.good_blocks, [0, 2, 4]);
+
+        let data: Vec<u8> = (1..=40).collect();
+        device.write_all_at(&data, 0).unwrap();
+
+        let mut read_back = vec![0; 40];
+        device.read_exact_at(&mut read_back, 0).unwrap();
+        assert_eq!(read_back, data);
+
+        let image = fs::read(&image_path).unwrap();
+        assert_eq!(image[0..8], data[0..8]);
+        assert_eq!(image[8..10], [ERASED_BYTE; 2]);
+        assert_eq!(image[10..18], data[8..16]);
+        // Bad erase blocks are left as they were
+        assert_eq!(image[20..40], [0; 20]);
+        assert_eq!(image[40..48], data[16..24]);
+        assert_eq!(image[60..68], [0xAA; 8]);
+        assert_eq!(image[80..88], data[32..40]);
+        assert_eq!(image[88..100], [ERASED_BYTE; 12]);
+
+        device.erase(20, 4).unwrap();
+        assert_eq!(fs::read(&image_path).unwrap()[40..60], [ERASED_BYTE; 20]);
+
+        assert!(device.write_all_at(&data, 16).is_err());
+        assert!(device.read_exact_at(&mut read_back, 16).is_err());
+    }
+
+    #[test]
+    fn nand_layout_check_test() {
+        let layout = create_layout(vec![]);
+        assert!(layout.check(32, 16, 8).is_ok());
+        assert!(layout.check(8, 16, 8).is_err());
+        assert!(layout.check(32, 24, 8).is_err());
+        assert!(layout.check(32, 32, 32).is_err());
+        assert!(NandLayout {
+            page_size: 0,
+            ..layout
+        }
+        .check(0, 16, 1)
+        .is_err());
+    }
+}
@@ -1,3 +1,8 @@
+use std::{error::Error, fmt, ops::Add, time::Duration};
+
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use tracing::{error, info};
+
 #[derive(Debug, PartialEq)]
 pub enum UpdateError {
     LogicalBlockWrite(LogicalBlockError),
@@ -5,10 +10,672 @@ pub enum UpdateError {
     MissingLogicalBlock(LogicalBlockError),
     LogicalBlockSize(LogicalBlockError),
     VerificationError(LogicalBlockError),
+    InvalidArchive(DocumentError),
+    InvalidMemoryMapping(DocumentError),
+    AuditLog(DocumentError),
+    /// An update of a single slot layout failed after the slot started being overwritten, the
+    /// device must boot its recovery image.
+    RecoveryRequired(LogicalBlockError),
+    /// The boot-control record selecting the bank to boot couldn't be read or updated.
+    BootControl(DocumentError),
+    /// The update was cancelled through its [`crate::UpdateControl`]. The targeted bank is left
+    /// staged, the active bank still boots. In the single slot layout, the slot may be left
+    /// partially written, until an update completes or the recovery image boots.
+    Cancelled(UpdateProgress),
+    /// A logical block of an exported bank couldn't be signed.
+    Signing(LogicalBlockError),
+    /// The runtime running the update couldn't be started, nothing was read or written.
+    Runtime(DocumentError),
 }
 
-#[derive(Debug, PartialEq)]
+impl UpdateError {
+    /// Stable numeric code identifying the kind of error, meant for diagnostics backends.
+    pub fn code(&self) -> u16 {
+        match self {
+            UpdateError::InvalidArchive(_) => 100,
+            UpdateError::InvalidMemoryMapping(_) => 200,
+            UpdateError::MissingLogicalBlock(_) => 201,
+            UpdateError::LogicalBlockRead(_) => 300,
+            UpdateError::LogicalBlockWrite(_) => 301,
+            UpdateError::LogicalBlockSize(_) => 302,
+            UpdateError::VerificationError(_) => 400,
+            UpdateError::AuditLog(_) => 500,
+            UpdateError::RecoveryRequired(_) => 600,
+            UpdateError::BootControl(_) => 700,
+            UpdateError::Cancelled(_) => 800,
+            UpdateError::Signing(_) => 900,
+            UpdateError::Runtime(_) => 1000,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            UpdateError::InvalidArchive(_) => ErrorCategory::Archive,
+            UpdateError::InvalidMemoryMapping(_) | UpdateError::MissingLogicalBlock(_) => {
+                ErrorCategory::Mapping
+            }
+            UpdateError::LogicalBlockRead(_)
+            | UpdateError::LogicalBlockWrite(_)
+            | UpdateError::LogicalBlockSize(_) => ErrorCategory::Io,
+            UpdateError::VerificationError(_) => ErrorCategory::Integrity,
+            UpdateError::AuditLog(_) => ErrorCategory::Audit,
+            UpdateError::RecoveryRequired(_) => ErrorCategory::Recovery,
+            UpdateError::BootControl(_) => ErrorCategory::Boot,
+            UpdateError::Cancelled(_) => ErrorCategory::Cancellation,
+            UpdateError::Signing(_) => ErrorCategory::Signing,
+            UpdateError::Runtime(_) => ErrorCategory::Runtime,
+        }
+    }
+
+    pub fn phase(&self) -> UpdatePhase {
+        self.get_details().get_phase()
+    }
+
+    fn get_name(&self) -> &'static str {
+        match self {
+            UpdateError::LogicalBlockWrite(_) => "LogicalBlockWrite",
+            UpdateError::LogicalBlockRead(_) => "LogicalBlockRead",
+            UpdateError::MissingLogicalBlock(_) => "MissingLogicalBlock",
+            UpdateError::LogicalBlockSize(_) => "LogicalBlockSize",
+            UpdateError::VerificationError(_) => "VerificationError",
+            UpdateError::InvalidArchive(_) => "InvalidArchive",
+            UpdateError::InvalidMemoryMapping(_) => "InvalidMemoryMapping",
+            UpdateError::AuditLog(_) => "AuditLog",
+            UpdateError::RecoveryRequired(_) => "RecoveryRequired",
+            UpdateError::BootControl(_) => "BootControl",
+            UpdateError::Cancelled(_) => "Cancelled",
+            UpdateError::Signing(_) => "Signing",
+            UpdateError::Runtime(_) => "Runtime",
+        }
+    }
+
+    fn get_cause(&self) -> Option<&ErrorCause> {
+        self.get_details().get_cause()
+    }
+
+    /// Returns the details held by the error, the same kind for every variant about a document
+    /// or about a logical block.
+    fn get_details(&self) -> &dyn ErrorDetails {
+        match self {
+            UpdateError::InvalidArchive(error)
+            | UpdateError::InvalidMemoryMapping(error)
+            | UpdateError::AuditLog(error)
+            | UpdateError::BootControl(error)
+            | UpdateError::Runtime(error) => error,
+            UpdateError::LogicalBlockWrite(error)
+            | UpdateError::LogicalBlockRead(error)
+            | UpdateError::MissingLogicalBlock(error)
+            | UpdateError::LogicalBlockSize(error)
+            | UpdateError::VerificationError(error)
+            | UpdateError::RecoveryRequired(error)
+            | UpdateError::Signing(error) => error,
+            UpdateError::Cancelled(progress) => progress,
+        }
+    }
+
+    /// Records the byte offset at which a logical block error happened, if not already known.
+    pub(crate) fn at_offset(mut self, offset: u64) -> UpdateError {
+        match &mut self {
+            UpdateError::LogicalBlockWrite(error)
+            | UpdateError::LogicalBlockRead(error)
+            | UpdateError::MissingLogicalBlock(error)
+            | UpdateError::LogicalBlockSize(error)
+            | UpdateError::VerificationError(error)
+            | UpdateError::RecoveryRequired(error)
+            | UpdateError::Signing(error) => {
+                error.offset.get_or_insert(offset);
+            }
+            UpdateError::Cancelled(progress) => {
+                progress.offset.get_or_insert(offset);
+            }
+            UpdateError::InvalidArchive(_)
+            | UpdateError::InvalidMemoryMapping(_)
+            | UpdateError::AuditLog(_)
+            | UpdateError::BootControl(_)
+            | UpdateError::Runtime(_) => {}
+        }
+        self
+    }
+
+    /// Returns the logical block the error is about, if any.
+    pub fn get_logical_block_id(&self) -> Option<&str> {
+        match self {
+            UpdateError::LogicalBlockWrite(error)
+            | UpdateError::LogicalBlockRead(error)
+            | UpdateError::MissingLogicalBlock(error)
+            | UpdateError::LogicalBlockSize(error)
+            | UpdateError::VerificationError(error)
+            | UpdateError::RecoveryRequired(error)
+            | UpdateError::Signing(error) => Some(&error.logical_block_id),
+            UpdateError::Cancelled(progress) => progress.logical_block_id.as_deref(),
+            UpdateError::InvalidArchive(_)
+            | UpdateError::InvalidMemoryMapping(_)
+            | UpdateError::AuditLog(_)
+            | UpdateError::BootControl(_)
+            | UpdateError::Runtime(_) => None,
+        }
+    }
+
+    /// Records the logical blocks completed before a cancellation, ahead of those already
+    /// recorded. Other errors are left untouched.
+    pub(crate) fn after_completing(mut self, logical_block_ids: Vec<String>) -> UpdateError {
+        if let UpdateError::Cancelled(progress) = &mut self {
+            progress
+                .completed_logical_blocks
+                .splice(0..0, logical_block_ids);
+        }
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[E{}] {} error during ", self.code(), self.get_name())?;
+        self.get_details().fmt(f)
+    }
+}
+
+impl Error for UpdateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.get_cause()
+            .map(|cause| cause as &(dyn Error + 'static))
+    }
+}
+
+impl Serialize for UpdateError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("UpdateError", 5)?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("kind", self.get_name())?;
+        state.serialize_field("category", &self.category())?;
+        state.serialize_field("phase", &self.phase())?;
+        state.serialize_field("details", &self.get_details().as_serializable())?;
+        state.end()
+    }
+}
+
+/// Details held by the variants of [`UpdateError`], so that the error can be rendered the same
+/// way whatever the kind of its details.
+trait ErrorDetails: fmt::Display {
+    fn get_phase(&self) -> UpdatePhase;
+
+    fn get_cause(&self) -> Option<&ErrorCause>;
+
+    fn as_serializable(&self) -> SerializableDetails<'_>;
+}
+
+/// Details of an [`UpdateError`], serialized as the details they borrow.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SerializableDetails<'a> {
+    Document(&'a DocumentError),
+    LogicalBlock(&'a LogicalBlockError),
+    Progress(&'a UpdateProgress),
+}
+
+impl ErrorDetails for DocumentError {
+    fn get_phase(&self) -> UpdatePhase {
+        self.phase
+    }
+
+    fn get_cause(&self) -> Option<&ErrorCause> {
+        self.cause.as_ref()
+    }
+
+    fn as_serializable(&self) -> SerializableDetails<'_> {
+        SerializableDetails::Document(self)
+    }
+}
+
+impl ErrorDetails for LogicalBlockError {
+    fn get_phase(&self) -> UpdatePhase {
+        self.phase
+    }
+
+    fn get_cause(&self) -> Option<&ErrorCause> {
+        self.cause.as_ref()
+    }
+
+    fn as_serializable(&self) -> SerializableDetails<'_> {
+        SerializableDetails::LogicalBlock(self)
+    }
+}
+
+impl ErrorDetails for UpdateProgress {
+    fn get_phase(&self) -> UpdatePhase {
+        self.phase
+    }
+
+    fn get_cause(&self) -> Option<&ErrorCause> {
+        None
+    }
+
+    fn as_serializable(&self) -> SerializableDetails<'_> {
+        SerializableDetails::Progress(self)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    Archive,
+    Mapping,
+    Io,
+    Integrity,
+    Audit,
+    Recovery,
+    Boot,
+    Cancellation,
+    Signing,
+    Runtime,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdatePhase {
+    Index,
+    Manifest,
+    Mapping,
+    Write,
+    Verify,
+    Audit,
+    Commit,
+    Export,
+}
+
+impl fmt::Display for UpdatePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            UpdatePhase::Index => "index",
+            UpdatePhase::Manifest => "manifest",
+            UpdatePhase::Mapping => "mapping",
+            UpdatePhase::Write => "write",
+            UpdatePhase::Verify => "verify",
+            UpdatePhase::Audit => "audit",
+            UpdatePhase::Commit => "commit",
+            UpdatePhase::Export => "export",
+        };
+        f.write_str(phase)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
 pub struct LogicalBlockError {
     pub logical_block_id: String,
     pub description: String,
+    pub phase: UpdatePhase,
+    /// Byte offset in the destination at which the error happened, when known.
+    pub offset: Option<u64>,
+    pub cause: Option<ErrorCause>,
+}
+
+impl LogicalBlockError {
+    pub(crate) fn new(
+        logical_block_id: impl Into<String>,
+        phase: UpdatePhase,
+        description: impl Into<String>,
+    ) -> LogicalBlockError {
+        LogicalBlockError {
+            logical_block_id: logical_block_id.into(),
+            description: description.into(),
+            phase,
+            offset: None,
+            cause: None,
+        }
+    }
+
+    pub(crate) fn at_offset(mut self, offset: u64) -> LogicalBlockError {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub(crate) fn caused_by(mut self, error: &(dyn Error + 'static)) -> LogicalBlockError {
+        self.cause = Some(ErrorCause::from(error));
+        self
+    }
+}
+
+impl fmt::Display for LogicalBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of logical block {}",
+            self.phase, self.logical_block_id
+        )?;
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {offset}")?;
+        }
+        write!(f, ": {}", self.description)?;
+        if let Some(cause) = &self.cause {
+            write!(f, " ({cause})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Error about a document needed by the update (archive, index, manifest or memory mapping).
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DocumentError {
+    pub path: String,
+    pub description: String,
+    pub phase: UpdatePhase,
+    /// Position in the document at which the error was found, when known.
+    pub position: Option<TextPosition>,
+    pub cause: Option<ErrorCause>,
+}
+
+impl DocumentError {
+    pub(crate) fn new(
+        path: impl Into<String>,
+        phase: UpdatePhase,
+        description: impl Into<String>,
+    ) -> DocumentError {
+        DocumentError {
+            path: path.into(),
+            description: description.into(),
+            phase,
+            position: None,
+            cause: None,
+        }
+    }
+
+    pub(crate) fn at_position(mut self, line: u32, column: u32) -> DocumentError {
+        self.position = Some(TextPosition { line, column });
+        self
+    }
+
+    pub(crate) fn caused_by(mut self, error: &(dyn Error + 'static)) -> DocumentError {
+        self.cause = Some(ErrorCause::from(error));
+        self
+    }
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {}", self.phase, self.path)?;
+        if let Some(position) = &self.position {
+            write!(f, " at line {}, column {}", position.line, position.column)?;
+        }
+        write!(f, ": {}", self.description)?;
+        if let Some(cause) = &self.cause {
+            write!(f, " ({cause})")?;
+        }
+        Ok(())
+    }
+}
+
+/// How far an update went before it was cancelled.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UpdateProgress {
+    pub phase: UpdatePhase,
+    /// Logical block being written or verified when the update was cancelled, if any.
+    pub logical_block_id: Option<String>,
+    /// Byte offset in the destination reached in that logical block, when known.
+    pub offset: Option<u64>,
+    /// Logical blocks written and verified, skipped or cloned before the cancellation.
+    pub completed_logical_blocks: Vec<String>,
+}
+
+impl UpdateProgress {
+    pub(crate) fn new(phase: UpdatePhase) -> UpdateProgress {
+        UpdateProgress {
+            phase,
+            logical_block_id: None,
+            offset: None,
+            completed_logical_blocks: Vec::new(),
+        }
+    }
+
+    pub(crate) fn in_logical_block(
+        mut self,
+        logical_block_id: impl Into<String>,
+    ) -> UpdateProgress {
+        self.logical_block_id = Some(logical_block_id.into());
+        self
+    }
+}
+
+impl fmt::Display for UpdateProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.phase)?;
+        if let Some(logical_block_id) = &self.logical_block_id {
+            write!(f, " of logical block {logical_block_id}")?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {offset}")?;
+        }
+        write!(
+            f,
+            ": update cancelled after completing {} logical blocks",
+            self.completed_logical_blocks.len()
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct TextPosition {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Serializable snapshot of an underlying error (io, openssl, parsing...) and its own causes.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct ErrorCause {
+    pub message: String,
+    pub source: Option<Box<ErrorCause>>,
+}
+
+impl From<&(dyn Error + 'static)> for ErrorCause {
+    fn from(error: &(dyn Error + 'static)) -> ErrorCause {
+        ErrorCause {
+            message: error.to_string(),
+            source: error
+                .source()
+                .map(|source| Box::new(ErrorCause::from(source))),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ErrorCause {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn Error + 'static))
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct UpdateReport {
+    pub logical_blocks: Vec<LogicalBlockReport>,
+    /// Bank written by the update, known once its memory mapping is loaded.
+    pub targeted_bank: Option<String>,
+    /// Time spent opening the archive and parsing its index and manifest.
+    pub index_parsing_duration: Duration,
+}
+
+impl UpdateReport {
+    /// Returns the time spent in each phase of the update, summed over its logical blocks.
+    pub fn get_phase_durations(&self) -> PhaseDurations {
+        let index_parsing = PhaseDurations {
+            index_parsing: self.index_parsing_duration,
+            ..PhaseDurations::default()
+        };
+
+        self.logical_blocks
+            .iter()
+            .fold(index_parsing, |phase_durations, logical_block| {
+                phase_durations + logical_block.phase_durations
+            })
+    }
+
+    pub fn get_logical_blocks_with_status(&self, status: LogicalBlockStatus) -> Vec<String> {
+        self.logical_blocks
+            .iter()
+            .filter(|logical_block| logical_block.status == status)
+            .map(|logical_block| logical_block.logical_block_id.clone())
+            .collect()
+    }
+
+    pub(crate) fn get_logical_block_ids(&self) -> Vec<String> {
+        self.logical_blocks
+            .iter()
+            .map(|logical_block| logical_block.logical_block_id.clone())
+            .collect()
+    }
+}
+
+/// Returns the error of an archive whose document at `path` is invalid, because of `error`.
+pub(crate) fn invalid_archive(
+    path: &str,
+    phase: UpdatePhase,
+    description: &str,
+    error: &(dyn Error + 'static),
+) -> UpdateError {
+    UpdateError::InvalidArchive(DocumentError::new(path, phase, description).caused_by(error))
+}
+
+/// Traces how an update ended, from within the span of the update.
+pub(crate) fn trace_update_result(
+    update_result: &Result<(), UpdateError>,
+    update_report: &UpdateReport,
+) {
+    match update_result {
+        Ok(()) => info!(
+            logical_blocks = update_report.logical_blocks.len(),
+            "update installed"
+        ),
+        Err(error) => error!(
+            code = error.code(),
+            phase = %error.phase(),
+            logical_block_id = error.get_logical_block_id(),
+            %error,
+            "update failed"
+        ),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LogicalBlockReport {
+    pub logical_block_id: String,
+    pub status: LogicalBlockStatus,
+    /// SHA-256 digest of the logical block content, when known.
+    pub digest: Option<String>,
+    /// Signature from the manifest, `None` for logical blocks cloned from the active bank.
+    pub signature: Option<String>,
+    /// Path of the device or file the logical block was installed to.
+    pub destination_path: String,
+    /// Number of bytes written to the destination, 0 for skipped logical blocks.
+    pub written_bytes: u64,
+    /// Number of chunk or logical block writes retried, as allowed by the destination.
+    pub retries: u32,
+    pub phase_durations: PhaseDurations,
+}
+
+/// Time spent in each phase of an update. Logical blocks installed concurrently spend time in
+/// their phases at once, so the durations of an update can add up to more than its wall time.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct PhaseDurations {
+    /// Opening the archive and parsing its index and manifest.
+    pub index_parsing: Duration,
+    /// Reading logical blocks out of the archive, decompressing them if needed.
+    pub decompression: Duration,
+    /// Writing logical blocks to their destination, or cloning them from the active bank.
+    pub write: Duration,
+    /// Reading destinations back, to skip up to date logical blocks and check signatures.
+    pub verify: Duration,
+}
+
+impl Add for PhaseDurations {
+    type Output = PhaseDurations;
+
+    fn add(self, other: PhaseDurations) -> PhaseDurations {
+        PhaseDurations {
+            index_parsing: self.index_parsing + other.index_parsing,
+            decompression: self.decompression + other.decompression,
+            write: self.write + other.write,
+            verify: self.verify + other.verify,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogicalBlockStatus {
+    Written,
+    /// The destination already held the logical block content, nothing was written.
+    Skipped,
+    /// The logical block wasn't part of the update and was copied from the active bank.
+    Cloned,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_error() -> UpdateError {
+        let io_error = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "early eof");
+
+        UpdateError::LogicalBlockRead(
+            LogicalBlockError::new("FD03", UpdatePhase::Verify, "Unable to read back chunk")
+                .at_offset(8192)
+                .caused_by(&io_error),
+        )
+    }
+
+    #[test]
+    fn update_error_display_test() {
+        assert_eq!(
+            read_error().to_string(),
+            "[E300] LogicalBlockRead error during verify of logical block FD03 at offset 8192: \
+             Unable to read back chunk (early eof)"
+        );
+    }
+
+    #[test]
+    fn update_error_source_test() {
+        let error = read_error();
+        let source = error.source().unwrap();
+
+        assert_eq!(source.to_string(), "early eof");
+        assert_eq!(error.category(), ErrorCategory::Io);
+    }
+
+    #[test]
+    fn update_error_to_json_test() {
+        let json: serde_json::Value = serde_json::from_str(&read_error().to_json()).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": 300,
+                "kind": "LogicalBlockRead",
+                "category": "io",
+                "phase": "verify",
+                "details": {
+                    "logical_block_id": "FD03",
+                    "description": "Unable to read back chunk",
+                    "phase": "verify",
+                    "offset": 8192,
+                    "cause": { "message": "early eof", "source": null }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn at_offset_keeps_first_offset_test() {
+        let error = read_error().at_offset(0);
+
+        assert!(error.to_string().contains("at offset 8192"));
+    }
 }
@@ -0,0 +1,415 @@
+use std::{io, sync::Arc, thread, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    chunked_io::DestinationFile,
+    memory_mapping::{LogicalBlockDestination, MemoryMapping},
+    reporting::{
+        LogicalBlockError, LogicalBlockReport, LogicalBlockStatus, PhaseDurations, UpdateError,
+        UpdatePhase,
+    },
+    throttle::IoThrottle,
+};
+
+/// What gets written again when a write to a destination fails.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy, Default)]
+pub enum RetryGranularity {
+    /// The chunk that failed, the rest of the logical block is kept.
+    #[default]
+    #[serde(rename = "chunk")]
+    Chunk,
+    /// The whole logical block, from its first byte.
+    #[serde(rename = "block")]
+    Block,
+}
+
+/// How writes to a destination are retried when they fail, like NAND pages that usually take a
+/// rewrite after an erase. Without one, the first failed write aborts the update.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct RetryPolicy {
+    /// Number of times a chunk or logical block is written before giving up, retries included.
+    max_attempts: u32,
+    /// Milliseconds waited before the first retry, doubled before each following one.
+    #[serde(default)]
+    backoff_ms: u64,
+    #[serde(default)]
+    granularity: RetryGranularity,
+    /// Whether the failed region is erased, filled with `0xFF`, before being written again.
+    #[serde(default)]
+    erase: bool,
+    /// Whether writes that succeed but don't read back as written are retried too. Chunks are
+    /// compared to what was written, logical blocks are checked against their signature.
+    #[serde(default)]
+    verify: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff_ms: 0,
+            granularity: RetryGranularity::default(),
+            erase: false,
+            verify: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Checks the settings of the policy and tells why they are invalid.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        match self.max_attempts {
+            0 => Err("Retry policy must allow at least 1 attempt".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn get_granularity(&self) -> RetryGranularity {
+        self.granularity
+    }
+
+    pub fn is_erasing(&self) -> bool {
+        self.erase
+    }
+
+    pub fn is_verifying(&self) -> bool {
+        self.verify
+    }
+
+    /// Returns how long to wait before the `retry`th retry, counted from 1.
+    pub fn get_backoff(&self, retry: u32) -> Duration {
+        let factor = 1u64
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+
+    fn get_chunk_attempts(&self) -> u32 {
+        match self.granularity {
+            RetryGranularity::Chunk => self.max_attempts,
+            RetryGranularity::Block => 1,
+        }
+    }
+
+    /// Tells whether a logical block whose `attempt`th write, counted from 1, failed with
+    /// `error` gets written again. Errors reading the archive or cancellations aren't retried,
+    /// writing again wouldn't change them.
+    pub(crate) fn retries_logical_block(&self, attempt: u32, error: &UpdateError) -> bool {
+        if self.granularity != RetryGranularity::Block || attempt >= self.max_attempts {
+            return false;
+        }
+        match error {
+            UpdateError::LogicalBlockWrite(_) | UpdateError::LogicalBlockSize(_) => true,
+            UpdateError::VerificationError(_) => self.verify,
+            _ => false,
+        }
+    }
+
+    /// Waits before the `retry`th retry, counted from 1.
+    pub(crate) fn wait_before_retry(&self, retry: u32) {
+        thread::sleep(self.get_backoff(retry));
+    }
+
+    /// Writes a chunk of `size` bytes at `offset` of `destination` with `write`, retrying as
+    /// the policy allows. When verifying, the chunk is read back and compared to the content
+    /// `read_expected` fills its buffer with.
+    ///
+    /// Returns the number of retries it took.
+    pub(crate) fn write_chunk(
+        &self,
+        destination: &DestinationFile,
+        offset: u64,
+        size: usize,
+        mut write: impl FnMut() -> io::Result<()>,
+        mut read_expected: impl FnMut(&mut [u8]) -> io::Result<()>,
+    ) -> io::Result<u32> {
+        let mut retries = 0;
+
+        loop {
+            let result = write().and_then(|_| match self.verify && self.get_chunk_attempts() > 1 {
+                true => check_written_chunk(destination, offset, size, &mut read_expected),
+                false => Ok(()),
+            });
+
+            match result {
+                Ok(()) => return Ok(retries),
+                Err(error) if retries + 1 < self.get_chunk_attempts() => {
+                    retries += 1;
+                    warn!(offset, size, retry = retries, %error, "retrying chunk write");
+
+                    self.wait_before_retry(retries);
+                    if self.erase {
+                        destination.erase(offset, size, size)?;
+                    }
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Same as [`RetryPolicy::write_chunk`] for `chunk`, on the blocking thread pool, with the
+    /// niceness of `io_throttle`.
+    pub(crate) async fn write_chunk_async(
+        &self,
+        destination: &Arc<DestinationFile>,
+        offset: u64,
+        chunk: Vec<u8>,
+        io_throttle: &IoThrottle,
+    ) -> io::Result<u32> {
+        let retry_policy = self.clone();
+        let destination = destination.clone();
+        let io_throttle = io_throttle.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let _niceness = io_throttle.apply_niceness();
+            retry_policy.write_chunk(
+                &destination,
+                offset,
+                chunk.len(),
+                || destination.write_all_at(&chunk, offset),
+                |expected| {
+                    expected.copy_from_slice(&chunk);
+                    Ok(())
+                },
+            )
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+/// Logical block installed attempt after attempt, each time from its first byte, as the retry
+/// policy of its destination allows.
+pub(crate) struct LogicalBlockInstall<'a> {
+    pub logical_block_id: String,
+    pub digest: Option<String>,
+    pub signature: String,
+    pub destination: LogicalBlockDestination,
+    pub memory_mapping: &'a MemoryMapping,
+}
+
+impl LogicalBlockInstall<'_> {
+    /// Calls `install_attempt` until it succeeds or the retry policy gives up, as
+    /// [`LogicalBlockInstall::retry`] tells, and returns the report of the logical block.
+    ///
+    /// `install_attempt` is given the attempt number, counted from 1, whether the destination is
+    /// up to date and the phase durations to add to, starting from `phase_durations`. It returns
+    /// the status of the logical block along with the number of chunk writes retried so far.
+    pub fn run(
+        self,
+        is_up_to_date: bool,
+        mut phase_durations: PhaseDurations,
+        mut install_attempt: impl FnMut(
+            u32,
+            bool,
+            &mut PhaseDurations,
+        ) -> Result<(LogicalBlockStatus, u32), UpdateError>,
+    ) -> Result<LogicalBlockReport, UpdateError> {
+        let mut attempt = 1;
+
+        loop {
+            // Whatever the destination held before, it gets written again once retried
+            match install_attempt(attempt, is_up_to_date && attempt == 1, &mut phase_durations) {
+                Ok((status, chunk_retries)) => {
+                    return Ok(self.into_report(
+                        status,
+                        chunk_retries + attempt - 1,
+                        phase_durations,
+                    ))
+                }
+                Err(error) => self.retry(attempt, error)?,
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Gets the logical block ready to be written again after its `attempt`th attempt, counted
+    /// from 1, failed with `error`: waits, then erases the destination if the retry policy says
+    /// so. Gives `error` back when the policy doesn't retry it.
+    pub fn retry(&self, attempt: u32, error: UpdateError) -> Result<(), UpdateError> {
+        let retry_policy = self.destination.get_retry_policy();
+        if !retry_policy.retries_logical_block(attempt, &error) {
+            return Err(error);
+        }
+        warn!(attempt, %error, "retrying logical block write");
+
+        retry_policy.wait_before_retry(attempt);
+        if retry_policy.is_erasing() {
+            self.memory_mapping.record_slot_write();
+            self.destination
+                .erase()
+                .map_err(|error| self.erase_error(&error))?;
+        }
+        Ok(())
+    }
+
+    /// Same as [`LogicalBlockInstall::retry`], waiting and erasing without blocking the runtime.
+    pub async fn retry_async(&self, attempt: u32, error: UpdateError) -> Result<(), UpdateError> {
+        let retry_policy = self.destination.get_retry_policy();
+        if !retry_policy.retries_logical_block(attempt, &error) {
+            return Err(error);
+        }
+        warn!(attempt, %error, "retrying logical block write");
+
+        tokio::time::sleep(retry_policy.get_backoff(attempt)).await;
+        if retry_policy.is_erasing() {
+            self.memory_mapping.record_slot_write();
+            self.destination
+                .erase_async()
+                .await
+                .map_err(|error| self.erase_error(&error))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the report of the logical block installed with `status`, after `retries` chunk or
+    /// logical block writes were retried.
+    pub fn into_report(
+        self,
+        status: LogicalBlockStatus,
+        retries: u32,
+        phase_durations: PhaseDurations,
+    ) -> LogicalBlockReport {
+        LogicalBlockReport {
+            logical_block_id: self.logical_block_id,
+            status,
+            digest: self.digest,
+            signature: Some(self.signature),
+            destination_path: self.destination.get_path().to_string(),
+            written_bytes: match status {
+                LogicalBlockStatus::Skipped => 0,
+                _ => self.destination.get_size() as u64,
+            },
+            retries,
+            phase_durations,
+        }
+    }
+
+    fn erase_error(&self, error: &io::Error) -> UpdateError {
+        UpdateError::LogicalBlockWrite(
+            LogicalBlockError::new(
+                self.logical_block_id.clone(),
+                UpdatePhase::Write,
+                "Unable to erase destination before writing logical block again",
+            )
+            .caused_by(error),
+        )
+    }
+}
+
+fn check_written_chunk(
+    destination: &DestinationFile,
+    offset: u64,
+    size: usize,
+    read_expected: &mut impl FnMut(&mut [u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut written = vec![0; size];
+    let mut expected = vec![0; size];
+    destination.read_exact_at(&mut written, offset)?;
+    read_expected(&mut expected)?;
+
+    match written == expected {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Chunk doesn't read back as written",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::nand::ERASED_BYTE;
+
+    fn create_policy(granularity: RetryGranularity) -> RetryPolicy {
+        serde_json::from_value(serde_json::json!({
+            "max_attempts": 3,
+            "backoff_ms": 1,
+            "granularity": granularity,
+            "erase": true,
+            "verify": true,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn write_chunk_retries_failed_writes_test() {
+        let destination_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        fs::write(&destination_path, [0; 16]).unwrap();
+        let destination =
+            DestinationFile::create(destination_path.to_str().unwrap(), None).unwrap();
+        let chunk = [0xA5; 8];
+
+        // Fails once, then writes a corrupted chunk, then gets it right
+        let mut attempts = 0;
+        let retries = create_policy(RetryGranularity::Chunk)
+            .write_chunk(
+                &destination,
+                4,
+                chunk.len(),
+                || {
+                    attempts += 1;
+                    match attempts {
+                        1 => Err(io::Error::other("write failed")),
+                        2 => destination.write_all_at(&[0x5A; 8], 4),
+                        _ => destination.write_all_at(&chunk, 4),
+                    }
+                },
+                |expected| {
+                    expected.copy_from_slice(&chunk);
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(retries, 2);
+        let written = fs::read(&destination_path).unwrap();
+        assert_eq!(written[4..12], chunk);
+        assert_eq!(written[12..], [0; 4]);
+
+        // Out of attempts
+        let error = create_policy(RetryGranularity::Chunk)
+            .write_chunk(
+                &destination,
+                0,
+                chunk.len(),
+                || Err(io::Error::other("write failed")),
+                |_| Ok(()),
+            )
+            .unwrap_err();
+        assert_eq!(error.to_string(), "write failed");
+        assert_eq!(fs::read(&destination_path).unwrap()[..8], [ERASED_BYTE; 8]);
+    }
+
+    #[test]
+    fn retry_policy_test() {
+        let policy = create_policy(RetryGranularity::Block);
+        assert_eq!(policy.get_backoff(1), Duration::from_millis(1));
+        assert_eq!(policy.get_backoff(3), Duration::from_millis(4));
+        assert!(policy.check().is_ok());
+        assert!(RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        }
+        .check()
+        .is_err());
+
+        let write_error = UpdateError::LogicalBlockWrite(crate::reporting::LogicalBlockError::new(
+            "FD01".to_string(),
+            crate::reporting::UpdatePhase::Write,
+            "Unable to write chunk to destination",
+        ));
+        assert!(policy.retries_logical_block(2, &write_error));
+        assert!(!policy.retries_logical_block(3, &write_error));
+        assert!(!create_policy(RetryGranularity::Chunk).retries_logical_block(1, &write_error));
+    }
+}
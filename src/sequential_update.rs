@@ -5,3 +5,9 @@ mod crypto;
 mod memory;
 
 pub mod update_sequence;
+
+pub mod update_plan;
+
+pub mod installed_verification;
+
+pub mod bank_export;
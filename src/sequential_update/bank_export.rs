@@ -0,0 +1,389 @@
+use std::{error::Error, fs::File, io::Write};
+
+use base64::{engine::general_purpose, Engine};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rsa::Padding,
+    sha::Sha256,
+    sign::{RsaPssSaltlen, Signer},
+};
+use serde::Serialize;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    digest::to_hex,
+    manifest::INDEX_PATH,
+    memory_mapping::{LogicalBlockDestination, MemoryMapping},
+    reporting::{DocumentError, LogicalBlockError, UpdateError, UpdatePhase},
+};
+
+const LOGICAL_BLOCKS_DIRECTORY: &str = "logical_blocks/";
+const MANIFEST_PATH: &str = "logical_blocks/update_manifest.xml";
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Signature given to logical blocks exported without a signing key, which installing the
+/// archive rejects.
+const UNSIGNED_SIGNATURE: &str = "unsigned";
+
+/// Logical blocks of a bank captured into a software archive by [`export_bank`].
+#[derive(Debug, Serialize, PartialEq)]
+pub struct BankExport {
+    exported_bank: String,
+    logical_blocks: Vec<ExportedLogicalBlock>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ExportedLogicalBlock {
+    id: String,
+    short_name: String,
+    path_in_archive: String,
+    /// SHA-256 of the exported content, in lowercase hex.
+    digest: String,
+    signed: bool,
+}
+
+impl ExportedLogicalBlock {
+    pub fn get_id(&self) -> &str {
+        self.id.as_ref()
+    }
+
+    pub fn get_digest(&self) -> &str {
+        self.digest.as_ref()
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signed
+    }
+}
+
+impl BankExport {
+    pub fn get_exported_bank(&self) -> &str {
+        self.exported_bank.as_ref()
+    }
+
+    pub fn get_logical_blocks(&self) -> &[ExportedLogicalBlock] {
+        &self.logical_blocks
+    }
+}
+
+/// Writes what is installed in `bank` into a software archive at `archive_path`, which
+/// [`crate::sequencial_update`] can install back.
+///
+/// Each logical block is signed with the RSA private key found at `signing_key_path`. Without a
+/// key, the archive still records the digest of each logical block, but can't be installed.
+/// Entries are stored uncompressed, so that the multi-threaded strategy writes them straight from
+/// the mapped archive.
+pub fn export_bank(
+    memory_mapping_path: &str,
+    bank: &str,
+    archive_path: &str,
+    signing_key_path: Option<&str>,
+) -> Result<BankExport, UpdateError> {
+    let memory_mapping = MemoryMapping::from_slot(memory_mapping_path, bank)?;
+    let signing_key = signing_key_path.map(read_signing_key).transpose()?;
+
+    let archive_error = |description: &str, error: &(dyn Error + 'static)| {
+        UpdateError::InvalidArchive(
+            DocumentError::new(archive_path, UpdatePhase::Export, description).caused_by(error),
+        )
+    };
+    let file = File::create(archive_path)
+        .map_err(|error| archive_error("Unable to create archive", &error))?;
+
+    let mut archive = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    archive
+        .add_directory(LOGICAL_BLOCKS_DIRECTORY, options)
+        .map_err(|error| archive_error("Unable to write archive", &error))?;
+
+    let mut logical_blocks = Vec::new();
+    for (id, name, destination) in memory_mapping.get_logical_blocks() {
+        let path_in_archive = format!("{LOGICAL_BLOCKS_DIRECTORY}{id}.bin");
+        archive
+            .start_file(&path_in_archive, options)
+            .map_err(|error| archive_error("Unable to write archive", &error))?;
+
+        let (digest, signature) =
+            export_logical_block(id, destination, signing_key.as_ref(), &mut archive)?;
+
+        logical_blocks.push((
+            ExportedLogicalBlock {
+                id: id.to_string(),
+                short_name: name.to_string(),
+                path_in_archive,
+                digest,
+                signed: signature.is_some(),
+            },
+            signature.unwrap_or(UNSIGNED_SIGNATURE.to_string()),
+        ));
+    }
+
+    for (path, content) in [
+        (INDEX_PATH, get_index(&logical_blocks)),
+        (MANIFEST_PATH, get_manifest(&logical_blocks)),
+    ] {
+        archive
+            .start_file(path, options)
+            .and_then(|_| Ok(archive.write_all(content.as_bytes())?))
+            .map_err(|error| archive_error("Unable to write archive", &error))?;
+    }
+    archive
+        .finish()
+        .and_then(|file| Ok(file.sync_all()?))
+        .map_err(|error| archive_error("Unable to write archive", &error))?;
+
+    Ok(BankExport {
+        exported_bank: memory_mapping.get_targeted_bank().to_string(),
+        logical_blocks: logical_blocks
+            .into_iter()
+            .map(|(logical_block, _)| logical_block)
+            .collect(),
+    })
+}
+
+/// Copies the content of `destination` into the current archive entry and returns its digest,
+/// along with its signature when a signing key is given.
+fn export_logical_block(
+    id: &str,
+    destination: &LogicalBlockDestination,
+    signing_key: Option<&PKey<Private>>,
+    archive: &mut ZipWriter<File>,
+) -> Result<(String, Option<String>), UpdateError> {
+    let read_error = |offset: u64, error: &(dyn Error + 'static)| {
+        UpdateError::LogicalBlockRead(
+            LogicalBlockError::new(id, UpdatePhase::Export, "Unable to read destination")
+                .at_offset(offset)
+                .caused_by(error),
+        )
+    };
+
+    let file = destination
+        .open_file()
+        .map_err(|error| read_error(destination.get_offset(), &error))?;
+
+    let mut hasher = Sha256::new();
+    let mut signer = signing_key
+        .map(|signing_key| get_signer(id, signing_key))
+        .transpose()?;
+
+    let mut read_buffer = vec![0; CHUNK_SIZE];
+    let mut remaining_bytes = destination.get_size();
+    while remaining_bytes > 0 {
+        let offset = destination.get_offset() + (destination.get_size() - remaining_bytes) as u64;
+        let chunk = &mut read_buffer[..remaining_bytes.min(CHUNK_SIZE)];
+
+        file.read_exact_at(chunk, offset)
+            .map_err(|error| read_error(offset, &error))?;
+        hasher.update(chunk);
+        if let Some(signer) = signer.as_mut() {
+            signer
+                .update(chunk)
+                .map_err(|error| signing_error(id, &error))?;
+        }
+        archive.write_all(chunk).map_err(|error| {
+            UpdateError::LogicalBlockWrite(
+                LogicalBlockError::new(id, UpdatePhase::Export, "Unable to write archive entry")
+                    .at_offset(offset)
+                    .caused_by(&error),
+            )
+        })?;
+
+        remaining_bytes -= chunk.len();
+    }
+
+    let signature = match signer {
+        Some(signer) => Some(
+            signer
+                .sign_to_vec()
+                .map(|signature| general_purpose::STANDARD.encode(signature))
+                .map_err(|error| signing_error(id, &error))?,
+        ),
+        None => None,
+    };
+
+    Ok((to_hex(&hasher.finish()), signature))
+}
+
+fn read_signing_key(signing_key_path: &str) -> Result<PKey<Private>, UpdateError> {
+    let key_error = |error: &(dyn Error + 'static)| {
+        UpdateError::InvalidArchive(
+            DocumentError::new(
+                signing_key_path,
+                UpdatePhase::Export,
+                "Unable to read signing key",
+            )
+            .caused_by(error),
+        )
+    };
+
+    let key = std::fs::read(signing_key_path).map_err(|error| key_error(&error))?;
+    PKey::private_key_from_pem(&key).map_err(|error| key_error(&error))
+}
+
+/// Returns a signer matching what [`super::crypto::LogicalBlockVerifier`] verifies.
+fn get_signer<'a>(id: &str, signing_key: &'a PKey<Private>) -> Result<Signer<'a>, UpdateError> {
+    let configure = || -> Result<Signer<'a>, openssl::error::ErrorStack> {
+        let mut signer = Signer::new(MessageDigest::sha256(), signing_key)?;
+        signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+        signer.set_rsa_pss_saltlen(RsaPssSaltlen::custom(0))?;
+        signer.set_rsa_mgf1_md(MessageDigest::sha256())?;
+        Ok(signer)
+    };
+
+    configure().map_err(|error| signing_error(id, &error))
+}
+
+fn signing_error(id: &str, error: &(dyn Error + 'static)) -> UpdateError {
+    UpdateError::Signing(
+        LogicalBlockError::new(id, UpdatePhase::Export, "Unable to sign logical block")
+            .caused_by(error),
+    )
+}
+
+fn get_index(logical_blocks: &[(ExportedLogicalBlock, String)]) -> String {
+    let mut index = format!(
+        "<file_list xmlns=\"file_list\">\n    <file short_name=\"update_manifest\">\n        <path>{MANIFEST_PATH}</path>\n    </file>\n"
+    );
+    for (logical_block, _) in logical_blocks {
+        index.push_str(&format!(
+            "    <file short_name=\"{}\">\n        <path>{}</path>\n    </file>\n",
+            escape_xml(&logical_block.short_name),
+            escape_xml(&logical_block.path_in_archive)
+        ));
+    }
+    index.push_str("</file_list>\n");
+    index
+}
+
+fn get_manifest(logical_blocks: &[(ExportedLogicalBlock, String)]) -> String {
+    let mut manifest = String::from("<logical_blocks xmlns=\"logical_blocks\">\n");
+    for (logical_block, signature) in logical_blocks {
+        manifest.push_str(&format!(
+            "    <logical_block>\n        <id>{}</id>\n        <short_name>{}</short_name>\n        <signature>{signature}</signature>\n        <digest>{}</digest>\n    </logical_block>\n",
+            escape_xml(&logical_block.id),
+            escape_xml(&logical_block.short_name),
+            logical_block.digest
+        ));
+    }
+    manifest.push_str("</logical_blocks>\n");
+    manifest
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+    };
+
+    use super::*;
+    use crate::reporting::LogicalBlockStatus;
+    use crate::sequential_update::update_sequence::sequencial_update;
+    use crate::test_utils::MappingBuilder;
+
+    #[test]
+    fn exported_bank_can_be_installed_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let swapped_mapping_path = MappingBuilder::new(destination_dir.path())
+            .swapped()
+            .build();
+        let archive_path = destination_dir.path().join("bank_a.zip");
+        let archive_path = archive_path.to_str().unwrap();
+
+        let update_report =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+        let export = export_bank(
+            &mapping_path,
+            "bank_a",
+            archive_path,
+            Some("./resources/test/test_private_key.pem"),
+        )
+        .unwrap();
+        assert_eq!(export.get_exported_bank(), "bank_a");
+        assert_eq!(export.get_logical_blocks().len(), 9);
+        assert!(export
+            .get_logical_blocks()
+            .iter()
+            .all(ExportedLogicalBlock::is_signed));
+        for logical_block in export.get_logical_blocks() {
+            let installed_digest = update_report
+                .logical_blocks
+                .iter()
+                .find(|report| report.logical_block_id == logical_block.get_id())
+                .and_then(|report| report.digest.as_deref());
+            assert_eq!(installed_digest, Some(logical_block.get_digest()));
+        }
+
+        // Installing the export in bank B reproduces bank A
+        let reinstall_report = sequencial_update(&swapped_mapping_path, archive_path).unwrap();
+        assert_eq!(
+            reinstall_report
+                .get_logical_blocks_with_status(LogicalBlockStatus::Written)
+                .len(),
+            9
+        );
+    }
+
+    #[test]
+    fn unsigned_export_is_rejected_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let swapped_mapping_path = MappingBuilder::new(destination_dir.path())
+            .swapped()
+            .build();
+        let archive_path = destination_dir.path().join("bank_a.zip");
+        let archive_path = archive_path.to_str().unwrap();
+
+        sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+        let export = export_bank(&mapping_path, "bank_a", archive_path, None).unwrap();
+        assert!(!export.get_logical_blocks()[0].is_signed());
+
+        let error = sequencial_update(&swapped_mapping_path, archive_path).unwrap_err();
+        assert_eq!(error.code(), 400);
+
+        let error = export_bank(
+            &mapping_path,
+            "bank_a",
+            archive_path,
+            Some("./resources/test/missing_key.pem"),
+        )
+        .unwrap_err();
+        assert_eq!(error.phase(), UpdatePhase::Export);
+    }
+
+    #[test]
+    fn export_signing_failure_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let archive_path = destination_dir.path().join("bank_a.zip");
+        let archive_path = archive_path.to_str().unwrap();
+
+        // The manifest signatures use RSA-PSS padding, which an EC key can't sign with
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec_key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let ec_key_path = destination_dir.path().join("ec_key.pem");
+        std::fs::write(&ec_key_path, ec_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+        let error = export_bank(
+            &mapping_path,
+            "bank_a",
+            archive_path,
+            Some(ec_key_path.to_str().unwrap()),
+        )
+        .unwrap_err();
+        assert_eq!(error.code(), 900);
+        assert_eq!(error.get_logical_block_id(), Some("FD01"));
+    }
+}
@@ -1,7 +1,4 @@
-use std::{
-    fs::File,
-    io::{Read, Seek},
-};
+use std::{fs::File, io::Read};
 
 use base64::{engine::general_purpose, Engine};
 use openssl::{
@@ -12,15 +9,18 @@ use openssl::{
 };
 
 use crate::{
-    reporting::{LogicalBlockError, UpdateError},
-    sequential_update::memory::LogicalBlockDestination,
+    chunked_io::DestinationFile,
+    memory_mapping::LogicalBlockDestination,
+    reporting::{LogicalBlockError, UpdateError, UpdatePhase, UpdateProgress},
     sequential_update::software_archive::LogicalBlockInfo,
+    update_control::UpdateControl,
 };
 
 pub struct LogicalBlockVerifier {
     logical_block: LogicalBlockDestination,
     logical_block_info: LogicalBlockInfo,
     public_key: PKey<Public>,
+    update_control: UpdateControl,
 }
 
 impl LogicalBlockVerifier {
@@ -34,9 +34,16 @@ impl LogicalBlockVerifier {
             logical_block: logical_block_location,
             logical_block_info,
             public_key,
+            update_control: UpdateControl::new(),
         }
     }
 
+    /// Lets `update_control` cancel or pause the verification between chunks.
+    pub(crate) fn controlled_by(mut self, update_control: &UpdateControl) -> LogicalBlockVerifier {
+        self.update_control = update_control.clone();
+        self
+    }
+
     fn get_public_key() -> PKey<Public> {
         let mut public_key = Vec::new();
         File::open("./resources/test/test_public_key.pem")
@@ -49,20 +56,33 @@ impl LogicalBlockVerifier {
     pub(crate) fn verify(&self) -> Result<bool, UpdateError> {
         let mut verifier = self.get_verifier();
 
-        let logical_block_file = self.get_logical_block_file();
+        let logical_block_file = self.get_logical_block_file()?;
 
         self.update_verifier_with_logical_block_content(&mut verifier, logical_block_file)?;
 
         let decoded_signature = general_purpose::STANDARD
             .decode(self.logical_block_info.get_signature())
-            .unwrap();
+            .map_err(|error| {
+                UpdateError::VerificationError(
+                    LogicalBlockError::new(
+                        self.logical_block_info.get_id(),
+                        UpdatePhase::Verify,
+                        "Unable to decode signature",
+                    )
+                    .caused_by(&error),
+                )
+            })?;
 
         match verifier.verify(&decoded_signature) {
             Ok(n) => Ok(n),
-            Err(_) => Err(UpdateError::VerificationError(LogicalBlockError {
-                logical_block_id: self.logical_block_info.get_id(),
-                description: "todo!()".to_string(),
-            })),
+            Err(error) => Err(UpdateError::VerificationError(
+                LogicalBlockError::new(
+                    self.logical_block_info.get_id(),
+                    UpdatePhase::Verify,
+                    "Unable to verify signature",
+                )
+                .caused_by(&error),
+            )),
         }
     }
 
@@ -78,21 +98,29 @@ impl LogicalBlockVerifier {
         verifier
     }
 
-    fn get_logical_block_file(&self) -> File {
-        let mut logical_block_file = File::open(self.logical_block.get_path()).unwrap();
-        logical_block_file
-            .seek(std::io::SeekFrom::Start(self.logical_block.get_offset()))
-            .unwrap();
-        logical_block_file
+    fn get_logical_block_file(&self) -> Result<DestinationFile, UpdateError> {
+        let read_error = |error: std::io::Error| {
+            UpdateError::LogicalBlockRead(
+                LogicalBlockError::new(
+                    self.logical_block_info.get_id(),
+                    UpdatePhase::Verify,
+                    "Unable to open destination",
+                )
+                .at_offset(self.logical_block.get_offset())
+                .caused_by(&error),
+            )
+        };
+
+        self.logical_block.open_file().map_err(read_error)
     }
 
     fn update_verifier_with_logical_block_content(
         &self,
         verifier: &mut Verifier<'_>,
-        mut logical_block_file: File,
+        logical_block_file: DestinationFile,
     ) -> Result<(), UpdateError> {
-        const CHUNK_SIZE: usize = 4096;
-        let mut read_buffer = [0; CHUNK_SIZE];
+        let chunk_size = self.logical_block.get_buffer_size();
+        let mut read_buffer = vec![0; chunk_size];
 
         let total_bytes_to_read = self.logical_block.get_size();
         let mut total_bytes_read = 0;
@@ -103,23 +131,38 @@ impl LogicalBlockVerifier {
             if remaining_bytes == 0 {
                 return Ok(());
             }
+            if !self.update_control.proceed() {
+                return Err(UpdateError::Cancelled(
+                    UpdateProgress::new(UpdatePhase::Verify)
+                        .in_logical_block(self.logical_block_info.get_id()),
+                )
+                .at_offset(self.logical_block.get_offset() + total_bytes_read as u64));
+            }
 
-            let bytes_to_read = if remaining_bytes >= CHUNK_SIZE {
-                CHUNK_SIZE
+            let bytes_to_read = if remaining_bytes >= chunk_size {
+                chunk_size
             } else {
                 remaining_bytes
             };
 
-            match logical_block_file.read_exact(&mut read_buffer[..bytes_to_read]) {
+            match logical_block_file.read_exact_at(
+                &mut read_buffer[..bytes_to_read],
+                self.logical_block.get_offset() + total_bytes_read as u64,
+            ) {
                 Ok(_) => {
                     verifier.update(&read_buffer[..bytes_to_read]).unwrap();
                     total_bytes_read += bytes_to_read;
                 }
-                Err(_) => {
-                    return Err(UpdateError::LogicalBlockRead(LogicalBlockError {
-                        logical_block_id: self.logical_block_info.get_id(),
-                        description: "todo!()".to_string(),
-                    }))
+                Err(error) => {
+                    return Err(UpdateError::LogicalBlockRead(
+                        LogicalBlockError::new(
+                            self.logical_block_info.get_id(),
+                            UpdatePhase::Verify,
+                            "Unable to read back chunk from destination",
+                        )
+                        .at_offset(self.logical_block.get_offset() + total_bytes_read as u64)
+                        .caused_by(&error),
+                    ))
                 }
             }
         }
@@ -0,0 +1,274 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::memory_mapping::MemoryMapping;
+use crate::reporting::UpdateError;
+use crate::sequential_update::crypto::LogicalBlockVerifier;
+use crate::sequential_update::software_archive::{LogicalBlockInfo, SoftwareArchive};
+
+/// Outcome of checking an installed bank against a software archive, without writing to it.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct InstalledVerification {
+    verified_bank: String,
+    logical_blocks: Vec<InstalledLogicalBlock>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct InstalledLogicalBlock {
+    id: String,
+    #[serde(flatten)]
+    verdict: InstalledLogicalBlockVerdict,
+}
+
+#[derive(Debug, Serialize, PartialEq, Clone)]
+#[serde(tag = "verdict", rename_all = "snake_case")]
+pub enum InstalledLogicalBlockVerdict {
+    /// The installed content matches the digest and signature of the archive.
+    Intact,
+    /// The installed content doesn't match the digest given by the manifest.
+    DigestMismatch,
+    /// The installed content doesn't match the signature given by the manifest.
+    SignatureMismatch,
+    /// The logical block has no destination in the verified bank.
+    Unmapped,
+    /// The destination of the logical block couldn't be read.
+    Unreadable { description: String },
+}
+
+impl InstalledLogicalBlock {
+    fn from(
+        logical_block_info: &LogicalBlockInfo,
+        memory_mapping: &MemoryMapping,
+    ) -> Result<InstalledLogicalBlock, UpdateError> {
+        Ok(InstalledLogicalBlock {
+            id: logical_block_info.get_id(),
+            verdict: Self::verify_destination(logical_block_info, memory_mapping)?,
+        })
+    }
+
+    fn verify_destination(
+        logical_block_info: &LogicalBlockInfo,
+        memory_mapping: &MemoryMapping,
+    ) -> Result<InstalledLogicalBlockVerdict, UpdateError> {
+        let destination =
+            match memory_mapping.get_logical_block_destination(&logical_block_info.get_id()) {
+                Ok(destination) => destination.clone(),
+                Err(UpdateError::MissingLogicalBlock(_)) => {
+                    return Ok(InstalledLogicalBlockVerdict::Unmapped)
+                }
+                Err(error) => return Err(error),
+            };
+
+        if let Some(digest) = logical_block_info.get_digest() {
+            match destination.get_content_digest() {
+                Some(content_digest) if content_digest == digest => {}
+                Some(_) => return Ok(InstalledLogicalBlockVerdict::DigestMismatch),
+                None => {
+                    return Ok(InstalledLogicalBlockVerdict::Unreadable {
+                        description: format!(
+                            "Unable to read {} bytes at offset {} of {}",
+                            destination.get_size(),
+                            destination.get_offset(),
+                            destination.get_path()
+                        ),
+                    })
+                }
+            }
+        }
+
+        match LogicalBlockVerifier::from(destination, logical_block_info.clone()).verify() {
+            Ok(true) => Ok(InstalledLogicalBlockVerdict::Intact),
+            Ok(false) => Ok(InstalledLogicalBlockVerdict::SignatureMismatch),
+            Err(error @ UpdateError::LogicalBlockRead(_)) => {
+                Ok(InstalledLogicalBlockVerdict::Unreadable {
+                    description: error.to_string(),
+                })
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        self.id.as_ref()
+    }
+
+    pub fn get_verdict(&self) -> &InstalledLogicalBlockVerdict {
+        &self.verdict
+    }
+}
+
+impl InstalledVerification {
+    pub fn from(
+        software_archive: &SoftwareArchive,
+        memory_mapping: &MemoryMapping,
+    ) -> Result<InstalledVerification, UpdateError> {
+        Ok(InstalledVerification {
+            verified_bank: memory_mapping.get_targeted_bank().to_string(),
+            logical_blocks: software_archive
+                .get_logical_blocks_info()
+                .iter()
+                .map(|logical_block_info| {
+                    InstalledLogicalBlock::from(logical_block_info, memory_mapping)
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn get_verified_bank(&self) -> &str {
+        self.verified_bank.as_ref()
+    }
+
+    pub fn get_logical_blocks(&self) -> &[InstalledLogicalBlock] {
+        &self.logical_blocks
+    }
+
+    /// Returns whether every logical block of the archive is intact in the verified bank.
+    pub fn is_intact(&self) -> bool {
+        self.logical_blocks
+            .iter()
+            .all(|logical_block| logical_block.verdict == InstalledLogicalBlockVerdict::Intact)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for InstalledLogicalBlockVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstalledLogicalBlockVerdict::Intact => write!(f, "intact"),
+            InstalledLogicalBlockVerdict::DigestMismatch => write!(f, "digest mismatch"),
+            InstalledLogicalBlockVerdict::SignatureMismatch => write!(f, "signature mismatch"),
+            InstalledLogicalBlockVerdict::Unmapped => {
+                write!(f, "no destination in the memory mapping")
+            }
+            InstalledLogicalBlockVerdict::Unreadable { description } => {
+                write!(f, "unreadable ({description})")
+            }
+        }
+    }
+}
+
+impl fmt::Display for InstalledVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Verification of {}:", self.verified_bank)?;
+
+        for logical_block in &self.logical_blocks {
+            writeln!(f, "  0x{}: {}", logical_block.id, logical_block.verdict)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the logical blocks installed in `bank` still match the software archive, without
+/// writing to the device.
+pub fn verify_installed(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    bank: &str,
+) -> Result<InstalledVerification, UpdateError> {
+    let software_archive = SoftwareArchive::from(software_archive_path)?;
+
+    let memory_mapping = MemoryMapping::from_slot(memory_mapping_path, bank)?;
+
+    InstalledVerification::from(&software_archive, &memory_mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{Seek, SeekFrom, Write},
+    };
+
+    use super::*;
+    use crate::sequential_update::update_sequence::sequencial_update;
+    use crate::test_utils::MappingBuilder;
+
+    #[test]
+    fn verify_installed_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+        let verification = verify_installed(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            "bank_a",
+        )
+        .unwrap();
+        assert_eq!(verification.get_verified_bank(), "bank_a");
+        assert_eq!(verification.get_logical_blocks().len(), 9);
+        assert!(verification.is_intact());
+
+        let never_installed = verify_installed(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            "bank_b",
+        )
+        .unwrap();
+        assert!(never_installed
+            .get_logical_blocks()
+            .iter()
+            .all(|logical_block| {
+                logical_block.get_verdict() == &InstalledLogicalBlockVerdict::DigestMismatch
+            }));
+
+        std::fs::remove_file(destination_dir.path().join("mtd_b")).unwrap();
+        let missing_destination = verify_installed(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            "bank_b",
+        )
+        .unwrap();
+        assert!(matches!(
+            missing_destination.get_logical_blocks()[0].get_verdict(),
+            InstalledLogicalBlockVerdict::Unreadable { .. }
+        ));
+
+        let unknown_bank = verify_installed(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            "bank_c",
+        )
+        .unwrap_err();
+        assert_eq!(unknown_bank.code(), 200);
+    }
+
+    #[test]
+    fn verify_installed_detects_corruption_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+        let mut destination = File::options()
+            .write(true)
+            .open(destination_dir.path().join("mtd_a"))
+            .unwrap();
+        destination.seek(SeekFrom::Start(16)).unwrap();
+        destination.write_all(b"bit rot").unwrap();
+
+        let verification = verify_installed(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            "bank_a",
+        )
+        .unwrap();
+        assert!(!verification.is_intact());
+
+        let corrupted: Vec<_> = verification
+            .get_logical_blocks()
+            .iter()
+            .filter(|logical_block| {
+                logical_block.get_verdict() != &InstalledLogicalBlockVerdict::Intact
+            })
+            .map(InstalledLogicalBlock::get_id)
+            .collect();
+        assert_eq!(corrupted, vec!["FD01"]);
+        assert!(verification.to_json().contains("\"verdict\""));
+    }
+}
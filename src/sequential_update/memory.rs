@@ -1,72 +1,23 @@
-use serde::Deserialize;
 use std::{
-    collections::HashMap,
     fs::File,
-    io::{Read, Seek, Write},
+    os::unix::fs::FileExt,
+    time::{Duration, Instant},
 };
 
-use crate::sequential_update::software_archive;
 use crate::{
-    reporting::{LogicalBlockError, UpdateError},
+    chunked_io::{read_chunk_within, DestinationFile},
+    memory_mapping::LogicalBlockDestination,
+    reporting::{LogicalBlockError, UpdateError, UpdatePhase, UpdateProgress},
     sequential_update::software_archive::LogicalBlockReader,
+    update_control::UpdateControl,
 };
 
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct LogicalBlockCfg {
-    pub logical_blocks: Vec<LogicalBlock>,
-}
-
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct LogicalBlock {
-    pub name: String,
-    pub id: String,
-    pub destination: Banks,
-}
-impl LogicalBlock {
-    fn get_location_from_bank(
-        &self,
-        targeted_bank: &str,
-    ) -> Result<LogicalBlockDestination, UpdateError> {
-        match targeted_bank {
-            "bank_a" => Ok(self.destination.bank_a.clone()),
-            "bank_b" => Ok(self.destination.bank_b.clone()),
-            other => panic!("{} is not a supported bank", other),
-        }
-    }
-}
-
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct Banks {
-    pub bank_a: LogicalBlockDestination,
-    pub bank_b: LogicalBlockDestination,
-}
-
-#[derive(Debug, Deserialize, PartialEq, Clone)]
-
-pub struct LogicalBlockDestination {
-    path: String,
-    offset: u64,
-    size: usize,
-}
-
-impl LogicalBlockDestination {
-    pub fn get_path(&self) -> &str {
-        self.path.as_ref()
-    }
-
-    pub fn get_offset(&self) -> u64 {
-        self.offset
-    }
-
-    pub fn get_size(&self) -> usize {
-        self.size
-    }
-}
-
 pub struct LogicalBlockWriter<'a> {
     logical_block_destination: LogicalBlockDestination,
     logical_block_reader: LogicalBlockReader<'a>,
-    destination_file: File,
+    destination_file: DestinationFile,
+    decompression_duration: Duration,
+    retries: u32,
 }
 
 impl<'a> LogicalBlockWriter<'a> {
@@ -74,17 +25,24 @@ impl<'a> LogicalBlockWriter<'a> {
         logical_block_reader: LogicalBlockReader<'a>,
         logical_block_destination: LogicalBlockDestination,
     ) -> Result<LogicalBlockWriter<'a>, UpdateError> {
-        let mut file = File::options()
-            .write(true)
-            .open(&logical_block_destination.path)
-            .unwrap();
-        file.seek(std::io::SeekFrom::Start(logical_block_destination.offset))
-            .unwrap();
+        // Read back when retried chunks are verified
+        let file = logical_block_destination.create_file().map_err(|error| {
+            UpdateError::LogicalBlockWrite(
+                LogicalBlockError::new(
+                    logical_block_reader.get_logical_block_id(),
+                    UpdatePhase::Write,
+                    "Unable to open destination",
+                )
+                .caused_by(&error),
+            )
+        })?;
 
         Ok(LogicalBlockWriter {
             logical_block_destination,
             logical_block_reader,
             destination_file: file,
+            decompression_duration: Duration::ZERO,
+            retries: 0,
         })
     }
 
@@ -92,117 +50,206 @@ impl<'a> LogicalBlockWriter<'a> {
         self.logical_block_destination.get_size()
     }
 
-    pub fn write(&mut self) -> Result<usize, UpdateError> {
-        let mut read_buffer = [0; 4096];
-        let mut total_copied_bytes = 0;
+    /// Returns the time spent reading chunks out of the archive so far.
+    pub fn get_decompression_duration(&self) -> Duration {
+        self.decompression_duration
+    }
+
+    /// Returns the number of chunk writes retried so far.
+    pub fn get_retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn write(&mut self, update_control: &UpdateControl) -> Result<usize, UpdateError> {
+        let mut chunk_buffer = vec![0; self.logical_block_destination.get_buffer_size()];
+        let mut total_copied_bytes: u64 = 0;
+
+        let logical_block_size = self
+            .logical_block_reader
+            .get_logical_block_info()
+            .get_size();
+        if logical_block_size != self.get_size() as u64 {
+            return Err(UpdateError::LogicalBlockSize(LogicalBlockError::new(
+                self.logical_block_reader.get_logical_block_id(),
+                UpdatePhase::Write,
+                format!(
+                    "Number of bytes to write ({logical_block_size}) doesn't match the expected logical block size ({})",
+                    self.get_size()
+                ),
+            )));
+        }
+
+        let _niceness = update_control.get_io_throttle().apply_niceness();
 
         loop {
-            let copied_bytes_count = self.copy_chunk(&mut read_buffer)?;
+            let chunk_size = self
+                .logical_block_destination
+                .get_chunk_size(total_copied_bytes as usize);
+
+            let copied_bytes_count = match self.logical_block_reader.get_stored_content() {
+                Some(stored_content) => {
+                    self.copy_stored_chunk(stored_content, total_copied_bytes, chunk_size)
+                }
+                None => self.copy_chunk(&mut chunk_buffer[..chunk_size], total_copied_bytes),
+            }
+            .map_err(|error| {
+                error.at_offset(self.logical_block_destination.get_offset() + total_copied_bytes)
+            })?;
             if copied_bytes_count == 0 {
                 break;
             } else {
-                total_copied_bytes += copied_bytes_count;
+                total_copied_bytes += copied_bytes_count as u64;
+                update_control
+                    .get_io_throttle()
+                    .throttle(copied_bytes_count);
+            }
+            if !update_control.proceed() {
+                return Err(UpdateError::Cancelled(
+                    UpdateProgress::new(UpdatePhase::Write)
+                        .in_logical_block(self.logical_block_reader.get_logical_block_id()),
+                )
+                .at_offset(self.logical_block_destination.get_offset() + total_copied_bytes));
             }
         }
 
-        Ok(total_copied_bytes)
+        Ok(total_copied_bytes as usize)
     }
 
-    fn copy_chunk(&mut self, chunk_buffer: &mut [u8]) -> Result<usize, UpdateError> {
-        let read_bytes = self.read_chunk_from_logical_block(chunk_buffer)?;
+    /// Copies a chunk of a logical block stored uncompressed in the archive, within the kernel
+    /// unless the destination is on a NAND device.
+    fn copy_stored_chunk(
+        &mut self,
+        (archive_file, content_offset): (&File, u64),
+        copied_bytes: u64,
+        chunk_size: usize,
+    ) -> Result<usize, UpdateError> {
+        let remaining_bytes = self
+            .logical_block_reader
+            .get_logical_block_info()
+            .get_size()
+            .saturating_sub(copied_bytes);
+        let chunk_size = chunk_size.min(remaining_bytes as usize);
+        if chunk_size == 0 {
+            return Ok(0);
+        }
+
+        let destination_offset = self.logical_block_destination.get_offset() + copied_bytes;
+        let source_offset = content_offset + copied_bytes;
+
+        self.retries += self
+            .logical_block_destination
+            .get_retry_policy()
+            .write_chunk(
+                &self.destination_file,
+                destination_offset,
+                chunk_size,
+                || {
+                    self.destination_file.copy_from(
+                        archive_file,
+                        source_offset,
+                        destination_offset,
+                        chunk_size,
+                    )
+                },
+                |expected| archive_file.read_exact_at(expected, source_offset),
+            )
+            .map_err(|error| {
+                UpdateError::LogicalBlockWrite(
+                    LogicalBlockError::new(
+                        self.logical_block_reader.get_logical_block_id(),
+                        UpdatePhase::Write,
+                        "Unable to copy stored chunk to destination",
+                    )
+                    .caused_by(&error),
+                )
+            })?;
+        Ok(chunk_size)
+    }
+
+    fn copy_chunk(
+        &mut self,
+        chunk_buffer: &mut [u8],
+        copied_bytes: u64,
+    ) -> Result<usize, UpdateError> {
+        let remaining_bytes = self.get_size() - copied_bytes as usize;
+        let read_bytes = self.read_chunk_from_logical_block(chunk_buffer, remaining_bytes)?;
 
-        let written_bytes = self.write_chunk_to_destination(&mut chunk_buffer[..read_bytes])?;
+        let written_bytes =
+            self.write_chunk_to_destination(&chunk_buffer[..read_bytes], copied_bytes)?;
 
         match written_bytes == read_bytes {
             true => Ok(written_bytes),
-            false => Err(UpdateError::LogicalBlockWrite(LogicalBlockError {
-                logical_block_id: self.logical_block_reader.get_logical_block_id(),
-                description: "todo!()".to_string(),
-            })),
+            false => Err(UpdateError::LogicalBlockWrite(LogicalBlockError::new(
+                self.logical_block_reader.get_logical_block_id(),
+                UpdatePhase::Write,
+                format!("Chunk copy error: number of bytes read ({read_bytes}) doesn't match the number of bytes written ({written_bytes})"),
+            ))),
         }
     }
 
     fn read_chunk_from_logical_block(
         &mut self,
         chunk_buffer: &mut [u8],
+        remaining_bytes: usize,
     ) -> Result<usize, UpdateError> {
-        match self.logical_block_reader.read(chunk_buffer) {
+        let read_start = Instant::now();
+        let read_result = read_chunk_within(
+            &mut self.logical_block_reader,
+            chunk_buffer,
+            remaining_bytes,
+        );
+        self.decompression_duration += read_start.elapsed();
+
+        match read_result {
             Ok(n) => Ok(n),
-            Err(_) => Err(UpdateError::LogicalBlockRead(LogicalBlockError {
-                logical_block_id: self.logical_block_reader.get_logical_block_id(),
-                description: "todo!()".to_string(),
-            })),
+            Err(error) => Err(UpdateError::LogicalBlockRead(
+                LogicalBlockError::new(
+                    self.logical_block_reader.get_logical_block_id(),
+                    UpdatePhase::Write,
+                    "Unable to read chunk from source",
+                )
+                .caused_by(&error),
+            )),
         }
     }
 
+    /// Writes the chunk `copied_bytes` into the destination region, retrying as the destination
+    /// allows.
     fn write_chunk_to_destination(
         &mut self,
-        chunk_buffer: &mut [u8],
+        chunk_buffer: &[u8],
+        copied_bytes: u64,
     ) -> Result<usize, UpdateError> {
-        match self.destination_file.write(chunk_buffer) {
-            Ok(n) => Ok(n),
-            Err(_) => Err(UpdateError::LogicalBlockWrite(LogicalBlockError {
-                logical_block_id: self.logical_block_reader.get_logical_block_id(),
-                description: "todo!()".to_string(),
-            })),
+        if chunk_buffer.is_empty() {
+            return Ok(0);
         }
-    }
-}
-pub struct MemoryMapping {
-    logical_blocks: HashMap<String, LogicalBlockDestination>,
-}
-
-impl MemoryMapping {
-    pub fn from(mapping_path: &str) -> Result<MemoryMapping, UpdateError> {
-        let mapping_file = File::open(mapping_path).unwrap();
-        let lb_cfg: LogicalBlockCfg = serde_json::from_reader(mapping_file).unwrap();
-
-        let targeted_bank = Self::get_target_bank()?;
-        let target_bank_mapping =
-            lb_cfg
-                .logical_blocks
-                .iter()
-                .fold(HashMap::new(), |mut map, lb| {
-                    let location = lb.get_location_from_bank(&targeted_bank).unwrap();
-
-                    map.insert(lb.id.clone(), location);
-                    map
-                });
-
-        Ok(MemoryMapping {
-            logical_blocks: target_bank_mapping,
-        })
-    }
-
-    pub fn get_logical_block_writer(
-        &self,
-        logical_block: &software_archive::LogicalBlockInfo,
-    ) -> Result<LogicalBlockDestination, UpdateError> {
-        if let Some(location) = self.logical_blocks.get(&logical_block.get_id()) {
-            Ok(location.clone())
-        } else {
-            Err(UpdateError::MissingLogicalBlock(LogicalBlockError {
-                logical_block_id: logical_block.get_id(),
-                description: "todo!()".to_string(),
-            }))
-        }
-    }
-
-    fn get_target_bank() -> Result<String, UpdateError> {
-        Ok("bank_a".to_string())
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn real_mapping_test() {
-        let mapping = MemoryMapping::from("./resources/test/test_lb_cfg.json").unwrap();
-
-        for (id, location) in mapping.logical_blocks {
-            println!("id: {}, location: {:#?}", id, location);
+        let offset = self.logical_block_destination.get_offset() + copied_bytes;
+
+        match self
+            .logical_block_destination
+            .get_retry_policy()
+            .write_chunk(
+                &self.destination_file,
+                offset,
+                chunk_buffer.len(),
+                || self.destination_file.write_all_at(chunk_buffer, offset),
+                |expected| {
+                    expected.copy_from_slice(chunk_buffer);
+                    Ok(())
+                },
+            ) {
+            Ok(retries) => {
+                self.retries += retries;
+                Ok(chunk_buffer.len())
+            }
+            Err(error) => Err(UpdateError::LogicalBlockWrite(
+                LogicalBlockError::new(
+                    self.logical_block_reader.get_logical_block_id(),
+                    UpdatePhase::Write,
+                    "Unable to write chunk to destination",
+                )
+                .caused_by(&error),
+            )),
         }
     }
 }
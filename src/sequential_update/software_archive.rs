@@ -1,23 +1,43 @@
-use std::{fmt, fs::File, io::Read};
+use std::{error::Error, fmt, fs::File, io::Read};
 
-use zip::{read::ZipFile, ZipArchive};
+use tracing::info_span;
+use zip::{read::ZipFile, CompressionMethod, ZipArchive};
 
-use crate::reporting::UpdateError;
+use crate::{
+    manifest::{ArchiveIndex, UpdateManifest, INDEX_PATH},
+    reporting::{invalid_archive, UpdateError, UpdatePhase},
+};
 
-const MANIFEST_XML_NAMESPACE: &str = "logical_blocks";
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LogicalBlockInfo {
     id: String,
     name: String,
     signature: String,
     path_in_archive: String,
+    size: u64,
+    digest: Option<String>,
 }
 impl LogicalBlockInfo {
     pub(crate) fn get_id(&self) -> String {
         self.id.clone()
     }
 
+    pub fn get_name(&self) -> &str {
+        self.name.as_ref()
+    }
+
+    pub fn get_path_in_archive(&self) -> &str {
+        self.path_in_archive.as_ref()
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn get_digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
     pub fn get_signature(&self) -> String {
         self.signature.clone()
     }
@@ -36,6 +56,7 @@ impl fmt::Display for LogicalBlockInfo {
 pub struct LogicalBlockReader<'a> {
     logical_block: LogicalBlockInfo,
     file: ZipFile<'a>,
+    archive_file: &'a File,
 }
 
 impl<'a> LogicalBlockReader<'a> {
@@ -43,6 +64,15 @@ impl<'a> LogicalBlockReader<'a> {
         self.logical_block.get_id()
     }
 
+    /// Returns the archive file along with the offset of the logical block in it when the
+    /// logical block is stored uncompressed, so that it can be copied as is.
+    pub(crate) fn get_stored_content(&self) -> Option<(&'a File, u64)> {
+        match self.file.compression() {
+            CompressionMethod::Stored => Some((self.archive_file, self.file.data_start())),
+            _ => None,
+        }
+    }
+
     pub fn get_logical_block_info(&self) -> &LogicalBlockInfo {
         &self.logical_block
     }
@@ -63,15 +93,17 @@ impl<'a> fmt::Display for LogicalBlockReader<'a> {
 #[derive(Debug)]
 pub struct SoftwareArchive {
     archive: ZipArchive<File>,
+    /// Second handle on the archive, which stored logical blocks are copied from.
+    archive_file: File,
     logical_blocks: Vec<LogicalBlockInfo>,
 }
 
 impl SoftwareArchive {
     pub fn from(archive_path: &str) -> Result<SoftwareArchive, UpdateError> {
-        let zipfile = File::open(archive_path).unwrap();
-        let archive = ZipArchive::new(zipfile).unwrap();
+        let (archive, archive_file) = Self::open(archive_path)?;
         let mut archive = SoftwareArchive {
             archive,
+            archive_file,
             logical_blocks: vec![],
         };
 
@@ -79,78 +111,107 @@ impl SoftwareArchive {
         Ok(archive)
     }
 
+    fn open(archive_path: &str) -> Result<(ZipArchive<File>, File), UpdateError> {
+        let _archive_open = info_span!("archive_open", path = archive_path).entered();
+
+        let zipfile = File::open(archive_path).map_err(|error| {
+            invalid_archive(
+                archive_path,
+                UpdatePhase::Index,
+                "Unable to open archive",
+                &error,
+            )
+        })?;
+        let archive_file = zipfile.try_clone().map_err(|error| {
+            invalid_archive(
+                archive_path,
+                UpdatePhase::Index,
+                "Unable to open archive",
+                &error,
+            )
+        })?;
+        let archive = ZipArchive::new(zipfile).map_err(|error| {
+            invalid_archive(
+                archive_path,
+                UpdatePhase::Index,
+                "Unable to read archive",
+                &error,
+            )
+        })?;
+        Ok((archive, archive_file))
+    }
+
     fn index_logical_blocks(&mut self) -> Result<(), UpdateError> {
         let index = self.get_index()?;
         let manifest = self.get_manifest(&index)?;
-        self.index_logical_blocks_from_manifest_and_index(&manifest, &index)?;
+        self.index_logical_blocks_from_manifest(manifest)?;
         Ok(())
     }
 
-    fn get_index(&mut self) -> Result<minidom::Element, UpdateError> {
-        let index = self.get_file_content("index.xml")?;
-        let index = index.parse().unwrap();
-        Ok(index)
-    }
+    fn get_index(&mut self) -> Result<ArchiveIndex, UpdateError> {
+        let _index_parse = info_span!("index_parse", path = INDEX_PATH).entered();
 
-    fn get_file_content(&mut self, relative_path: &str) -> Result<String, UpdateError> {
-        let mut file = self.archive.by_name(relative_path).unwrap();
-        let mut file_content = String::new();
-        file.read_to_string(&mut file_content).unwrap();
-        Ok(file_content)
+        let index = self.get_file_content(INDEX_PATH, UpdatePhase::Index)?;
+        ArchiveIndex::parse(&index)
     }
 
-    fn get_manifest(&mut self, index: &minidom::Element) -> Result<minidom::Element, UpdateError> {
-        let manifest_path = self.get_manifest_path_from_index(index)?;
-        let manifest = self.get_file_content(&manifest_path)?;
-        let manifest = manifest.parse().unwrap();
-        Ok(manifest)
-    }
+    fn get_file_content(
+        &mut self,
+        relative_path: &str,
+        phase: UpdatePhase,
+    ) -> Result<Vec<u8>, UpdateError> {
+        let read_error = |error: &(dyn Error + 'static)| {
+            invalid_archive(
+                relative_path,
+                phase,
+                "Unable to read file from archive",
+                error,
+            )
+        };
 
-    fn get_manifest_path_from_index(
-        &self,
-        index: &minidom::Element,
-    ) -> Result<String, UpdateError> {
-        let manifest_info = index
-            .children()
-            .find(|elem| elem.attr("short_name") == Some("update_manifest"))
-            .unwrap();
+        let mut file = self
+            .archive
+            .by_name(relative_path)
+            .map_err(|error| read_error(&error))?;
+        let mut file_content = Vec::new();
+        file.read_to_end(&mut file_content)
+            .map_err(|error| read_error(&error))?;
+        Ok(file_content)
+    }
 
-        let manifest_path = manifest_info.get_child("path", "file_list").unwrap().text();
+    fn get_manifest(&mut self, index: &ArchiveIndex) -> Result<UpdateManifest, UpdateError> {
+        let manifest_path = index.get_manifest_path()?;
+        let _manifest_parse = info_span!("manifest_parse", path = manifest_path).entered();
 
-        Ok(manifest_path)
+        let manifest = self.get_file_content(manifest_path, UpdatePhase::Manifest)?;
+        UpdateManifest::parse(manifest_path, &manifest, index)
     }
 
-    fn index_logical_blocks_from_manifest_and_index(
+    fn index_logical_blocks_from_manifest(
         &mut self,
-        manifest: &minidom::Element,
-        index: &minidom::Element,
+        manifest: UpdateManifest,
     ) -> Result<(), UpdateError> {
-        for elem in manifest.children() {
-            let id = elem.get_child("id", MANIFEST_XML_NAMESPACE).unwrap().text();
-
-            let name = elem
-                .get_child("short_name", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let signature = elem
-                .get_child("signature", MANIFEST_XML_NAMESPACE)
-                .unwrap()
-                .text();
-
-            let path_in_archive = index
-                .children()
-                .find(|elem| elem.attr("short_name") == Some(&name))
-                .unwrap()
-                .get_child("path", "file_list")
-                .unwrap()
-                .text();
+        for logical_block in manifest.logical_blocks {
+            let size = self
+                .archive
+                .by_name(&logical_block.path_in_archive)
+                .map_err(|error| {
+                    invalid_archive(
+                        &logical_block.path_in_archive,
+                        UpdatePhase::Manifest,
+                        "Logical block listed in the manifest is missing from the archive",
+                        &error,
+                    )
+                })?
+                .size();
 
             self.logical_blocks.push(LogicalBlockInfo {
-                id,
-                name,
-                signature,
-                path_in_archive,
+                id: logical_block.id,
+                name: logical_block.short_name,
+                signature: logical_block.signature,
+                path_in_archive: logical_block.path_in_archive,
+                size,
+                digest: logical_block.digest,
             });
         }
         Ok(())
@@ -166,6 +227,7 @@ impl SoftwareArchive {
                 .archive
                 .by_name(&logical_block.path_in_archive)
                 .unwrap(),
+            archive_file: &self.archive_file,
         }
     }
 
@@ -177,6 +239,7 @@ impl SoftwareArchive {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{manifest::ManifestFormat, test_utils::ArchiveBuilder};
 
     #[test]
     fn real_archive_test() {
@@ -186,4 +249,30 @@ mod tests {
             println!("{}", logical_block)
         }
     }
+
+    #[test]
+    fn manifest_formats_round_trip_test() {
+        let archive_dir = tempfile::tempdir().unwrap();
+
+        let logical_blocks_info: Vec<_> = [
+            ManifestFormat::Xml,
+            ManifestFormat::Json,
+            ManifestFormat::Cbor,
+        ]
+        .into_iter()
+        .map(|manifest_format| {
+            let archive_path = ArchiveBuilder::new(archive_dir.path())
+                .stored()
+                .manifest_format(manifest_format)
+                .build();
+            SoftwareArchive::from(&archive_path)
+                .unwrap()
+                .get_logical_blocks_info()
+        })
+        .collect();
+
+        assert_eq!(logical_blocks_info[0].len(), 9);
+        assert_eq!(logical_blocks_info[0], logical_blocks_info[1]);
+        assert_eq!(logical_blocks_info[0], logical_blocks_info[2]);
+    }
 }
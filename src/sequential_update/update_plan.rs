@@ -0,0 +1,226 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::memory_mapping::{LogicalBlockDestination, MemoryMapping};
+use crate::reporting::UpdateError;
+use crate::sequential_update::software_archive::{LogicalBlockInfo, SoftwareArchive};
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct UpdatePlan {
+    targeted_bank: String,
+    logical_blocks: Vec<LogicalBlockPlan>,
+    missing_logical_blocks: Vec<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LogicalBlockPlan {
+    id: String,
+    name: String,
+    path_in_archive: String,
+    size_in_archive: u64,
+    destination: LogicalBlockDestination,
+}
+
+impl LogicalBlockPlan {
+    fn from(
+        logical_block_info: &LogicalBlockInfo,
+        destination: LogicalBlockDestination,
+    ) -> LogicalBlockPlan {
+        LogicalBlockPlan {
+            id: logical_block_info.get_id(),
+            name: logical_block_info.get_name().to_string(),
+            path_in_archive: logical_block_info.get_path_in_archive().to_string(),
+            size_in_archive: logical_block_info.get_size(),
+            destination,
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        self.id.as_ref()
+    }
+
+    pub fn get_destination(&self) -> &LogicalBlockDestination {
+        &self.destination
+    }
+
+    pub fn has_size_mismatch(&self) -> bool {
+        self.size_in_archive != self.destination.get_size() as u64
+    }
+}
+
+impl fmt::Display for LogicalBlockPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (id: 0x{}): {} ({} bytes) -> {} at offset {} ({} bytes)",
+            self.name,
+            self.id,
+            self.path_in_archive,
+            self.size_in_archive,
+            self.destination.get_path(),
+            self.destination.get_offset(),
+            self.destination.get_size()
+        )?;
+
+        if self.has_size_mismatch() {
+            write!(f, " [size mismatch]")?;
+        }
+        Ok(())
+    }
+}
+
+impl UpdatePlan {
+    pub fn from(software_archive: &SoftwareArchive, memory_mapping: &MemoryMapping) -> UpdatePlan {
+        let mut logical_blocks = Vec::new();
+        let mut missing_logical_blocks = Vec::new();
+
+        for logical_block_info in software_archive.get_logical_blocks_info() {
+            match memory_mapping.get_logical_block_destination(&logical_block_info.get_id()) {
+                Ok(destination) => logical_blocks.push(LogicalBlockPlan::from(
+                    &logical_block_info,
+                    destination.clone(),
+                )),
+                Err(_) => missing_logical_blocks.push(logical_block_info.get_id()),
+            }
+        }
+
+        UpdatePlan {
+            targeted_bank: memory_mapping.get_targeted_bank().to_string(),
+            logical_blocks,
+            missing_logical_blocks,
+        }
+    }
+
+    pub fn get_targeted_bank(&self) -> &str {
+        self.targeted_bank.as_ref()
+    }
+
+    pub fn get_logical_blocks(&self) -> &[LogicalBlockPlan] {
+        &self.logical_blocks
+    }
+
+    pub fn get_missing_logical_blocks(&self) -> &[String] {
+        &self.missing_logical_blocks
+    }
+
+    pub fn get_size_mismatches(&self) -> Vec<&LogicalBlockPlan> {
+        self.logical_blocks
+            .iter()
+            .filter(|logical_block| logical_block.has_size_mismatch())
+            .collect()
+    }
+
+    pub fn is_applicable(&self) -> bool {
+        self.missing_logical_blocks.is_empty() && self.get_size_mismatches().is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for UpdatePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Update plan targeting {}:", self.targeted_bank)?;
+
+        for logical_block in &self.logical_blocks {
+            writeln!(f, "  {}", logical_block)?;
+        }
+
+        for logical_block_id in &self.missing_logical_blocks {
+            writeln!(
+                f,
+                "  0x{} has no destination in the memory mapping",
+                logical_block_id
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn plan_update(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+) -> Result<UpdatePlan, UpdateError> {
+    let software_archive = SoftwareArchive::from(software_archive_path)?;
+
+    let memory_mapping = MemoryMapping::from(memory_mapping_path)?;
+
+    Ok(UpdatePlan::from(&software_archive, &memory_mapping))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn real_update_plan_test() {
+        let plan = plan_update(
+            "./resources/test/test_lb_cfg.json",
+            "./resources/test/update_folder.zip",
+        )
+        .unwrap();
+
+        assert_eq!(plan.get_targeted_bank(), "bank_a");
+        assert_eq!(plan.get_logical_blocks().len(), 9);
+        assert!(plan.get_missing_logical_blocks().is_empty());
+        assert!(plan.get_size_mismatches().is_empty());
+        assert!(plan.is_applicable());
+
+        let rendered_plan = plan.to_string();
+        let lines: Vec<_> = rendered_plan.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "Update plan targeting bank_a:");
+        assert_eq!(
+            lines[1],
+            "  dummy_FD01 (id: 0xFD01): logical_blocks/FD01.bin (130757 bytes) -> ./mtd_a at offset 0 (130757 bytes)"
+        );
+        assert_eq!(
+            lines[5],
+            "  dummy_FD05 (id: 0xFD05): logical_blocks/FD05.bin (16777035 bytes) -> ./mtd_a at offset 1249280 (16777035 bytes)"
+        );
+    }
+
+    #[test]
+    fn update_plan_reports_missing_and_mismatching_logical_blocks_test() {
+        let mapping_dir = tempfile::tempdir().unwrap();
+        let mapping_path = mapping_dir.path().join("partial_lb_cfg.json");
+        fs::write(
+            &mapping_path,
+            r#"{
+                "logical_blocks": [
+                    {
+                        "name": "dummy_FD01",
+                        "id": "FD01",
+                        "destination": {
+                            "bank_a": { "path": "./mtd_a", "offset": 0, "size": 1024 },
+                            "bank_b": { "path": "./mtd_b", "offset": 0, "size": 1024 }
+                        }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let plan = plan_update(
+            mapping_path.to_str().unwrap(),
+            "./resources/test/update_folder.zip",
+        )
+        .unwrap();
+
+        assert!(!plan.is_applicable());
+        assert_eq!(plan.get_missing_logical_blocks().len(), 8);
+
+        let size_mismatches = plan.get_size_mismatches();
+        assert_eq!(size_mismatches.len(), 1);
+        assert_eq!(size_mismatches[0].get_id(), "FD01");
+
+        let json: serde_json::Value = serde_json::from_str(&plan.to_json()).unwrap();
+        assert_eq!(json["targeted_bank"], "bank_a");
+        assert_eq!(json["logical_blocks"][0]["size_in_archive"], 130757);
+        assert_eq!(json["logical_blocks"][0]["destination"]["size"], 1024);
+    }
+}
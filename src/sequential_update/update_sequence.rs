@@ -1,79 +1,770 @@
-use crate::reporting::UpdateError;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, field, info_span, Span};
+
+use crate::memory_mapping::{LogicalBlockDestination, MemoryMapping};
+use crate::reporting::{
+    trace_update_result, LogicalBlockReport, LogicalBlockStatus, PhaseDurations, UpdateError,
+    UpdatePhase, UpdateProgress, UpdateReport,
+};
+use crate::retry::LogicalBlockInstall;
 use crate::sequential_update::crypto::LogicalBlockVerifier;
-use crate::sequential_update::memory::{LogicalBlockDestination, MemoryMapping};
 use crate::sequential_update::software_archive::{
     LogicalBlockInfo, LogicalBlockReader, SoftwareArchive,
 };
+use crate::update_control::UpdateControl;
 use crate::{reporting::LogicalBlockError, sequential_update::memory::LogicalBlockWriter};
 
 pub fn sequencial_update(
     memory_mapping_path: &str,
     software_archive_path: &str,
+) -> Result<UpdateReport, UpdateError> {
+    sequencial_update_with_control(
+        memory_mapping_path,
+        software_archive_path,
+        &UpdateControl::new(),
+    )
+}
+
+/// Same as [`sequencial_update`], letting `update_control` cancel, pause, throttle or hook the
+/// writes of the update while it runs, and record its outcome.
+pub fn sequencial_update_with_control(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    update_control: &UpdateControl,
+) -> Result<UpdateReport, UpdateError> {
+    let update_start = Instant::now();
+
+    let update_span = info_span!(
+        "update",
+        strategy = "sequential",
+        archive = software_archive_path,
+        mapping = memory_mapping_path
+    );
+    let mut update_report = UpdateReport::default();
+    let update_result = update_span.in_scope(|| {
+        let update_result = install_update(
+            memory_mapping_path,
+            software_archive_path,
+            update_control,
+            &mut update_report,
+        );
+        trace_update_result(&update_result, &update_report);
+        update_result
+    });
+
+    let record_result = update_control.record_update(
+        software_archive_path,
+        &update_report,
+        update_result.as_ref().err(),
+        update_start.elapsed(),
+    );
+    update_result.and(record_result).map(|()| update_report)
+}
+
+/// Installs the update, recording the logical blocks in `update_report` as they complete, so
+/// that it tells how far a failed update went.
+fn install_update(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    update_control: &UpdateControl,
+    update_report: &mut UpdateReport,
 ) -> Result<(), UpdateError> {
-    let mut new_software_archive = SoftwareArchive::from(software_archive_path).unwrap();
+    let memory_mapping =
+        MemoryMapping::from(memory_mapping_path)?.with_write_hook(update_control.get_write_hook());
+    update_report.targeted_bank = Some(memory_mapping.get_targeted_bank().to_string());
+
+    let index_parsing_start = Instant::now();
+    let mut new_software_archive = SoftwareArchive::from(software_archive_path)?;
+    update_report.index_parsing_duration = index_parsing_start.elapsed();
+
+    memory_mapping.stage_update()?;
+
+    install_logical_blocks(
+        &mut new_software_archive,
+        &memory_mapping,
+        update_control,
+        update_report,
+    )
+    .map_err(|error| memory_mapping.require_recovery_on_failure(error))?;
+
+    clone_logical_blocks_missing_from_update(&memory_mapping, update_report, update_control)?;
 
-    let memory_mapping = MemoryMapping::from(memory_mapping_path).unwrap();
+    memory_mapping.commit_update()
+}
 
+fn install_logical_blocks(
+    new_software_archive: &mut SoftwareArchive,
+    memory_mapping: &MemoryMapping,
+    update_control: &UpdateControl,
+    update_report: &mut UpdateReport,
+) -> Result<(), UpdateError> {
     for logical_block_info in new_software_archive.get_logical_blocks_info() {
-        let logical_block_reader =
-            new_software_archive.get_logical_block_reader(&logical_block_info);
-        let logical_block_destination =
-            memory_mapping.get_logical_block_writer(&logical_block_info)?;
+        install_logical_block(
+            new_software_archive,
+            memory_mapping,
+            &logical_block_info,
+            update_control,
+        )
+        .map(|logical_block_report| update_report.logical_blocks.push(logical_block_report))
+        .map_err(|error| error.after_completing(update_report.get_logical_block_ids()))?;
+    }
+
+    Ok(())
+}
+
+fn install_logical_block(
+    new_software_archive: &mut SoftwareArchive,
+    memory_mapping: &MemoryMapping,
+    logical_block_info: &LogicalBlockInfo,
+    update_control: &UpdateControl,
+) -> Result<LogicalBlockReport, UpdateError> {
+    if !update_control.proceed() {
+        return Err(cancelled(UpdatePhase::Write));
+    }
+
+    let _logical_block_span = info_span!(
+        "logical_block",
+        logical_block_id = %logical_block_info.get_id()
+    )
+    .entered();
 
-        write_logical_block(logical_block_reader, &logical_block_destination)?;
+    memory_mapping.check_recovery()?;
+    let logical_block_destination = memory_mapping
+        .get_logical_block_destination(&logical_block_info.get_id())?
+        .clone();
+    let offset = logical_block_destination.get_offset();
+    let size = logical_block_destination.get_size();
+    let mut phase_durations = PhaseDurations::default();
+    let mut chunk_retries = 0;
 
-        verify_logical_block(logical_block_destination, logical_block_info)?;
+    let verify_start = Instant::now();
+    let is_up_to_date =
+        logical_block_destination.holds_content_with_digest(logical_block_info.get_digest());
+    phase_durations.verify += verify_start.elapsed();
+
+    LogicalBlockInstall {
+        logical_block_id: logical_block_info.get_id(),
+        digest: logical_block_info.get_digest().map(str::to_string),
+        signature: logical_block_info.get_signature(),
+        destination: logical_block_destination.clone(),
+        memory_mapping,
+    }
+    .run(
+        is_up_to_date,
+        phase_durations,
+        |_, is_up_to_date, phase_durations| {
+            let status = if is_up_to_date {
+                debug!("logical block already up to date");
+                LogicalBlockStatus::Skipped
+            } else {
+                let logical_block_reader =
+                    new_software_archive.get_logical_block_reader(logical_block_info);
+
+                memory_mapping.record_slot_write();
+                let write_start = Instant::now();
+                let (decompression_duration, retries) =
+                    info_span!("write", offset, size, bytes = field::Empty).in_scope(|| {
+                        write_logical_block(
+                            logical_block_reader,
+                            &logical_block_destination,
+                            update_control,
+                        )
+                    })?;
+                phase_durations.decompression += decompression_duration;
+                phase_durations.write +=
+                    write_start.elapsed().saturating_sub(decompression_duration);
+                chunk_retries += retries;
+                LogicalBlockStatus::Written
+            };
+
+            let verify_start = Instant::now();
+            info_span!("verify", offset, size).in_scope(|| {
+                verify_logical_block(
+                    logical_block_destination.clone(),
+                    logical_block_info.clone(),
+                    update_control,
+                )
+            })?;
+            phase_durations.verify += verify_start.elapsed();
+
+            Ok((status, chunk_retries))
+        },
+    )
+}
+
+fn clone_logical_blocks_missing_from_update(
+    memory_mapping: &MemoryMapping,
+    update_report: &mut UpdateReport,
+    update_control: &UpdateControl,
+) -> Result<(), UpdateError> {
+    let updated_logical_block_ids = update_report.get_logical_block_ids();
+
+    for logical_block_clone in
+        memory_mapping.get_logical_block_clones(&updated_logical_block_ids)?
+    {
+        if !update_control.proceed() {
+            return Err(cancelled(UpdatePhase::Write)
+                .after_completing(update_report.get_logical_block_ids()));
+        }
+
+        let write_start = Instant::now();
+        let digest = info_span!(
+            "clone",
+            logical_block_id = %logical_block_clone.id,
+            offset = logical_block_clone.destination.get_offset(),
+            size = logical_block_clone.destination.get_size(),
+        )
+        .in_scope(|| logical_block_clone.copy())?;
+
+        update_report.logical_blocks.push(LogicalBlockReport {
+            logical_block_id: logical_block_clone.id,
+            status: LogicalBlockStatus::Cloned,
+            digest: Some(digest),
+            signature: None,
+            destination_path: logical_block_clone.destination.get_path().to_string(),
+            written_bytes: logical_block_clone.destination.get_size() as u64,
+            retries: 0,
+            phase_durations: PhaseDurations {
+                write: write_start.elapsed(),
+                ..PhaseDurations::default()
+            },
+        });
     }
     Ok(())
 }
 
+fn cancelled(phase: UpdatePhase) -> UpdateError {
+    UpdateError::Cancelled(UpdateProgress::new(phase))
+}
+
+/// Writes the logical block to its destination and returns the time spent decompressing it,
+/// along with the number of chunk writes retried.
 fn write_logical_block(
     logical_block_reader: LogicalBlockReader<'_>,
     logical_block_destination: &LogicalBlockDestination,
-) -> Result<(), UpdateError> {
+    update_control: &UpdateControl,
+) -> Result<(Duration, u32), UpdateError> {
     let logical_block_info = logical_block_reader.get_logical_block_info().clone();
     let mut logical_block_writer =
         LogicalBlockWriter::from(logical_block_reader, logical_block_destination.clone())?;
 
-    let bytes_count = logical_block_writer.write()?;
+    let bytes_count = logical_block_writer.write(update_control)?;
+    Span::current().record("bytes", bytes_count);
 
     match bytes_count == logical_block_writer.get_size() {
-        true => Ok(()),
-        false => Err(UpdateError::LogicalBlockSize(LogicalBlockError {
-            logical_block_id: logical_block_info.get_id(),
-            description: "todo!()".to_string(),
-        })),
+        true => Ok((
+            logical_block_writer.get_decompression_duration(),
+            logical_block_writer.get_retries(),
+        )),
+        false => Err(UpdateError::LogicalBlockSize(LogicalBlockError::new(
+            logical_block_info.get_id(),
+            UpdatePhase::Write,
+            format!(
+                "Number of bytes written ({bytes_count}) doesn't match the expected logical block size ({})",
+                logical_block_writer.get_size()
+            ),
+        ))),
     }
 }
 
 fn verify_logical_block(
     logical_block_destination: LogicalBlockDestination,
     logical_block_info: LogicalBlockInfo,
+    update_control: &UpdateControl,
 ) -> Result<(), UpdateError> {
     let logical_block_verifier =
-        LogicalBlockVerifier::from(logical_block_destination, logical_block_info.clone());
+        LogicalBlockVerifier::from(logical_block_destination, logical_block_info.clone())
+            .controlled_by(update_control);
 
     if logical_block_verifier.verify()? {
         Ok(())
     } else {
-        Err(UpdateError::VerificationError(LogicalBlockError {
-            logical_block_id: logical_block_info.get_id(),
-            description: "todo!()".to_string(),
-        }))
+        Err(UpdateError::VerificationError(LogicalBlockError::new(
+            logical_block_info.get_id(),
+            UpdatePhase::Verify,
+            format!(
+                "Verification failed: logical block {} doesn't match its signature ({})",
+                logical_block_info.get_id(),
+                logical_block_info.get_signature()
+            ),
+        )))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use tracing_subscriber::fmt::format::FmtSpan;
+
     use super::*;
 
+    use crate::audit_log::{AuditLog, AuditOutcome};
+    use crate::boot_control::{BootControl, SlotState};
+    use crate::reporting::UpdatePhase;
+    use crate::test_utils::{
+        assert_written_in_stages, fail_failing_writes, ArchiveBuilder, MappingBuilder,
+        WriteRecorder,
+    };
+    use crate::throttle::{IoBudget, IoThrottle};
+
     #[test]
     fn sequencial_update_test() {
-        let result = sequencial_update(
-            "./resources/test/test_lb_cfg.json",
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        let result = sequencial_update(&mapping_path, "./resources/test/update_folder.zip");
+
+        assert_eq!(result.unwrap().logical_blocks.len(), 9)
+    }
+
+    #[test]
+    fn sequencial_update_installs_stages_in_order_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let archive_path = ArchiveBuilder::new(destination_dir.path())
+            .depends_on("FD03", &["FD01"])
+            .depends_on("FD07", &["FD01"])
+            .phase(1, &["FD02", "FD05", "FD08"])
+            .build();
+        let write_recorder = WriteRecorder::new(&mapping_path);
+
+        sequencial_update_with_control(
+            &mapping_path,
+            &archive_path,
+            &UpdateControl::new().with_write_hook(write_recorder.clone()),
+        )
+        .unwrap();
+
+        assert_written_in_stages(
+            &write_recorder.get_writes(),
+            &[
+                &["FD01", "FD04", "FD06", "FD09"],
+                &["FD03", "FD07"],
+                &["FD02", "FD05", "FD08"],
+            ],
+        );
+    }
+
+    #[test]
+    fn sequencial_update_never_creates_destinations_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let missing_destination_path = destination_dir.path().join("mtd_a");
+        std::fs::remove_file(&missing_destination_path).unwrap();
+
+        let error =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap_err();
+
+        assert_eq!(error.code(), 301);
+        assert!(!missing_destination_path.exists());
+    }
+
+    #[test]
+    fn sequencial_update_with_audit_log_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let failing_mapping_path = MappingBuilder::new(destination_dir.path())
+            .retry(serde_json::json!({ "max_attempts": 1 }))
+            .failing(&["FD09"])
+            .build();
+        let audit_log_path = destination_dir.path().join("audit.log");
+        let audit_log_path = audit_log_path.to_str().unwrap();
+        let update_control = UpdateControl::new()
+            .with_audit_log(AuditLog::from(audit_log_path))
+            .with_write_hook(fail_failing_writes);
+
+        sequencial_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap();
+        sequencial_update_with_control(
+            &mapping_path,
+            "./resources/test/missing_update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+        sequencial_update_with_control(
+            "./resources/test/missing_lb_cfg.json",
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+        sequencial_update_with_control(
+            &failing_mapping_path,
             "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+
+        let audit_log = AuditLog::from(audit_log_path);
+        assert_eq!(audit_log.verify().unwrap(), 4);
+
+        let entries = audit_log.read_entries().unwrap();
+        assert_eq!(entries[0].record.outcome, AuditOutcome::Success);
+        assert_eq!(entries[0].record.targeted_bank.as_deref(), Some("bank_a"));
+        assert_eq!(entries[0].record.logical_blocks.len(), 9);
+
+        // Archive, mapping and logical block failures are all recorded
+        let failure_codes: Vec<_> = entries[1..]
+            .iter()
+            .map(|entry| match &entry.record.outcome {
+                AuditOutcome::Failure { error_code, .. } => *error_code,
+                AuditOutcome::Success => panic!("update recorded as successful"),
+            })
+            .collect();
+        assert_eq!(failure_codes, vec![100, 200, 301]);
+        assert_eq!(entries[1].record.archive_digest, None);
+        assert_eq!(entries[2].record.targeted_bank, None);
+
+        let completed_logical_block_ids: Vec<_> = entries[3]
+            .record
+            .logical_blocks
+            .iter()
+            .map(|logical_block| logical_block.id.as_str())
+            .collect();
+        assert_eq!(
+            completed_logical_block_ids,
+            vec!["FD01", "FD02", "FD03", "FD04", "FD05", "FD06", "FD07", "FD08"]
+        );
+    }
+
+    #[test]
+    fn sequencial_update_with_missing_mapping_test() {
+        let error = sequencial_update(
+            "./resources/test/missing_lb_cfg.json",
+            "./resources/test/update_folder.zip",
+        )
+        .unwrap_err();
+
+        assert_eq!(error.code(), 200);
+        assert_eq!(error.phase(), UpdatePhase::Mapping);
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn sequencial_update_reports_phase_durations_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        let update_report =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+        let phase_durations = update_report.get_phase_durations();
+
+        assert!(!phase_durations.index_parsing.is_zero());
+        assert!(!phase_durations.decompression.is_zero());
+        assert!(!phase_durations.write.is_zero());
+        assert!(!phase_durations.verify.is_zero());
+        assert_eq!(
+            phase_durations.verify,
+            update_report
+                .logical_blocks
+                .iter()
+                .map(|logical_block| logical_block.phase_durations.verify)
+                .sum::<Duration>()
+        );
+    }
+
+    #[test]
+    fn sequencial_update_with_invalid_chunking_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .chunked(4096, 3)
+            .build();
+
+        let error =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap_err();
+
+        assert_eq!(error.code(), 200);
+        assert!(error
+            .to_string()
+            .contains("Alignment (3) must be a power of two"));
+    }
+
+    #[test]
+    fn sequencial_update_with_retry_policy_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .retry(serde_json::json!({
+                "max_attempts": 3,
+                "granularity": "chunk",
+                "erase": true,
+                "verify": true,
+            }))
+            .build();
+
+        let update_report =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+        assert_eq!(update_report.logical_blocks.len(), 9);
+        assert!(update_report
+            .logical_blocks
+            .iter()
+            .all(|logical_block| logical_block.retries == 0));
+    }
+
+    #[test]
+    fn sequencial_update_commits_verified_bank_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .boot_controlled()
+            .build();
+        let incomplete_mapping_path = MappingBuilder::new(destination_dir.path())
+            .unmapped(&["FD09"])
+            .boot_controlled()
+            .build();
+        let boot_control = BootControl::from(
+            destination_dir
+                .path()
+                .join("boot_control")
+                .to_str()
+                .unwrap(),
+        );
+
+        sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+        let record = boot_control.read_record().unwrap();
+        assert_eq!(record.active_slot.as_deref(), Some("bank_a"));
+        assert_eq!(record.slots["bank_a"], SlotState::Valid);
+        assert_eq!(
+            MemoryMapping::from(&mapping_path)
+                .unwrap()
+                .get_targeted_bank(),
+            "bank_b"
+        );
+
+        boot_control.mark_boot_successful().unwrap();
+        let error = sequencial_update(
+            &incomplete_mapping_path,
+            "./resources/test/update_folder.zip",
+        )
+        .unwrap_err();
+        assert_eq!(error.code(), 201);
+
+        let record = boot_control.read_record().unwrap();
+        assert_eq!(record.active_slot.as_deref(), Some("bank_a"));
+        assert_eq!(record.slots["bank_b"], SlotState::Invalid);
+    }
+
+    #[test]
+    fn sequencial_update_in_single_slot_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .single_slot()
+            .build();
+
+        let update_report =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+        assert_eq!(
+            update_report
+                .get_logical_blocks_with_status(LogicalBlockStatus::Written)
+                .len(),
+            9
+        );
+
+        std::fs::write(destination_dir.path().join("recovery"), b"corrupted").unwrap();
+        let error =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap_err();
+        assert_eq!(error.code(), 400);
+        assert_eq!(error.get_logical_block_id(), Some("recovery"));
+    }
+
+    #[test]
+    fn sequencial_update_in_single_slot_requires_recovery_on_failure_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .unmapped(&["FD09"])
+            .single_slot()
+            .build();
+
+        let error =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap_err();
+
+        assert_eq!(error.code(), 600);
+        assert_eq!(error.get_logical_block_id(), Some("FD09"));
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn sequencial_updates_in_boot_controlled_single_slot_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .single_slot()
+            .boot_controlled()
+            .build();
+        let boot_control = BootControl::from(
+            destination_dir
+                .path()
+                .join("boot_control")
+                .to_str()
+                .unwrap(),
+        );
+
+        for _ in 0..2 {
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+
+            let record = boot_control.read_record().unwrap();
+            assert_eq!(record.active_slot.as_deref(), Some("single"));
+            assert_eq!(record.slots["single"], SlotState::Valid);
+        }
+    }
+
+    #[test]
+    fn sequencial_update_in_single_slot_keeps_errors_before_writing_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .unmapped(&["FD01"])
+            .single_slot()
+            .build();
+
+        let error =
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap_err();
+        assert_eq!(error.code(), 201);
+
+        let update_control = UpdateControl::new();
+        update_control.cancel();
+        let error = sequencial_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+        assert_eq!(error.code(), 800);
+    }
+
+    #[test]
+    fn cancelled_sequencial_update_keeps_active_bank_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .boot_controlled()
+            .build();
+        let boot_control = BootControl::from(
+            destination_dir
+                .path()
+                .join("boot_control")
+                .to_str()
+                .unwrap(),
+        );
+        // Slowed down so that the update, about 18.7 MB, is still running when cancelled
+        let update_control = UpdateControl::new().with_io_throttle(IoThrottle::new(IoBudget {
+            bytes_per_second: Some(20_000_000),
+            ..IoBudget::default()
+        }));
+
+        let canceller = {
+            let update_control = update_control.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                update_control.cancel();
+            })
+        };
+        let error = sequencial_update_with_control(
+            &mapping_path,
+            "./resources/test/update_folder.zip",
+            &update_control,
+        )
+        .unwrap_err();
+        canceller.join().unwrap();
+
+        assert_eq!(error.code(), 800);
+        match &error {
+            UpdateError::Cancelled(progress) => {
+                assert!(progress.completed_logical_blocks.len() < 9)
+            }
+            _ => panic!("Unexpected error: {error}"),
+        }
+
+        let record = boot_control.read_record().unwrap();
+        assert_eq!(record.active_slot, None);
+        assert_eq!(record.slots["bank_a"], SlotState::Invalid);
+
+        sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+        let record = boot_control.read_record().unwrap();
+        assert_eq!(record.active_slot.as_deref(), Some("bank_a"));
+        assert_eq!(record.slots["bank_a"], SlotState::Valid);
+    }
+
+    #[derive(Clone, Default)]
+    struct TraceBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for TraceBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sequencial_update_traces_logical_blocks_test() {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let trace_buffer = TraceBuffer::default();
+
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer({
+                let trace_buffer = trace_buffer.clone();
+                move || trace_buffer.clone()
+            })
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            sequencial_update(&mapping_path, "./resources/test/update_folder.zip").unwrap();
+            sequencial_update(&mapping_path, "./resources/test/missing_update_folder.zip")
+                .unwrap_err();
+        });
+
+        let trace = String::from_utf8(trace_buffer.0.lock().unwrap().clone()).unwrap();
+        let trace: Vec<serde_json::Value> = trace
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let closed_spans: Vec<_> = trace
+            .iter()
+            .filter(|event| event["fields"]["message"] == "close")
+            .map(|event| &event["span"])
+            .collect();
+
+        for span_name in [
+            "archive_open",
+            "index_parse",
+            "manifest_parse",
+            "mapping_load",
+        ] {
+            assert!(closed_spans.iter().any(|span| span["name"] == span_name));
+        }
+        let fd05_write = trace
+            .iter()
+            .find(|event| {
+                event["span"]["name"] == "write" && event["spans"][1]["logical_block_id"] == "FD05"
+            })
+            .unwrap();
+        assert_eq!(fd05_write["spans"][0]["strategy"], "sequential");
+        assert_eq!(fd05_write["span"]["offset"], 1249280);
+        assert_eq!(fd05_write["span"]["bytes"], 16777035);
+        assert_eq!(
+            closed_spans
+                .iter()
+                .filter(|span| span["name"] == "verify")
+                .count(),
+            9
         );
 
-        assert_eq!(result, Ok(()))
+        let failure = trace
+            .iter()
+            .find(|event| event["level"] == "ERROR")
+            .unwrap();
+        assert_eq!(failure["fields"]["code"], 100);
     }
 }
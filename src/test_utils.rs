@@ -0,0 +1,518 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use openssl::sha::sha256;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::digest::to_hex;
+use crate::manifest::{
+    ArchiveIndex, ManifestDocument, ManifestEntry, ManifestFormat, UpdateManifest, INDEX_PATH,
+};
+use crate::WriteHook;
+
+const TEST_MAPPING_PATH: &str = "./resources/test/test_lb_cfg.json";
+const TEST_ARCHIVE_PATH: &str = "./resources/test/update_folder.zip";
+const RECOVERY_IMAGE: &[u8] = b"recovery image";
+
+/// Writes copies of the test memory mapping whose destinations live in `destination_dir`, so
+/// that tests don't share destination files. Destinations are never created by updates, the
+/// files they live in are created by [`MappingBuilder::build`], large enough for every region.
+///
+/// Settings apply in the order they are given, each copy gets a file name of its own.
+pub(crate) struct MappingBuilder<'a> {
+    destination_dir: &'a Path,
+    mapping: serde_json::Value,
+}
+
+impl<'a> MappingBuilder<'a> {
+    pub(crate) fn new(destination_dir: &'a Path) -> MappingBuilder<'a> {
+        let mapping = fs::read_to_string(TEST_MAPPING_PATH).unwrap();
+        let mapping = mapping.replace("\"./", &format!("\"{}/", destination_dir.display()));
+
+        MappingBuilder {
+            destination_dir,
+            mapping: serde_json::from_str(&mapping).unwrap(),
+        }
+    }
+
+    /// Swaps the two banks, so that updating with the mapping installs the logical blocks in
+    /// what is the active bank for the regular mapping.
+    pub(crate) fn swapped(mut self) -> Self {
+        for logical_block in self.get_logical_blocks() {
+            let destinations = logical_block["destination"].as_object_mut().unwrap();
+            let bank_a = destinations.remove("bank_a").unwrap();
+            let bank_b = destinations.remove("bank_b").unwrap();
+            destinations.insert("bank_a".to_string(), bank_b);
+            destinations.insert("bank_b".to_string(), bank_a);
+        }
+        self
+    }
+
+    /// Copies every destination in chunks of `buffer_size` bytes aligned on `alignment`.
+    pub(crate) fn chunked(mut self, buffer_size: usize, alignment: usize) -> Self {
+        for destination in self.get_destinations() {
+            destination["buffer_size"] = buffer_size.into();
+            destination["alignment"] = alignment.into();
+        }
+        self
+    }
+
+    /// Retries failed writes to every destination as `retry_policy` says.
+    pub(crate) fn retry(mut self, retry_policy: serde_json::Value) -> Self {
+        for destination in self.get_destinations() {
+            destination["retry"] = retry_policy.clone();
+        }
+        self
+    }
+
+    /// Moves the bank A destinations of the logical blocks listed in `failing_logical_block_ids`
+    /// to files of their own, every write to which fails under [`fail_failing_writes`].
+    pub(crate) fn failing(mut self, failing_logical_block_ids: &[&str]) -> Self {
+        let destination_dir = self.destination_dir;
+        for logical_block in self.get_logical_blocks() {
+            let id = logical_block["id"].as_str().unwrap().to_string();
+            if !failing_logical_block_ids.contains(&id.as_str()) {
+                continue;
+            }
+
+            let path = destination_dir.join(format!("failing_{id}"));
+            let path = path.to_str().unwrap();
+            logical_block["destination"]["bank_a"]["path"] = path.into();
+        }
+        self
+    }
+
+    /// Moves the logical blocks stored on MTD to simulated NAND images of 2048 byte pages
+    /// followed by 64 OOB bytes, 64 pages per erase block. The erase blocks listed in
+    /// `marked_bad_blocks` have their bad-block marker set, those listed in `listed_bad_blocks`
+    /// are in the bad-block table of the mapping. Both have their page data zeroed.
+    pub(crate) fn nand(mut self, marked_bad_blocks: &[u64], listed_bad_blocks: &[u64]) -> Self {
+        const PAGE_SIZE: usize = 2048;
+        const OOB_SIZE: usize = 64;
+        const PAGES_PER_BLOCK: usize = 64;
+        const BLOCK_SIZE: usize = PAGE_SIZE * PAGES_PER_BLOCK;
+        const SPARE_BLOCKS: usize = 8;
+
+        let destination_dir = self.destination_dir;
+        let mut used_blocks = 0;
+        for logical_block in self.get_logical_blocks() {
+            let destinations = logical_block["destination"].as_object_mut().unwrap();
+            if !destinations["bank_a"]["path"]
+                .as_str()
+                .unwrap()
+                .ends_with("mtd_a")
+            {
+                continue;
+            }
+
+            let size = destinations["bank_a"]["size"].as_u64().unwrap() as usize;
+            for (bank, destination) in destinations {
+                let image_name = bank.replace("bank", "nand");
+                destination["path"] = destination_dir.join(image_name).to_str().unwrap().into();
+                destination["offset"] = (used_blocks * BLOCK_SIZE).into();
+                destination["buffer_size"] = BLOCK_SIZE.into();
+                destination["nand"] = serde_json::json!({
+                    "page_size": PAGE_SIZE,
+                    "oob_size": OOB_SIZE,
+                    "pages_per_block": PAGES_PER_BLOCK,
+                    "bad_blocks": listed_bad_blocks,
+                });
+            }
+            used_blocks += size.div_ceil(BLOCK_SIZE);
+        }
+
+        let raw_block_size = (PAGE_SIZE + OOB_SIZE) * PAGES_PER_BLOCK;
+        let mut image = vec![0xFF; (used_blocks + SPARE_BLOCKS) * raw_block_size];
+        for block in marked_bad_blocks.iter().chain(listed_bad_blocks) {
+            let block_start = *block as usize * raw_block_size;
+            for page in 0..PAGES_PER_BLOCK {
+                let page_start = block_start + page * (PAGE_SIZE + OOB_SIZE);
+                image[page_start..page_start + PAGE_SIZE].fill(0);
+            }
+            if marked_bad_blocks.contains(block) {
+                image[block_start + PAGE_SIZE] = 0;
+            }
+        }
+        for image_name in ["nand_a", "nand_b"] {
+            fs::write(destination_dir.join(image_name), &image).unwrap();
+        }
+        self
+    }
+
+    /// Switches to the single slot layout, where the bank A destinations are overwritten in
+    /// place, and writes a valid recovery image next to them.
+    pub(crate) fn single_slot(mut self) -> Self {
+        let recovery_path = self.destination_dir.join("recovery");
+        fs::write(&recovery_path, RECOVERY_IMAGE).unwrap();
+
+        for logical_block in self.get_logical_blocks() {
+            let destination = logical_block["destination"]["bank_a"].take();
+            logical_block["destination"] = serde_json::json!({ "single": destination });
+        }
+        self.mapping["layout"] = "single".into();
+        self.mapping["recovery"] = serde_json::json!({
+            "path": recovery_path.to_str().unwrap(),
+            "offset": 0,
+            "size": RECOVERY_IMAGE.len(),
+            "digest": to_hex(&sha256(RECOVERY_IMAGE)),
+        });
+        self
+    }
+
+    /// Records the booted bank in a boot-control record stored next to the destinations, as
+    /// `boot_control`.
+    pub(crate) fn boot_controlled(mut self) -> Self {
+        self.mapping["boot_control"] = self
+            .destination_dir
+            .join("boot_control")
+            .to_str()
+            .unwrap()
+            .into();
+        self
+    }
+
+    /// Leaves the logical blocks listed in `unmapped_logical_block_ids` out of the mapping.
+    pub(crate) fn unmapped(mut self, unmapped_logical_block_ids: &[&str]) -> Self {
+        self.get_logical_blocks().retain(|logical_block| {
+            !unmapped_logical_block_ids.contains(&logical_block["id"].as_str().unwrap())
+        });
+        self
+    }
+
+    /// Creates the destination files of the mapping, then writes it and returns its path.
+    pub(crate) fn build(self) -> String {
+        create_destination_files(&self.mapping);
+
+        let (_, mapping_path) = tempfile::Builder::new()
+            .prefix("test_lb_cfg_")
+            .suffix(".json")
+            .tempfile_in(self.destination_dir)
+            .unwrap()
+            .keep()
+            .unwrap();
+        fs::write(&mapping_path, self.mapping.to_string()).unwrap();
+
+        mapping_path.to_str().unwrap().to_string()
+    }
+
+    fn get_logical_blocks(&mut self) -> &mut Vec<serde_json::Value> {
+        self.mapping["logical_blocks"].as_array_mut().unwrap()
+    }
+
+    fn get_destinations(&mut self) -> impl Iterator<Item = &mut serde_json::Value> {
+        self.get_logical_blocks()
+            .iter_mut()
+            .flat_map(|logical_block| {
+                logical_block["destination"]
+                    .as_object_mut()
+                    .unwrap()
+                    .values_mut()
+            })
+    }
+}
+
+/// Creates the missing destination files of `mapping`, extending those too short to hold
+/// their regions.
+fn create_destination_files(mapping: &serde_json::Value) {
+    for logical_block in mapping["logical_blocks"].as_array().unwrap() {
+        for destination in logical_block["destination"].as_object().unwrap().values() {
+            let file = File::options()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(destination["path"].as_str().unwrap())
+                .unwrap();
+            let end =
+                destination["offset"].as_u64().unwrap() + destination["size"].as_u64().unwrap();
+            if file.metadata().unwrap().len() < end {
+                file.set_len(end).unwrap();
+            }
+        }
+    }
+}
+
+/// Writes copies of the test archive, each with a file name of its own.
+pub(crate) struct ArchiveBuilder<'a> {
+    archive_dir: &'a Path,
+    logical_block_ids: Option<Vec<String>>,
+    stored: bool,
+    manifest_format: ManifestFormat,
+    phases: HashMap<String, u32>,
+    dependencies: HashMap<String, Vec<String>>,
+}
+
+impl<'a> ArchiveBuilder<'a> {
+    pub(crate) fn new(archive_dir: &'a Path) -> ArchiveBuilder<'a> {
+        ArchiveBuilder {
+            archive_dir,
+            logical_block_ids: None,
+            stored: false,
+            manifest_format: ManifestFormat::Xml,
+            phases: HashMap::new(),
+            dependencies: HashMap::new(),
+        }
+    }
+
+    /// Only keeps the given logical blocks.
+    pub(crate) fn partial(mut self, logical_block_ids: &[&str]) -> Self {
+        self.logical_block_ids = Some(logical_block_ids.iter().map(|id| id.to_string()).collect());
+        self
+    }
+
+    /// Stores the entries uncompressed, they keep the compression of the test archive otherwise.
+    pub(crate) fn stored(mut self) -> Self {
+        self.stored = true;
+        self
+    }
+
+    /// Encodes the manifest in `manifest_format`.
+    pub(crate) fn manifest_format(mut self, manifest_format: ManifestFormat) -> Self {
+        self.manifest_format = manifest_format;
+        self
+    }
+
+    /// Moves the given logical blocks to install phase `phase`.
+    pub(crate) fn phase(mut self, phase: u32, logical_block_ids: &[&str]) -> Self {
+        for id in logical_block_ids {
+            self.phases.insert(id.to_string(), phase);
+        }
+        self
+    }
+
+    /// Makes the logical block `id` depend on the `dependencies` logical blocks.
+    pub(crate) fn depends_on(mut self, id: &str, dependencies: &[&str]) -> Self {
+        self.dependencies.insert(
+            id.to_string(),
+            dependencies.iter().map(|id| id.to_string()).collect(),
+        );
+        self
+    }
+
+    /// Writes the archive and returns its path.
+    pub(crate) fn build(self) -> String {
+        const XML_MANIFEST_PATH: &str = "logical_blocks/update_manifest.xml";
+
+        let mut source = ZipArchive::new(File::open(TEST_ARCHIVE_PATH).unwrap()).unwrap();
+        let index = read_entry_to_string(&mut source, INDEX_PATH);
+        let manifest = UpdateManifest::parse(
+            XML_MANIFEST_PATH,
+            read_entry_to_string(&mut source, XML_MANIFEST_PATH).as_bytes(),
+            &ArchiveIndex::parse(index.as_bytes()).unwrap(),
+        )
+        .unwrap();
+
+        let logical_blocks: Vec<_> = manifest
+            .logical_blocks
+            .into_iter()
+            .filter(|logical_block| match &self.logical_block_ids {
+                Some(logical_block_ids) => logical_block_ids.contains(&logical_block.id),
+                None => true,
+            })
+            .collect();
+        let manifest_document = ManifestDocument {
+            logical_blocks: logical_blocks
+                .iter()
+                .map(|logical_block| ManifestEntry {
+                    id: logical_block.id.clone(),
+                    short_name: logical_block.short_name.clone(),
+                    signature: logical_block.signature.clone(),
+                    digest: logical_block.digest.clone(),
+                    phase: match self.phases.get(&logical_block.id) {
+                        Some(phase) => *phase,
+                        None => logical_block.phase,
+                    },
+                    depends_on: match self.dependencies.get(&logical_block.id) {
+                        Some(dependencies) => dependencies.clone(),
+                        None => logical_block.depends_on.clone(),
+                    },
+                })
+                .collect(),
+        };
+        let (manifest_path, manifest_content) = match self.manifest_format {
+            ManifestFormat::Xml => (XML_MANIFEST_PATH, get_xml_manifest(&manifest_document)),
+            ManifestFormat::Json => (
+                "logical_blocks/update_manifest.json",
+                serde_json::to_vec_pretty(&manifest_document).unwrap(),
+            ),
+            ManifestFormat::Cbor => {
+                let mut content = Vec::new();
+                ciborium::into_writer(&manifest_document, &mut content).unwrap();
+                ("logical_blocks/update_manifest.cbor", content)
+            }
+        };
+
+        let mut index = format!(
+            "<file_list xmlns=\"file_list\">\n    <file short_name=\"update_manifest\">\n        <path>{manifest_path}</path>\n    </file>\n"
+        );
+        for logical_block in &logical_blocks {
+            index.push_str(&format!(
+                "    <file short_name=\"{}\">\n        <path>{}</path>\n    </file>\n",
+                logical_block.short_name, logical_block.path_in_archive
+            ));
+        }
+        index.push_str("</file_list>\n");
+
+        let (file, archive_path) = tempfile::Builder::new()
+            .prefix("update_folder_")
+            .suffix(".zip")
+            .tempfile_in(self.archive_dir)
+            .unwrap()
+            .keep()
+            .unwrap();
+        let mut archive = ZipWriter::new(file);
+        let options = match self.stored {
+            true => FileOptions::default().compression_method(CompressionMethod::Stored),
+            false => FileOptions::default(),
+        };
+
+        archive.add_directory("logical_blocks/", options).unwrap();
+        archive.start_file(INDEX_PATH, options).unwrap();
+        archive.write_all(index.as_bytes()).unwrap();
+        archive.start_file(manifest_path, options).unwrap();
+        archive.write_all(&manifest_content).unwrap();
+
+        for logical_block in &logical_blocks {
+            let mut entry = source.by_name(&logical_block.path_in_archive).unwrap();
+            if !self.stored {
+                archive.raw_copy_file(entry).unwrap();
+                continue;
+            }
+
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            archive
+                .start_file(&logical_block.path_in_archive, options)
+                .unwrap();
+            archive.write_all(&content).unwrap();
+        }
+        archive.finish().unwrap();
+
+        archive_path.to_str().unwrap().to_string()
+    }
+}
+
+/// Write hook failing every write to the destinations moved by [`MappingBuilder::failing`].
+pub(crate) fn fail_failing_writes(path: &str, _: u64, _: usize) -> io::Result<()> {
+    let file_name = Path::new(path).file_name().unwrap().to_str().unwrap();
+    if file_name.starts_with("failing_") {
+        return Err(io::Error::other("Injected write failure"));
+    }
+    Ok(())
+}
+
+/// Id, start and end offset of the logical blocks stored in each destination file.
+type DestinationRegions = HashMap<String, Vec<(String, u64, u64)>>;
+
+/// Write hook recording the id of the logical block of a mapping every write to its
+/// destinations is for, in the order they happen.
+#[derive(Clone)]
+pub(crate) struct WriteRecorder {
+    regions: Arc<DestinationRegions>,
+    writes: Arc<Mutex<Vec<String>>>,
+}
+
+impl WriteRecorder {
+    /// Records the writes to the destinations of the mapping at `mapping_path`.
+    pub(crate) fn new(mapping_path: &str) -> WriteRecorder {
+        let mapping: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(mapping_path).unwrap()).unwrap();
+        let mut regions = DestinationRegions::new();
+        for logical_block in mapping["logical_blocks"].as_array().unwrap() {
+            for destination in logical_block["destination"].as_object().unwrap().values() {
+                let offset = destination["offset"].as_u64().unwrap();
+                regions
+                    .entry(destination["path"].as_str().unwrap().to_string())
+                    .or_default()
+                    .push((
+                        logical_block["id"].as_str().unwrap().to_string(),
+                        offset,
+                        offset + destination["size"].as_u64().unwrap(),
+                    ));
+            }
+        }
+
+        WriteRecorder {
+            regions: Arc::new(regions),
+            writes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns the ids recorded so far.
+    pub(crate) fn get_writes(&self) -> Vec<String> {
+        self.writes.lock().unwrap().clone()
+    }
+}
+
+impl WriteHook for WriteRecorder {
+    fn before_write(&self, path: &str, offset: u64, _: usize) -> io::Result<()> {
+        if let Some(regions) = self.regions.get(path) {
+            let (id, _, _) = regions
+                .iter()
+                .find(|(_, start, end)| (*start..*end).contains(&offset))
+                .unwrap();
+            self.writes.lock().unwrap().push(id.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Checks that every logical block of `stages` was written, each stage only once every write
+/// to the logical blocks of the previous one is done.
+pub(crate) fn assert_written_in_stages(writes: &[String], stages: &[&[&str]]) {
+    for stage in stages {
+        for id in *stage {
+            assert!(writes.contains(&id.to_string()), "{id} wasn't written");
+        }
+    }
+    for (stage, next_stage) in stages.iter().zip(&stages[1..]) {
+        let last_write = writes
+            .iter()
+            .rposition(|id| stage.contains(&id.as_str()))
+            .unwrap();
+        let first_next_write = writes
+            .iter()
+            .position(|id| next_stage.contains(&id.as_str()))
+            .unwrap();
+        assert!(
+            last_write < first_next_write,
+            "{next_stage:?} written before {stage:?} was done: {writes:?}"
+        );
+    }
+}
+
+fn get_xml_manifest(manifest_document: &ManifestDocument) -> Vec<u8> {
+    let mut manifest = String::from("<logical_blocks xmlns=\"logical_blocks\">\n");
+    for logical_block in &manifest_document.logical_blocks {
+        manifest.push_str(&format!(
+            "    <logical_block>\n        <id>{}</id>\n        <short_name>{}</short_name>\n        <signature>{}</signature>\n",
+            logical_block.id, logical_block.short_name, logical_block.signature
+        ));
+        if let Some(digest) = &logical_block.digest {
+            manifest.push_str(&format!("        <digest>{digest}</digest>\n"));
+        }
+        if logical_block.phase != 0 {
+            manifest.push_str(&format!("        <phase>{}</phase>\n", logical_block.phase));
+        }
+        for dependency in &logical_block.depends_on {
+            manifest.push_str(&format!("        <depends_on>{dependency}</depends_on>\n"));
+        }
+        manifest.push_str("    </logical_block>\n");
+    }
+    manifest.push_str("</logical_blocks>\n");
+    manifest.into_bytes()
+}
+
+fn read_entry_to_string(archive: &mut ZipArchive<File>, path_in_archive: &str) -> String {
+    let mut content = String::new();
+    archive
+        .by_name(path_in_archive)
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    content
+}
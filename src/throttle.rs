@@ -0,0 +1,234 @@
+use std::{
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Limits of the I/O performed by the copy loops of an update.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoBudget {
+    /// Bytes written per second, `None` for no limit.
+    pub bytes_per_second: Option<u64>,
+    /// Chunk writes per second, `None` for no limit.
+    pub operations_per_second: Option<u64>,
+    /// Niceness given to the threads running copy loops while they do, `None` to leave it
+    /// unchanged.
+    pub niceness: Option<i32>,
+}
+
+/// I/O budget shared by every thread and task copying logical blocks, which can be adjusted
+/// while the update runs.
+///
+/// Chunks are given consecutive time slots, so that the copy loops together never exceed the
+/// budget, whatever the number of rayon threads or tokio tasks running them.
+#[derive(Debug, Clone)]
+pub struct IoThrottle {
+    state: Arc<Mutex<ThrottleState>>,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    budget: IoBudget,
+    next_byte_slot: Instant,
+    next_operation_slot: Instant,
+}
+
+impl Default for IoThrottle {
+    fn default() -> IoThrottle {
+        IoThrottle::new(IoBudget::default())
+    }
+}
+
+impl IoThrottle {
+    pub fn new(budget: IoBudget) -> IoThrottle {
+        let now = Instant::now();
+
+        IoThrottle {
+            state: Arc::new(Mutex::new(ThrottleState {
+                budget,
+                next_byte_slot: now,
+                next_operation_slot: now,
+            })),
+        }
+    }
+
+    pub fn unlimited() -> IoThrottle {
+        IoThrottle::default()
+    }
+
+    pub fn get_budget(&self) -> IoBudget {
+        self.state.lock().unwrap().budget
+    }
+
+    /// Replaces the budget, taking effect from the next chunk of every copy loop.
+    pub fn set_budget(&self, budget: IoBudget) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        state.budget = budget;
+        state.next_byte_slot = now;
+        state.next_operation_slot = now;
+    }
+
+    /// Blocks the calling thread until a chunk of `bytes` fits in the budget.
+    pub(crate) fn throttle(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Same as [`IoThrottle::throttle`], without blocking the runtime.
+    pub(crate) async fn throttle_async(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Gives the calling thread the niceness of the budget, if any, until the returned guard is
+    /// dropped. Lowering the niceness needs privileges, so this is best effort, and a thread
+    /// whose niceness was raised keeps it when it isn't allowed to lower it back.
+    pub(crate) fn apply_niceness(&self) -> NicenessGuard {
+        let previous_niceness = self
+            .get_budget()
+            .niceness
+            .and_then(|niceness| Some((get_thread_niceness()?, niceness)))
+            .map(|(previous_niceness, niceness)| {
+                set_thread_niceness(niceness);
+                previous_niceness
+            });
+
+        NicenessGuard {
+            previous_niceness,
+            _thread_bound: PhantomData,
+        }
+    }
+
+    /// Books the next time slots for a chunk of `bytes` and returns how long to wait for them.
+    fn reserve(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let budget = state.budget;
+
+        let byte_wait = match budget.bytes_per_second.filter(|limit| *limit > 0) {
+            Some(limit) => Self::book_slot(
+                &mut state.next_byte_slot,
+                now,
+                Duration::from_secs_f64(bytes as f64 / limit as f64),
+            ),
+            None => Duration::ZERO,
+        };
+        let operation_wait = match budget.operations_per_second.filter(|limit| *limit > 0) {
+            Some(limit) => Self::book_slot(
+                &mut state.next_operation_slot,
+                now,
+                Duration::from_secs_f64(1.0 / limit as f64),
+            ),
+            None => Duration::ZERO,
+        };
+
+        byte_wait.max(operation_wait)
+    }
+
+    fn book_slot(next_slot: &mut Instant, now: Instant, slot_duration: Duration) -> Duration {
+        let slot = (*next_slot).max(now);
+        *next_slot = slot + slot_duration;
+        slot - now
+    }
+}
+
+/// Restores the niceness the thread had before [`IoThrottle::apply_niceness`] when dropped.
+/// Bound to its thread, the niceness being per thread.
+#[must_use]
+pub(crate) struct NicenessGuard {
+    previous_niceness: Option<i32>,
+    _thread_bound: PhantomData<*const ()>,
+}
+
+impl Drop for NicenessGuard {
+    fn drop(&mut self) {
+        if let Some(previous_niceness) = self.previous_niceness {
+            set_thread_niceness(previous_niceness);
+        }
+    }
+}
+
+fn get_thread_niceness() -> Option<i32> {
+    // -1 is a valid niceness, errors are told apart through errno
+    unsafe {
+        *libc::__errno_location() = 0;
+        let niceness = libc::getpriority(libc::PRIO_PROCESS as _, 0);
+        (niceness != -1 || *libc::__errno_location() == 0).then_some(niceness)
+    }
+}
+
+fn set_thread_niceness(niceness: i32) {
+    // With `who` 0, PRIO_PROCESS targets the calling thread on Linux
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS as _, 0, niceness);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_spreads_chunks_over_budget_test() {
+        let throttle = IoThrottle::new(IoBudget {
+            bytes_per_second: Some(1_000_000),
+            operations_per_second: Some(5),
+            niceness: None,
+        });
+
+        assert!(throttle.reserve(100_000).is_zero());
+        // Limited by the 5 operations per second rather than the 1 MB/s
+        let second_wait = throttle.reserve(100_000);
+        assert!(
+            second_wait > Duration::from_millis(150) && second_wait <= Duration::from_millis(200)
+        );
+
+        // A shared clone books the following slots
+        let third_wait = throttle.clone().reserve(1_000_000);
+        assert!(
+            third_wait > Duration::from_millis(350) && third_wait <= Duration::from_millis(400)
+        );
+        assert!(throttle.reserve(0) > Duration::from_millis(1150));
+
+        throttle.set_budget(IoBudget::default());
+        assert!(throttle.reserve(1_000_000).is_zero());
+        assert_eq!(throttle.get_budget(), IoBudget::default());
+    }
+
+    #[test]
+    fn niceness_restored_test() {
+        let throttle = IoThrottle::new(IoBudget {
+            niceness: Some(0),
+            ..IoBudget::default()
+        });
+
+        // On a thread of its own, raising the niceness of the test thread would outlive the test
+        std::thread::spawn(move || {
+            let initial_niceness = get_thread_niceness().unwrap();
+            let raised_niceness = (initial_niceness + 5).min(19);
+            throttle.set_budget(IoBudget {
+                niceness: Some(raised_niceness),
+                ..IoBudget::default()
+            });
+
+            let niceness_guard = throttle.apply_niceness();
+            assert_eq!(get_thread_niceness(), Some(raised_niceness));
+            drop(niceness_guard);
+
+            if get_thread_niceness() != Some(initial_niceness) {
+                // Only left raised when lowering it back isn't allowed
+                let result =
+                    unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, initial_niceness) };
+                assert_eq!(result, -1);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
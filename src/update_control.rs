@@ -0,0 +1,222 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    audit_log::{get_archive_digest, AuditLog, AuditRecord},
+    chunked_io::WriteHook,
+    metrics::UpdateMetrics,
+    reporting::{UpdateError, UpdateReport},
+    throttle::IoThrottle,
+};
+
+/// Handle to cancel, pause and resume an update in flight from another thread or task, and to
+/// configure how it runs: the I/O throttle its writes stay within, the metrics and audit log
+/// its outcome is recorded in, and the hook its writes go through.
+///
+/// Clones share the same state, the copy and verify loops of every strategy check it between
+/// chunks. A paused update holds on to its files until resumed or cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateControl {
+    state: Arc<ControlState>,
+    io_throttle: IoThrottle,
+    metrics: Option<UpdateMetrics>,
+    audit_log: Option<AuditLog>,
+    write_hook: Option<Arc<dyn WriteHook>>,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    flags: Mutex<ControlFlags>,
+    released: Condvar,
+    released_async: Notify,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ControlFlags {
+    cancelled: bool,
+    paused: bool,
+}
+
+impl UpdateControl {
+    pub fn new() -> UpdateControl {
+        UpdateControl::default()
+    }
+
+    /// Keeps the writes of the update within the budget of `io_throttle`.
+    pub fn with_io_throttle(mut self, io_throttle: IoThrottle) -> UpdateControl {
+        self.io_throttle = io_throttle;
+        self
+    }
+
+    pub fn get_io_throttle(&self) -> &IoThrottle {
+        &self.io_throttle
+    }
+
+    /// Records the outcome of the update in `metrics` once it's over.
+    pub fn with_metrics(mut self, metrics: UpdateMetrics) -> UpdateControl {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn get_metrics(&self) -> Option<&UpdateMetrics> {
+        self.metrics.as_ref()
+    }
+
+    /// Appends the outcome of the update, successful or not, to `audit_log` once it's over.
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> UpdateControl {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Makes every write to the destinations of the update go through `write_hook` first.
+    pub fn with_write_hook(mut self, write_hook: impl WriteHook + 'static) -> UpdateControl {
+        self.write_hook = Some(Arc::new(write_hook));
+        self
+    }
+
+    pub(crate) fn get_write_hook(&self) -> Option<&Arc<dyn WriteHook>> {
+        self.write_hook.as_ref()
+    }
+
+    /// Stops the update at the next chunk, which then fails with [`crate::UpdateError::Cancelled`].
+    /// Cancelling also releases a paused update.
+    pub fn cancel(&self) {
+        self.set_flags(|flags| flags.cancelled = true);
+    }
+
+    /// Suspends the update at the next chunk, until [`UpdateControl::resume`] or
+    /// [`UpdateControl::cancel`] is called.
+    pub fn pause(&self) {
+        self.set_flags(|flags| flags.paused = true);
+    }
+
+    pub fn resume(&self) {
+        self.set_flags(|flags| flags.paused = false);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.get_flags().cancelled
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.get_flags().paused
+    }
+
+    /// Records the outcome of an update of `software_archive_path` that took `duration` in the
+    /// metrics and the audit log, if any. `update_report` holds the logical blocks completed,
+    /// before `update_error` if it failed.
+    pub(crate) fn record_update(
+        &self,
+        software_archive_path: &str,
+        update_report: &UpdateReport,
+        update_error: Option<&UpdateError>,
+        duration: Duration,
+    ) -> Result<(), UpdateError> {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_update(update_report, update_error, duration);
+        }
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.append(AuditRecord::from_update(
+                get_archive_digest(software_archive_path).ok(),
+                update_report,
+                update_error,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Blocks the calling thread while the update is paused, then tells whether it may go on.
+    pub(crate) fn proceed(&self) -> bool {
+        let flags = self.state.flags.lock().unwrap();
+        let flags = self
+            .state
+            .released
+            .wait_while(flags, |flags| flags.paused && !flags.cancelled)
+            .unwrap();
+
+        !flags.cancelled
+    }
+
+    /// Same as [`UpdateControl::proceed`], without blocking the runtime.
+    pub(crate) async fn proceed_async(&self) -> bool {
+        loop {
+            let released = self.state.released_async.notified();
+            tokio::pin!(released);
+            // Registers for the notification before reading the flags, so that none is missed
+            released.as_mut().enable();
+
+            let flags = self.get_flags();
+            if flags.cancelled || !flags.paused {
+                return !flags.cancelled;
+            }
+            released.await;
+        }
+    }
+
+    fn get_flags(&self) -> ControlFlags {
+        *self.state.flags.lock().unwrap()
+    }
+
+    fn set_flags(&self, change: impl FnOnce(&mut ControlFlags)) {
+        change(&mut self.state.flags.lock().unwrap());
+
+        self.state.released.notify_all();
+        self.state.released_async.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn paused_update_proceeds_once_resumed_test() {
+        let update_control = UpdateControl::new();
+        assert!(update_control.proceed());
+
+        update_control.pause();
+        let resumer = {
+            let update_control = update_control.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                update_control.resume();
+            })
+        };
+
+        let start = Instant::now();
+        assert!(update_control.proceed());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        resumer.join().unwrap();
+
+        update_control.pause();
+        update_control.cancel();
+        assert!(!update_control.proceed());
+        assert!(update_control.is_cancelled() && update_control.is_paused());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn paused_update_proceeds_once_resumed_async_test() {
+        let update_control = UpdateControl::new();
+        update_control.pause();
+
+        let canceller = {
+            let update_control = update_control.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                update_control.cancel();
+            })
+        };
+
+        assert!(!update_control.proceed_async().await);
+        canceller.await.unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    async_update::update_sequence::{
+        async_update, update_with_control, DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS,
+    },
+    multi_threaded_update::update_sequence::{
+        multi_threaded_update, multi_threaded_update_with_control,
+    },
+    reporting::{LogicalBlockError, LogicalBlockStatus, UpdateError, UpdateReport},
+    sequential_update::update_sequence::{sequencial_update, sequencial_update_with_control},
+    test_utils::{fail_failing_writes, ArchiveBuilder, MappingBuilder},
+    update_control::UpdateControl,
+};
+
+type UpdateStrategy = fn(&str, &str) -> Result<UpdateReport, UpdateError>;
+
+type ControlledUpdateStrategy = fn(&str, &str, &UpdateControl) -> Result<UpdateReport, UpdateError>;
+
+const UPDATE_STRATEGIES: [(&str, UpdateStrategy); 3] = [
+    ("sequential", sequencial_update),
+    ("multi-threaded", multi_threaded_update),
+    ("async", async_update),
+];
+
+const CONTROLLED_UPDATE_STRATEGIES: [(&str, ControlledUpdateStrategy); 3] = [
+    ("sequential", sequencial_update_with_control),
+    ("multi-threaded", multi_threaded_update_with_control),
+    ("async", async_update_with_control),
+];
+
+const TEST_ARCHIVE_PATH: &str = "./resources/test/update_folder.zip";
+
+fn async_update_with_control(
+    memory_mapping_path: &str,
+    software_archive_path: &str,
+    update_control: &UpdateControl,
+) -> Result<UpdateReport, UpdateError> {
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(update_with_control(
+            memory_mapping_path,
+            software_archive_path,
+            DEFAULT_MAX_CONCURRENT_LOGICAL_BLOCKS,
+            update_control,
+        ))
+}
+
+fn count_with_status(update_report: &UpdateReport, status: LogicalBlockStatus) -> usize {
+    update_report.get_logical_blocks_with_status(status).len()
+}
+
+#[test]
+fn skips_unchanged_logical_blocks_test() {
+    for (strategy, update) in UPDATE_STRATEGIES {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+
+        let first_report = update(&mapping_path, TEST_ARCHIVE_PATH).unwrap();
+        assert_eq!(
+            count_with_status(&first_report, LogicalBlockStatus::Written),
+            9,
+            "{strategy}"
+        );
+
+        let second_report = update(&mapping_path, TEST_ARCHIVE_PATH).unwrap();
+        assert_eq!(
+            count_with_status(&second_report, LogicalBlockStatus::Skipped),
+            9,
+            "{strategy}"
+        );
+    }
+}
+
+#[test]
+fn clones_logical_blocks_missing_from_archive_test() {
+    for (strategy, update) in UPDATE_STRATEGIES {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let active_bank_mapping_path = MappingBuilder::new(destination_dir.path())
+            .swapped()
+            .build();
+        let partial_archive_path = ArchiveBuilder::new(destination_dir.path())
+            .partial(&["FD02", "FD06"])
+            .build();
+
+        update(&active_bank_mapping_path, TEST_ARCHIVE_PATH).unwrap();
+
+        let update_report = update(&mapping_path, &partial_archive_path).unwrap();
+        assert_eq!(
+            update_report.get_logical_blocks_with_status(LogicalBlockStatus::Written),
+            vec!["FD02", "FD06"],
+            "{strategy}"
+        );
+        assert_eq!(
+            update_report.get_logical_blocks_with_status(LogicalBlockStatus::Cloned),
+            vec!["FD01", "FD03", "FD04", "FD05", "FD07", "FD08", "FD09"],
+            "{strategy}"
+        );
+
+        let full_update_report = update(&mapping_path, TEST_ARCHIVE_PATH).unwrap();
+        assert_eq!(
+            count_with_status(&full_update_report, LogicalBlockStatus::Skipped),
+            9,
+            "{strategy}"
+        );
+    }
+}
+
+#[test]
+fn chunked_destinations_test() {
+    for (strategy, update) in UPDATE_STRATEGIES {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .chunked(65536, 512)
+            .build();
+        let stored_archive_path = ArchiveBuilder::new(destination_dir.path()).stored().build();
+
+        let first_report = update(&mapping_path, &stored_archive_path).unwrap();
+        assert_eq!(
+            count_with_status(&first_report, LogicalBlockStatus::Written),
+            9,
+            "{strategy}"
+        );
+
+        let second_report = update(&mapping_path, TEST_ARCHIVE_PATH).unwrap();
+        assert_eq!(
+            count_with_status(&second_report, LogicalBlockStatus::Skipped),
+            9,
+            "{strategy}"
+        );
+    }
+}
+
+#[test]
+fn writes_nand_around_bad_blocks_test() {
+    const RAW_PAGE_SIZE: usize = 2048 + 64;
+    const RAW_BLOCK_SIZE: usize = RAW_PAGE_SIZE * 64;
+
+    for (strategy, update) in UPDATE_STRATEGIES {
+        for stored in [false, true] {
+            let destination_dir = tempfile::tempdir().unwrap();
+            let mapping_path = MappingBuilder::new(destination_dir.path())
+                .nand(&[1], &[3])
+                .build();
+            let archive_path = match stored {
+                true => ArchiveBuilder::new(destination_dir.path()).stored().build(),
+                false => TEST_ARCHIVE_PATH.to_string(),
+            };
+
+            let first_report = update(&mapping_path, &archive_path).unwrap();
+            assert_eq!(
+                count_with_status(&first_report, LogicalBlockStatus::Written),
+                9,
+                "{strategy}"
+            );
+
+            let image = fs::read(destination_dir.path().join("nand_a")).unwrap();
+            // FD01 fills the first pages of block 0 and leaves their OOB area erased
+            assert_ne!(image[..2048], [0xFF; 2048], "{strategy}");
+            assert_eq!(image[2048..RAW_PAGE_SIZE], [0xFF; 64], "{strategy}");
+            // FD03 starts on block 2, bad blocks 1 and 3 are left as they were
+            assert_eq!(
+                image[RAW_BLOCK_SIZE..RAW_BLOCK_SIZE + 2048],
+                [0; 2048],
+                "{strategy}"
+            );
+            assert_eq!(image[RAW_BLOCK_SIZE + 2048], 0, "{strategy}");
+            assert_ne!(
+                image[2 * RAW_BLOCK_SIZE..2 * RAW_BLOCK_SIZE + 2048],
+                [0xFF; 2048],
+                "{strategy}"
+            );
+            assert_eq!(
+                image[3 * RAW_BLOCK_SIZE..3 * RAW_BLOCK_SIZE + 2048],
+                [0; 2048],
+                "{strategy}"
+            );
+
+            // Read back through the same mapping, the installed logical blocks are up to date
+            let second_report = update(&mapping_path, &archive_path).unwrap();
+            assert_eq!(
+                count_with_status(&second_report, LogicalBlockStatus::Skipped),
+                9,
+                "{strategy}"
+            );
+        }
+
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .nand(&[1], &[3])
+            .build();
+        let mut mapping: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&mapping_path).unwrap()).unwrap();
+        mapping["logical_blocks"][0]["destination"]["bank_a"]["offset"] = 2048.into();
+        fs::write(&mapping_path, mapping.to_string()).unwrap();
+
+        let error = update(&mapping_path, TEST_ARCHIVE_PATH).unwrap_err();
+        assert_eq!(error.code(), 200, "{strategy}");
+    }
+}
+
+#[test]
+fn gives_up_after_max_attempts_test() {
+    for (strategy, update) in CONTROLLED_UPDATE_STRATEGIES {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .retry(serde_json::json!({
+                "max_attempts": 3,
+                "backoff_ms": 20,
+            }))
+            .failing(&["FD01"])
+            .build();
+
+        let update_control = UpdateControl::new().with_write_hook(fail_failing_writes);
+
+        let update_start = Instant::now();
+        let error = update(&mapping_path, TEST_ARCHIVE_PATH, &update_control).unwrap_err();
+
+        assert_eq!(error.code(), 301, "{strategy}");
+        assert_eq!(error.get_logical_block_id(), Some("FD01"), "{strategy}");
+        assert!(
+            matches!(
+                error,
+                UpdateError::LogicalBlockWrite(LogicalBlockError { cause: Some(_), .. })
+            ),
+            "{strategy}: {error}"
+        );
+        // Waited 20 ms before the second attempt of the first chunk and 40 ms before the third one
+        assert!(
+            update_start.elapsed() >= Duration::from_millis(60),
+            "{strategy}"
+        );
+
+        let mapping_path = MappingBuilder::new(destination_dir.path())
+            .retry(serde_json::json!({ "max_attempts": 0 }))
+            .build();
+        let error = update(&mapping_path, TEST_ARCHIVE_PATH, &update_control).unwrap_err();
+        assert_eq!(error.code(), 200, "{strategy}");
+    }
+}
+
+#[test]
+fn rejects_logical_blocks_larger_than_destination_test() {
+    for (strategy, update) in UPDATE_STRATEGIES {
+        for stored in [false, true] {
+            let destination_dir = tempfile::tempdir().unwrap();
+            let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+            let archive_path = match stored {
+                true => ArchiveBuilder::new(destination_dir.path()).stored().build(),
+                false => TEST_ARCHIVE_PATH.to_string(),
+            };
+            let mut mapping: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(&mapping_path).unwrap()).unwrap();
+            mapping["logical_blocks"][0]["destination"]["bank_a"]["size"] = 4096.into();
+            fs::write(&mapping_path, mapping.to_string()).unwrap();
+            let image_before = fs::read(destination_dir.path().join("mtd_a")).unwrap();
+
+            let error = update(&mapping_path, &archive_path).unwrap_err();
+            assert_eq!(error.get_logical_block_id(), Some("FD01"), "{strategy}");
+
+            // Neither the shrunk region of FD01 nor the gap up to FD03 were written
+            let image = fs::read(destination_dir.path().join("mtd_a")).unwrap();
+            assert!(
+                image[..135168] == image_before[..135168],
+                "{strategy}: {error}"
+            );
+        }
+    }
+}
+
+#[test]
+fn rejects_stored_logical_blocks_past_archive_end_test() {
+    const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+
+    for (strategy, update) in UPDATE_STRATEGIES {
+        let destination_dir = tempfile::tempdir().unwrap();
+        let mapping_path = MappingBuilder::new(destination_dir.path()).build();
+        let archive_path = ArchiveBuilder::new(destination_dir.path()).stored().build();
+
+        // The central directory claims FD01 is as large as the whole archive
+        let mut archive = fs::read(&archive_path).unwrap();
+        let file_name = b"logical_blocks/FD01.bin";
+        let header_start = archive
+            .windows(file_name.len())
+            .rposition(|window| window == file_name)
+            .unwrap()
+            - CENTRAL_DIRECTORY_HEADER_SIZE;
+        let archive_size = (archive.len() as u32).to_le_bytes();
+        archive[header_start + 20..header_start + 24].copy_from_slice(&archive_size);
+        archive[header_start + 24..header_start + 28].copy_from_slice(&archive_size);
+        fs::write(&archive_path, archive).unwrap();
+
+        // Rejected when indexing the mapped archive, or when the size doesn't match the
+        // destination for the sequential update, which reads the archive file
+        let error = update(&mapping_path, &archive_path).unwrap_err();
+        assert!(matches!(error.code(), 100 | 302), "{strategy}: {error}");
+    }
+}